@@ -0,0 +1,196 @@
+use crate::commands::{
+    channel_perms, send_to_channel_readers, server_perms, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{gen_uuid, LockedState, Uuid};
+use crate::message::Message;
+use crate::models::Channel;
+use crate::permissions::Perm;
+use crate::Peer;
+use serde::Deserialize;
+
+/// Make the channel `target` follow the announcement channel `source`, so that messages published in `source`
+/// are copied into `target`. Requires the `modify_channels` permission.
+/// Error conditions:
+/// - 400 (bad request) if `source` is not an announcement channel, or is the same as `target`.
+/// - 403 (forbidden) if the user does not have permission.
+/// - 404 (not found) if either channel does not exist.
+/// - 409 (conflict) if `target` already follows `source`.
+#[derive(Deserialize)]
+pub struct FollowChannelRequest {
+    pub source: Uuid,
+    pub target: Uuid,
+}
+
+/// Stop `target` following the announcement channel `source`. Requires the `modify_channels` permission.
+/// Error conditions:
+/// - 403 (forbidden) if the user does not have permission.
+/// - 404 (not found) if `target` does not follow `source`.
+#[derive(Deserialize)]
+pub struct UnfollowChannelRequest {
+    pub source: Uuid,
+    pub target: Uuid,
+}
+
+/// List the channels following an announcement channel. Returns a packet of type get_followers with a field
+/// "data" containing the uuids of the following channels that the user can see.
+/// Error conditions:
+/// - 403 (forbidden) if the user cannot see the channel.
+/// - 404 (not found) if the channel does not exist.
+#[derive(Deserialize)]
+pub struct GetFollowersRequest {
+    pub channel: Uuid,
+}
+
+/// Publish a message from an announcement channel to every channel following it. Each of them gets a copy of
+/// the message, with the same author, content and attachments, sent as usual. Archived channels are skipped. Requires the
+/// `post_announcements` permission in the announcement channel. Returns a packet of type publish_message with
+/// a field "messages" containing the uuids of the copies.
+/// Error conditions:
+/// - 400 (bad request) if the message is not in an announcement channel.
+/// - 403 (forbidden) if the user does not have permission.
+/// - 404 (not found) if the message does not exist.
+/// - 409 (conflict) if the message has already been published.
+#[derive(Deserialize)]
+pub struct PublishMessageRequest {
+    pub message: Uuid,
+}
+
+/// Get a channel from the server's channel list, leaving out direct messages and threads.
+fn get_server_channel(state_lock: &LockedState, uuid: Uuid) -> Result<Option<Channel>, CmdError> {
+    let Some(channel) = state_lock.get_channel(&uuid)? else {
+        return Ok(None);
+    };
+    if channel.is_dm() || state_lock.get_thread_parent(channel.uuid)?.is_some() {
+        return Ok(None);
+    }
+    Ok(Some(channel))
+}
+
+impl Request for FollowChannelRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.modify_channels != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        let (Some(source), Some(target)) = (
+            get_server_channel(state_lock, self.source)?,
+            get_server_channel(state_lock, self.target)?,
+        ) else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if !source.announcement || source.uuid == target.uuid {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+
+        if state_lock.follow_channel(source.uuid, target.uuid)? == 0 {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for UnfollowChannelRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.modify_channels != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        if state_lock.unfollow_channel(self.source, self.target)? == 0 {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for GetFollowersRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        let Some(channel) = get_server_channel(state_lock, self.channel)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if channel_perms(state_lock, peer.uuid, &channel)?.view_channel != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        let mut data = Vec::new();
+        for follower in state_lock.get_followers(channel.uuid)? {
+            let Some(follower) = state_lock.get_channel(&follower)? else {
+                continue;
+            };
+            if channel_perms(state_lock, peer.uuid, &follower)?.view_channel == Perm::Allow {
+                data.push(follower.uuid);
+            }
+        }
+        Ok(GetFollowersResponse { data })
+    }
+}
+
+impl Request for PublishMessageRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        let Some(message) = state_lock.get_message(self.message)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let Some(channel) = state_lock.get_channel(&message.channel_uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let perms = channel_perms(state_lock, peer.uuid, &channel)?;
+        if perms.read_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+        if !channel.announcement {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        if perms.post_announcements != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        if state_lock.is_published(message.uuid)? {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+
+        let date = chrono::offset::Utc::now().timestamp();
+        let mut copies = Vec::new();
+        for target in state_lock.get_followers(channel.uuid)? {
+            let Some(target) = state_lock.get_channel(&target)? else {
+                continue;
+            };
+            if target.archived {
+                continue;
+            }
+            let mut copy = Message {
+                uuid: gen_uuid(),
+                content: message.content.clone(),
+                author_uuid: message.author_uuid,
+                channel_uuid: target.uuid,
+                date: date as i32,
+                edited: false,
+                reply: None,
+                edited_at: None,
+                deleted_at: None,
+                deleted_by: None,
+                reactions: Vec::new(),
+                attachments: Vec::new(),
+            };
+            state_lock.add_to_history(&copy)?;
+            copy.attachments = state_lock.copy_attachments(message.uuid, copy.uuid)?;
+            state_lock.insert_published(copy.uuid, message.uuid)?;
+            copies.push(copy.uuid);
+
+            let mut packet = serde_json::to_value(ContentResponse { message: copy })?;
+            packet["status"] = (Status::Ok as i32).into();
+            send_to_channel_readers(state_lock, &target, &packet)?;
+        }
+
+        Ok(PublishMessageResponse { messages: copies })
+    }
+}
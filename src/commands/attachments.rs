@@ -0,0 +1,211 @@
+use crate::commands::{
+    channel_perms, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{gen_uuid, hash_hex, LockedState, Uuid};
+use crate::message::Attachment;
+use crate::peer::PendingUpload;
+use crate::permissions::Perm;
+use crate::Peer;
+use crate::CONF;
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+
+/// Largest piece of an attachment sent in a single `upload_chunk` or `get_attachment` packet, in bytes
+/// (before base64 encoding). This keeps individual lines/websocket frames to a sensible size.
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+/// How many uploads a single connection may have in progress at once.
+pub const MAX_PENDING_UPLOADS: usize = 4;
+pub const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+
+/// Begin uploading an attachment. Returns a packet of type upload_start with a field "upload" containing
+/// an id to pass to `upload_chunk` and `upload_finish`. In-progress uploads are tied to the connection,
+/// and are discarded if it is closed. Finished uploads which aren't sent in a message within
+/// `unattached_retention` seconds (a day by default) are deleted.
+/// Error conditions:
+/// - 400 (bad request) if the name is empty or too long, the size is zero or bigger than the server allows,
+///   the MIME type is not one the server accepts, or there are too many uploads in progress.
+/// - 409 (conflict) if the upload would take the user over the total size of unsent uploads they can have.
+#[derive(Deserialize)]
+pub struct UploadStartRequest {
+    pub name: String,
+    pub mime: String,
+    pub size: usize,
+}
+
+/// Send the next piece of an in-progress upload, as base64. Pieces must be sent in order, and each may be
+/// at most 256KiB before encoding.
+/// Error conditions:
+/// - 400 (bad request) if the data is not valid base64, is too big, or goes past the size given to
+///   `upload_start`.
+/// - 404 (not found) if there is no upload in progress with this id.
+#[derive(Deserialize)]
+pub struct UploadChunkRequest {
+    pub upload: Uuid,
+    pub data: String,
+}
+
+/// Finish an in-progress upload. Returns a packet of type upload_finish with a field "data" containing the
+/// new [`Attachment`], whose uuid can be given in the `attachments` field of `send`.
+/// Error conditions:
+/// - 400 (bad request) if fewer bytes were sent than given to `upload_start`.
+/// - 404 (not found) if there is no upload in progress with this id.
+/// - 409 (conflict) if the upload would take the user over the total size of unsent uploads they can have.
+#[derive(Deserialize)]
+pub struct UploadFinishRequest {
+    pub upload: Uuid,
+}
+
+/// Download up to 256KiB of an attachment, starting at `offset` bytes in. Returns a packet of type
+/// get_attachment with the fields "uuid", "offset", "size" (the attachment's total size), and "data" (base64).
+/// Clients should keep requesting with increasing offsets until they have `size` bytes.
+/// Error conditions:
+/// - 400 (bad request) if the offset is past the end of the attachment.
+/// - 403 (forbidden) if the user cannot read the channel the attachment was sent in.
+/// - 404 (not found) if the attachment does not exist, or has not been sent by someone else yet.
+#[derive(Deserialize)]
+pub struct GetAttachmentRequest {
+    pub uuid: Uuid,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+impl Request for UploadStartRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(uploader) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        if self.name.trim().is_empty() || self.name.len() > 255 {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        if self.size == 0 || self.size > CONF.max_attachment_size {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        if !CONF.attachment_types.contains(&self.mime) {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        if peer.uploads.len() >= MAX_PENDING_UPLOADS {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        let pending: usize = peer.uploads.values().map(|u| u.size).sum();
+        if state_lock.get_unattached_size(uploader)? + pending + self.size
+            > CONF.max_unattached_size
+        {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+
+        let upload = gen_uuid();
+        peer.uploads.insert(
+            upload,
+            PendingUpload {
+                name: self.name,
+                mime: self.mime,
+                size: self.size,
+                // grown as the chunks arrive, so that nothing is reserved for data that may never come
+                data: Vec::new(),
+            },
+        );
+        Ok(UploadStartResponse { upload })
+    }
+}
+
+impl Request for UploadChunkRequest {
+    fn execute(self, _: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        let Some(upload) = peer.uploads.get_mut(&self.upload) else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let Ok(chunk) = general_purpose::STANDARD.decode(&self.data) else {
+            return Ok(GenericResponse(Status::BadRequest));
+        };
+        if chunk.len() > MAX_CHUNK_SIZE || upload.data.len() + chunk.len() > upload.size {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        upload.data.extend_from_slice(&chunk);
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for UploadFinishRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(uploader_uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let Some(upload) = peer.uploads.get(&self.upload) else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if upload.data.len() != upload.size {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        // other connections may have finished uploads since this one started
+        if state_lock.get_unattached_size(uploader_uuid)? + upload.size > CONF.max_unattached_size {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+        let upload = peer.uploads.remove(&self.upload).unwrap(); // unwrap ok, we just checked it's there
+
+        let hash = hash_hex(&upload.data);
+        state_lock.insert_blob(&hash, &upload.data)?;
+        let attachment = Attachment {
+            uuid: gen_uuid(),
+            name: upload.name,
+            mime: upload.mime,
+            size: upload.size,
+            hash,
+            uploader_uuid,
+            message_uuid: None,
+            uploaded_at: chrono::offset::Utc::now().timestamp(),
+        };
+        state_lock.insert_attachment(&attachment)?;
+
+        Ok(UploadFinishResponse { data: attachment })
+    }
+}
+
+impl Request for GetAttachmentRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        let Some(attachment) = state_lock.get_attachment(self.uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+
+        // attachments that haven't been sent yet are only visible to whoever uploaded them
+        match attachment.message_uuid {
+            Some(message) => {
+                let Some(message) = state_lock.get_message(message)? else {
+                    return Ok(GenericResponse(Status::NotFound));
+                };
+                let Some(channel) = state_lock.get_channel(&message.channel_uuid)? else {
+                    return Ok(GenericResponse(Status::NotFound));
+                };
+                if channel_perms(state_lock, peer.uuid, &channel)?.read_messages != Perm::Allow {
+                    return Ok(GenericResponse(Status::Forbidden));
+                }
+            }
+            None if peer.uuid != Some(attachment.uploader_uuid) => {
+                return Ok(GenericResponse(Status::NotFound));
+            }
+            None => (),
+        }
+
+        if self.offset >= attachment.size {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        let Some(data) =
+            state_lock.get_blob_range(&attachment.hash, self.offset, MAX_CHUNK_SIZE)?
+        else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+
+        Ok(GetAttachmentResponse {
+            uuid: attachment.uuid,
+            offset: self.offset,
+            size: attachment.size,
+            data: general_purpose::STANDARD.encode(data),
+        })
+    }
+}
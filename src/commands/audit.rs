@@ -0,0 +1,72 @@
+use crate::commands::{
+    server_perms, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{LockedState, Uuid};
+use crate::models::{AuditAction, AuditLogEntry, AuditLogFilter};
+use crate::permissions::Perm;
+use crate::Peer;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+/// The most entries that will be returned by a single `get_audit_log`, regardless of `num`.
+const MAX_AUDIT_LOG_ENTRIES: u32 = 100;
+
+/// Get entries from the audit log, which records every privileged action taken on the server, newest first.
+/// Returns a packet of type get_audit_log with a field "data" containing up to `num` (at most 100) entries. To
+/// get the next page, request again with `before` set to the id of the last (oldest) entry. `actor`, `action`
+/// and `target` optionally filter the entries returned.
+/// Error conditions:
+/// - 403 (forbidden) if the user does not have the `view_audit_log` permission.
+#[derive(Deserialize)]
+pub struct GetAuditLogRequest {
+    pub num: u32,
+    pub before: Option<i64>,
+    pub actor: Option<Uuid>,
+    pub action: Option<AuditAction>,
+    pub target: Option<Uuid>,
+}
+
+/// Record a privileged action taken by the peer in the audit log.
+/// `before` and `after` are snapshots of whatever the action changed.
+pub fn record_audit(
+    state_lock: &LockedState,
+    peer: &Peer,
+    action: AuditAction,
+    target: Option<Uuid>,
+    before: Option<JsonValue>,
+    after: Option<JsonValue>,
+) -> Result<(), CmdError> {
+    state_lock.insert_audit_log(&AuditLogEntry {
+        id: 0,
+        date: chrono::offset::Utc::now().timestamp(),
+        actor_uuid: peer.uuid.unwrap(),
+        action,
+        target,
+        before,
+        after,
+    })?;
+    Ok(())
+}
+
+impl Request for GetAuditLogRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.view_audit_log != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        let filter = AuditLogFilter {
+            actor: self.actor,
+            action: self.action,
+            target: self.target,
+        };
+        let num = self.num.min(MAX_AUDIT_LOG_ENTRIES);
+        Ok(GetAuditLogResponse {
+            data: state_lock.get_audit_log(&filter, num, self.before)?,
+        })
+    }
+}
@@ -1,6 +1,19 @@
 use argon2::password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier, SaltString};
 use argon2::{Argon2, PasswordHash};
 
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::helper::hash_hex;
+use crate::CONF;
+
+/// How long each TOTP code lasts for, in seconds.
+const TOTP_PERIOD: i64 = 30;
+/// How many digits are in a TOTP code.
+const TOTP_DIGITS: u32 = 6;
+const SECRET_ALPHABET: Alphabet = Alphabet::Rfc4648 { padding: false };
+
 pub fn make_hash(passwd: &str) -> Result<String, std::io::Error> {
     let salt = SaltString::generate(&mut OsRng);
 
@@ -20,6 +33,85 @@ pub fn check_password(passwd: &str, hash: &str) -> Result<bool, std::io::Error>
         .is_ok())
 }
 
+/// Check a new password against the server's password policy.
+pub fn password_allowed(passwd: &str) -> bool {
+    let len = passwd.chars().count();
+    len >= CONF.min_password_length && len <= CONF.max_password_length
+}
+
+/// Generate a new random session token, as 64 hex characters.
+pub fn make_token() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The hash of a session token that is stored in the database. Tokens are random enough that a fast hash
+/// is fine here, and lets the token be looked up directly.
+pub fn hash_token(token: &str) -> String {
+    hash_hex(token.as_bytes())
+}
+
+/// Generate a new random TOTP secret, base32 encoded as authenticator apps expect.
+pub fn make_totp_secret() -> String {
+    base32::encode(SECRET_ALPHABET, &rand::random::<[u8; 20]>())
+}
+
+/// The otpauth URI for a TOTP secret, which authenticator apps can import (usually from a QR code).
+pub fn totp_uri(secret: &str, account: &str, issuer: &str) -> String {
+    let account = url_encode(account);
+    let issuer = url_encode(issuer);
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}\
+        &algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_PERIOD}"
+    )
+}
+
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// The HOTP code (RFC 4226) for `counter`, which for TOTP is the number of periods since the epoch.
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+    code % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Check a TOTP code against a secret at time `now`. Codes from the period either side are accepted too, to
+/// allow for clocks being a little out. Returns the time step the code was for, so that it can't be used
+/// again, or `None` if the code is wrong or isn't for a step later than `last_step`.
+pub fn check_totp(secret: &str, code: &str, now: i64, last_step: i64) -> Option<i64> {
+    let key = base32::decode(SECRET_ALPHABET, secret)?;
+    let code: u32 = code.trim().parse().ok()?;
+    let step = now / TOTP_PERIOD;
+    (step - 1..=step + 1)
+        .filter(|&s| s > last_step && s >= 0)
+        .find(|&s| hotp(&key, s as u64) == code)
+}
+
+/// Generate a new single-use recovery code, for logging in when the authenticator is lost.
+pub fn make_recovery_code() -> String {
+    let code = make_token();
+    format!("{}-{}", &code[..5], &code[5..10])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -41,4 +133,49 @@ mod tests {
         assert!(checked.is_ok());
         assert!(!checked.unwrap());
     }
+    #[test]
+    fn tokens_differ() {
+        let a = make_token();
+        let b = make_token();
+        assert_eq!(a.len(), 64);
+        assert_ne!(a, b);
+        assert_eq!(hash_token(&a), hash_token(&a));
+        assert_ne!(hash_token(&a), hash_token(&b));
+    }
+
+    // test vectors from RFC 6238, truncated to 6 digits
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn totp_codes() {
+        assert_eq!(hotp(RFC_SECRET, 59 / 30), 287082);
+        assert_eq!(hotp(RFC_SECRET, 1111111109 / 30), 81804);
+        assert_eq!(hotp(RFC_SECRET, 1234567890 / 30), 5924);
+    }
+
+    #[test]
+    fn totp_check() {
+        let secret = base32::encode(SECRET_ALPHABET, RFC_SECRET);
+        let step = 1111111109 / 30;
+        assert_eq!(check_totp(&secret, "081804", 1111111109, 0), Some(step));
+        // the neighbouring periods are allowed, but no further
+        assert_eq!(
+            check_totp(&secret, "081804", 1111111109 + 30, 0),
+            Some(step)
+        );
+        assert_eq!(check_totp(&secret, "081804", 1111111109 + 60, 0), None);
+        // codes can't be used twice
+        assert_eq!(check_totp(&secret, "081804", 1111111109, step), None);
+        assert_eq!(check_totp(&secret, "123456", 1111111109, 0), None);
+        assert_eq!(check_totp(&secret, "nonsense", 1111111109, 0), None);
+    }
+
+    #[test]
+    fn totp_uri_escapes() {
+        assert_eq!(
+            totp_uri("ABC", "a user", "Aster Server"),
+            "otpauth://totp/Aster%20Server:a%20user?secret=ABC&issuer=Aster%20Server\
+            &algorithm=SHA1&digits=6&period=30"
+        );
+    }
 }
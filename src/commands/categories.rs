@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+
+use crate::commands::{
+    channels_in_category, moveto, record_audit, server_perms, update_channels, CmdError, HasOrder,
+    Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{gen_uuid, LockedState, Uuid};
+use crate::models::{AuditAction, Category, User};
+use crate::permissions::{Perm, PermableEntity, Permissions};
+use crate::shared::DbError;
+use crate::Peer;
+use serde::Deserialize;
+
+/// List the channel categories the user can see, in order. Returns a packet of type list_categories with a
+/// field "data" containing the categories. The same packet is sent to everyone whenever categories change.
+#[derive(Deserialize)]
+pub struct ListCategoriesRequest;
+
+/// Create a channel category. Requires the `modify_channels` permission. Returns a packet of type
+/// create_category with a field "uuid" containing the uuid of the new category. `position` defaults to after
+/// every other category.
+/// Error conditions:
+/// - 400 (bad request) if `position` is past the end of the categories.
+/// - 403 (forbidden) if the user does not have permission.
+#[derive(Deserialize)]
+pub struct CreateCategoryRequest {
+    pub name: String,
+    pub position: Option<usize>,
+    #[serde(default)]
+    pub permissions: HashMap<PermableEntity, Permissions>,
+}
+
+/// Rename, reorder or change the permission overrides of a category. Requires the `modify_channels`
+/// permission. Any field left out is unchanged.
+/// Error conditions:
+/// - 400 (bad request) if `position` is past the end of the categories.
+/// - 403 (forbidden) if the user does not have permission.
+/// - 404 (not found) if the category does not exist.
+#[derive(Deserialize)]
+pub struct UpdateCategoryRequest {
+    pub uuid: Uuid,
+    pub name: Option<String>,
+    pub position: Option<usize>,
+    pub permissions: Option<HashMap<PermableEntity, Permissions>>,
+}
+
+/// Delete a category. Requires the `modify_channels` permission. The channels in it are not deleted, but
+/// moved to the end of the uncategorised channels.
+/// Error conditions:
+/// - 403 (forbidden) if the user does not have permission.
+/// - 404 (not found) if the category does not exist.
+#[derive(Deserialize)]
+pub struct DeleteCategoryRequest {
+    pub uuid: Uuid,
+}
+
+/// Move a channel into a category, or out of one if `category` is left out. `position` is where it goes among
+/// the channels already in there, and defaults to the end. Requires the `modify_channels` permission.
+/// Error conditions:
+/// - 400 (bad request) if `position` is past the end of the channels in the category.
+/// - 403 (forbidden) if the user does not have permission.
+/// - 404 (not found) if the channel or category does not exist.
+#[derive(Deserialize)]
+pub struct MoveChannelRequest {
+    pub channel: Uuid,
+    pub category: Option<Uuid>,
+    pub position: Option<usize>,
+}
+
+impl HasOrder for Category {
+    fn pos(&self) -> usize {
+        self.position
+    }
+    fn with_pos(self, pos: usize) -> Self {
+        Self {
+            position: pos,
+            ..self
+        }
+    }
+}
+
+fn get_viewable_categories(
+    state_lock: &LockedState,
+    user: &User,
+) -> Result<Vec<Category>, DbError> {
+    let mut categories = Vec::new();
+    for category in state_lock.get_categories()? {
+        if state_lock
+            .resolve_category_permissions(user, &category)?
+            .view_channel
+            == Perm::Allow
+        {
+            categories.push(category);
+        }
+    }
+    Ok(categories)
+}
+
+fn update_categories(state_lock: &LockedState) -> Result<(), CmdError> {
+    for (tx, _, uuid, _) in state_lock.peers.iter() {
+        if let Some(uuid) = uuid {
+            let user = state_lock.get_user_exists(*uuid)?;
+            let categories = get_viewable_categories(state_lock, &user)?;
+            let mut packet = serde_json::to_value(ListCategoriesResponse { data: categories })?;
+            packet["status"] = (Status::Ok as i32).into();
+            tx.send(packet)?;
+        }
+    }
+    Ok(())
+}
+
+impl Request for ListCategoriesRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let user = state_lock.get_user_exists(uuid)?;
+        Ok(ListCategoriesResponse {
+            data: get_viewable_categories(state_lock, &user)?,
+        })
+    }
+}
+
+impl Request for CreateCategoryRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.modify_channels != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        let categories = state_lock.get_categories()?;
+        let next_position = categories.len();
+        let position = self.position.unwrap_or(next_position);
+        if position > next_position {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+
+        let category = Category {
+            uuid: gen_uuid(),
+            name: self.name,
+            position: next_position,
+            permissions: self.permissions,
+        };
+        state_lock.insert_category(&category)?;
+        moveto(next_position, position, categories, |c| {
+            state_lock.update_category(&c)
+        })?;
+        let category = category.with_pos(position);
+        state_lock.update_category(&category)?;
+
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::CreateCategory,
+            Some(category.uuid),
+            None,
+            Some(serde_json::to_value(&category)?),
+        )?;
+        update_categories(state_lock)?;
+        Ok(CreateCategoryResponse {
+            uuid: category.uuid,
+        })
+    }
+}
+
+impl Request for UpdateCategoryRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.modify_channels != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        let Some(old) = state_lock.get_category(self.uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let categories = state_lock.get_categories()?;
+        let position = self.position.unwrap_or(old.position);
+        if position >= categories.len() {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        let before = serde_json::to_value(&old)?;
+
+        let category = Category {
+            uuid: old.uuid,
+            name: self.name.unwrap_or(old.name),
+            position,
+            permissions: self.permissions.unwrap_or(old.permissions),
+        };
+        moveto(old.position, position, categories, |c| {
+            state_lock.update_category(&c)
+        })?;
+        state_lock.update_category(&category)?;
+
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::UpdateCategory,
+            Some(category.uuid),
+            Some(before),
+            Some(serde_json::to_value(&category)?),
+        )?;
+        update_categories(state_lock)?;
+        // the category's permissions may have changed who can see its channels
+        update_channels(state_lock)?;
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for DeleteCategoryRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.modify_channels != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        let Some(category) = state_lock.get_category(self.uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+
+        // keep the channels in the same order, after all the uncategorised ones
+        let next_position = channels_in_category(state_lock, None)?.len();
+        for (i, mut channel) in channels_in_category(state_lock, Some(category.uuid))?
+            .into_iter()
+            .enumerate()
+        {
+            channel.category = None;
+            channel.position = next_position + i;
+            state_lock.update_channel(&channel)?;
+        }
+        state_lock.delete_category(category.uuid)?;
+        // shift down the categories
+        for c in state_lock.get_categories()? {
+            if c.position > category.position {
+                let new_pos = c.position - 1;
+                state_lock.update_category(&c.with_pos(new_pos))?;
+            }
+        }
+
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::DeleteCategory,
+            Some(category.uuid),
+            Some(serde_json::to_value(&category)?),
+            None,
+        )?;
+        update_categories(state_lock)?;
+        update_channels(state_lock)?;
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for MoveChannelRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.modify_channels != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        let Some(channel) = state_lock.get_channel(&self.channel)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        // direct message channels and threads aren't part of the server's channel list
+        if channel.is_dm() || state_lock.get_thread_parent(channel.uuid)?.is_some() {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+        if let Some(category) = self.category {
+            if state_lock.get_category(category)?.is_none() {
+                return Ok(GenericResponse(Status::NotFound));
+            }
+        }
+
+        let next_position = channels_in_category(state_lock, self.category)?
+            .iter()
+            .filter(|c| c.uuid != channel.uuid)
+            .count();
+        let position = self.position.unwrap_or(next_position);
+        if position > next_position {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        let before = serde_json::to_value(&channel)?;
+
+        // take the channel out of its old category, closing the gap it leaves
+        for c in channels_in_category(state_lock, channel.category)? {
+            if c.position > channel.position {
+                let new_pos = c.position - 1;
+                state_lock.update_channel(&c.with_pos(new_pos))?;
+            }
+        }
+        // then make room for it in the new one
+        let siblings = channels_in_category(state_lock, self.category)?
+            .into_iter()
+            .filter(|c| c.uuid != channel.uuid)
+            .collect();
+        moveto(next_position, position, siblings, |c| {
+            state_lock.update_channel(&c)
+        })?;
+
+        let mut new_channel = channel.with_pos(position);
+        new_channel.category = self.category;
+        state_lock.update_channel(&new_channel)?;
+
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::UpdateChannel,
+            Some(new_channel.uuid),
+            Some(before),
+            Some(serde_json::to_value(&new_channel)?),
+        )?;
+        update_channels(state_lock)?;
+        Ok(GenericResponse(Status::Ok))
+    }
+}
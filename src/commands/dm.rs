@@ -0,0 +1,83 @@
+use crate::commands::{
+    CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{gen_uuid, LockedState, Uuid};
+use crate::models::Channel;
+use crate::Peer;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Open a direct message channel with another user, creating it if the two users don't already have one.
+/// Returns a packet of type open_dm with a field "data" containing the channel, which can then be used with
+/// `send`, `history` etc. like any other channel. When a new channel is created, both users are sent an
+/// updated `list_dms` packet on all of their connections.
+/// Error conditions:
+/// - 400 (bad request) if the user is trying to open a direct message channel with themselves.
+/// - 404 (not found) if the other user does not exist.
+#[derive(Deserialize)]
+pub struct OpenDmRequest {
+    pub user: Uuid,
+}
+
+/// List all the direct message channels the user is a member of. These are never included in `list_channels`.
+#[derive(Deserialize)]
+pub struct ListDmsRequest;
+
+fn send_dm_list(state_lock: &LockedState, user: Uuid) -> Result<(), CmdError> {
+    let mut packet = serde_json::to_value(ListDmsResponse {
+        data: state_lock.get_dm_channels_of(user)?,
+    })?;
+    packet["status"] = (Status::Ok as i32).into();
+    state_lock.send_to_user(user, packet)?;
+    Ok(())
+}
+
+impl Request for OpenDmRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(our_uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        if self.user == our_uuid {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        if state_lock.get_user(self.user)?.is_none() {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+
+        if let Some(channel) = state_lock.get_dm_channel_between(our_uuid, self.user)? {
+            return Ok(OpenDmResponse { data: channel });
+        }
+
+        let channel = Channel {
+            uuid: gen_uuid(),
+            name: "".into(),
+            position: 0,
+            permissions: HashMap::new(),
+            dm_members: Some((our_uuid, self.user)),
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        };
+        state_lock.insert_channel(&channel)?;
+        send_dm_list(state_lock, our_uuid)?;
+        send_dm_list(state_lock, self.user)?;
+
+        Ok(OpenDmResponse { data: channel })
+    }
+}
+
+impl Request for ListDmsRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(our_uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        Ok(ListDmsResponse {
+            data: state_lock.get_dm_channels_of(our_uuid)?,
+        })
+    }
+}
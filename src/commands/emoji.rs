@@ -0,0 +1,181 @@
+use crate::commands::{
+    record_audit, server_perms, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{gen_uuid, is_png, LockedState, Uuid};
+use crate::models::{AuditAction, Emoji};
+use crate::permissions::Perm;
+use crate::Peer;
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use serde_json::json;
+
+/// Add a custom emoji from a base64-encoded PNG image. Requires the `manage_emoji` permission.
+/// Returns a packet of type create_emoji with a field "uuid" containing the uuid of the new emoji.
+/// On success, all connected clients are sent an `emoji_list_changed` packet.
+/// Error conditions:
+/// - 400 (bad request) if the name is invalid (see `valid_emoji_name`), or the data is not a base64-encoded
+///   PNG image, or is too big.
+/// - 403 (forbidden) if the user does not have permission.
+/// - 409 (conflict) if an emoji with that name already exists.
+#[derive(Deserialize)]
+pub struct CreateEmojiRequest {
+    pub name: String,
+    pub data: String,
+}
+
+/// Rename a custom emoji. Requires the `manage_emoji` permission.
+/// Error conditions are the same as for `create_emoji`, plus 404 (not found) if the emoji does not exist.
+#[derive(Deserialize)]
+pub struct RenameEmojiRequest {
+    pub uuid: Uuid,
+    pub name: String,
+}
+
+/// Delete a custom emoji, and remove every reaction made with it. Requires the `manage_emoji` permission.
+/// Error conditions:
+/// - 403 (forbidden) if the user does not have permission.
+/// - 404 (not found) if the emoji does not exist.
+#[derive(Deserialize)]
+pub struct DeleteEmojiRequest {
+    pub uuid: Uuid,
+}
+
+/// Emoji names must be non-empty, at most 32 characters, and only contain letters, numbers, `-` and `_`.
+fn valid_emoji_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+fn send_emoji_list(state_lock: &LockedState) -> Result<(), CmdError> {
+    let mut packet = serde_json::to_value(EmojiListChangedResponse {
+        data: state_lock.list_emoji()?,
+    })?;
+    packet["status"] = (Status::Ok as i32).into();
+    state_lock.send_to_all(packet)?;
+    Ok(())
+}
+
+impl Request for CreateEmojiRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.manage_emoji != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        if !valid_emoji_name(&self.name) {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+
+        // disallow emoji over 32kb, they're only ever displayed tiny
+        if self.data.len() > 32 * 1024 {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        let Ok(bytes) = general_purpose::STANDARD.decode(&self.data) else {
+            return Ok(GenericResponse(Status::BadRequest));
+        };
+        if !is_png(&bytes) {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+
+        if state_lock.get_emoji_by_name(&self.name)?.is_some() {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+
+        let emoji = Emoji::new(gen_uuid(), self.name, self.data);
+        state_lock.insert_emoji(&emoji)?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::CreateEmoji,
+            Some(emoji.uuid),
+            None,
+            Some(json!({ "name": emoji.name })),
+        )?;
+        send_emoji_list(state_lock)?;
+
+        Ok(CreateEmojiResponse { uuid: emoji.uuid })
+    }
+}
+
+impl Request for RenameEmojiRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.manage_emoji != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        if !valid_emoji_name(&self.name) {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        let Some(old) = state_lock.get_emoji(self.uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if state_lock
+            .get_emoji_by_name(&self.name)?
+            .is_some_and(|e| e.uuid != self.uuid)
+        {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+
+        state_lock.rename_emoji(self.uuid, &self.name)?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::RenameEmoji,
+            Some(self.uuid),
+            Some(json!({ "name": old.name })),
+            Some(json!({ "name": self.name })),
+        )?;
+        send_emoji_list(state_lock)?;
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for DeleteEmojiRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.manage_emoji != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        let Some(emoji) = state_lock.get_emoji(self.uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        state_lock.delete_emoji(self.uuid)?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::DeleteEmoji,
+            Some(self.uuid),
+            Some(json!({ "name": emoji.name })),
+            None,
+        )?;
+        send_emoji_list(state_lock)?;
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::valid_emoji_name;
+
+    #[test]
+    fn emoji_names() {
+        assert!(valid_emoji_name("party_parrot"));
+        assert!(valid_emoji_name("thumbs-up2"));
+        assert!(!valid_emoji_name(""));
+        assert!(!valid_emoji_name("two words"));
+        assert!(!valid_emoji_name(":colons:"));
+        assert!(!valid_emoji_name(&"a".repeat(33)));
+    }
+}
@@ -5,7 +5,7 @@ use crate::commands::{
 };
 use crate::helper::LockedState;
 use crate::Peer;
-use crate::CONF;
+use base64::{engine::general_purpose, Engine as _};
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -31,34 +31,40 @@ pub struct GetUserRequest {
 }
 
 impl Request for GetMetadataRequest {
-    fn execute(self, state_lock: &mut LockedState, _: &mut Peer) -> Result<Response, CmdError> {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
         Ok(Response::GetMetadataResponse {
-            data: state_lock.get_users()?,
+            data: state_lock
+                .get_users()?
+                .into_iter()
+                .map(|u| u.seen_by(peer.uuid))
+                .collect(),
         })
     }
 }
 
 impl Request for GetUserRequest {
-    fn execute(self, state_lock: &mut LockedState, _: &mut Peer) -> Result<Response, CmdError> {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
         match state_lock.get_user(self.uuid)? {
-            Some(peer_meta) => Ok(GetUserResponse { data: peer_meta }),
+            Some(peer_meta) => Ok(GetUserResponse {
+                data: peer_meta.seen_by(peer.uuid),
+            }),
             None => Ok(GenericResponse(Status::NotFound)),
         }
     }
 }
 
 impl Request for GetIconRequest {
-    fn execute(self, _: &mut LockedState, _: &mut Peer) -> Result<Response, CmdError> {
+    fn execute(self, state_lock: &mut LockedState, _: &mut Peer) -> Result<Response, CmdError> {
         Ok(GetIconResponse {
-            data: CONF.icon.to_owned(),
+            data: general_purpose::STANDARD.encode(state_lock.get_icon()?),
         })
     }
 }
 
 impl Request for GetNameRequest {
-    fn execute(self, _: &mut LockedState, _: &mut Peer) -> Result<Response, CmdError> {
+    fn execute(self, state_lock: &mut LockedState, _: &mut Peer) -> Result<Response, CmdError> {
         Ok(GetNameResponse {
-            data: CONF.name.to_owned(),
+            data: state_lock.get_name()?,
         })
     }
 }
@@ -2,12 +2,13 @@ use std::collections::HashMap;
 
 use crate::helper::{gen_uuid, LockedState};
 use crate::message::Message;
-use crate::models::{SyncData, SyncServer};
+use crate::models::{AuditAction, Channel, SyncData, SyncServer};
 use crate::peer::Peer;
 use crate::permissions::{Perm, PermableEntity, Permissions};
+use crate::shared::LoginKey;
 use crate::{
     commands::{
-        send_metadata, CmdError, Request,
+        notify_mentions, online_response, send_metadata, CmdError, Request,
         Response::{self, *},
         Status,
     },
@@ -15,14 +16,25 @@ use crate::{
 };
 use serde::Deserialize;
 
-use super::auth::make_hash;
-use super::{channel_perms, get_viewable_channels};
+use super::attachments::MAX_ATTACHMENTS_PER_MESSAGE;
+use super::auth::{check_password, make_hash, password_allowed};
+use super::{
+    channel_perms, get_viewable_channels, record_audit, send_to_channel_readers, start_session,
+};
 
+/// Send a message to a channel.
+/// Error conditions:
+/// - 403 (forbidden) if the user cannot send messages in the channel, or it is archived, or it is an announcement
+///   channel and they don't have the `post_announcements` permission.
+/// - 429 (too many requests) if the channel is in slow mode and the user's last message was too recent.
 #[derive(Deserialize)]
 pub struct SendRequest {
     pub content: String,
     pub channel: Uuid,
     pub reply: Option<i64>,
+    /// Attachments previously uploaded with `upload_start`, `upload_chunk` and `upload_finish`
+    #[serde(default)]
+    pub attachments: Vec<Uuid>,
 }
 #[derive(Deserialize)]
 pub struct HistoryRequest {
@@ -67,13 +79,40 @@ pub struct EditRequest {
     pub new_content: String,
 }
 
+/// Restore a deleted message, if it hasn't been purged yet. Requires the `manage_messages` permission in the
+/// message's channel. On success, everyone who can read the channel is sent a `message_restored` packet with
+/// the whole message.
+/// Error conditions:
+/// - 403 (forbidden) if the user cannot manage messages in the channel.
+/// - 404 (not found) if the message does not exist or is not deleted.
+#[derive(Deserialize)]
+pub struct RestoreMessageRequest {
+    pub message: Uuid,
+}
+
+/// Get every revision of a message, oldest first and ending with its current content. Only the message's
+/// author and users with `manage_messages` in its channel can see this.
+#[derive(Deserialize)]
+pub struct GetEditHistoryRequest {
+    pub message: Uuid,
+}
+
 #[derive(Deserialize)]
 pub struct DeleteRequest {
     pub message: Uuid,
 }
 
+/// Change the user's password. Every session is revoked, and the user's other connections are sent a `kicked`
+/// packet and disconnected, so other devices will need to log in with the new password again. Returns a packet
+/// of type change_password with a field "token" containing a new session token for this connection.
+/// Error conditions:
+/// - 400 (bad request) if the new password is too short or long.
+/// - 403 (forbidden) if `old_password` is wrong.
+/// - 429 (too many requests) if there have been too many failed logins to the account, or from this address,
+///   recently. A wrong `old_password` counts as a failed login.
 #[derive(Deserialize)]
 pub struct PasswordChangeRequest {
+    pub old_password: String,
     pub new_password: String,
 }
 
@@ -82,6 +121,9 @@ pub struct CreateChannelRequest {
     pub name: String,
     pub position: Option<usize>,
     pub permissions: HashMap<PermableEntity, Permissions>,
+    /// The category to create the channel in, which `position` is then relative to.
+    #[serde(default)]
+    pub category: Option<Uuid>,
 }
 
 #[derive(Deserialize)]
@@ -89,13 +131,30 @@ pub struct DeleteChannelRequest {
     pub channel: Uuid,
 }
 
-/// Position is new position channel should take. Updates all other channel positions to allow this.
+/// The longest a channel's topic can be, in characters.
+pub const MAX_TOPIC_LEN: usize = 1024;
+/// The longest slow mode interval a channel can have, in seconds.
+pub const MAX_SLOW_MODE: u32 = 6 * 60 * 60;
+
+/// Position is new position channel should take within its category. Updates all other channel positions to
+/// allow this. To move a channel to a different category, see `move_channel`.
+/// Setting `topic` to an empty string removes it.
+/// Error conditions:
+/// - 400 (bad request) if the position is past the end of the channels, the topic is longer than
+///   `MAX_TOPIC_LEN` characters, or `slow_mode` is more than `MAX_SLOW_MODE` seconds.
+/// - 403 (forbidden) if the user does not have the `modify_channels` permission.
+/// - 404 (not found) if the channel does not exist.
 #[derive(Deserialize)]
 pub struct UpdateChannelRequest {
     pub uuid: Uuid,
     pub name: Option<String>,
     pub position: Option<usize>,
     pub permissions: Option<HashMap<PermableEntity, Permissions>>,
+    pub topic: Option<String>,
+    pub nsfw: Option<bool>,
+    pub archived: Option<bool>,
+    pub slow_mode: Option<u32>,
+    pub announcement: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -133,10 +192,31 @@ impl Request for PasswordChangeRequest {
         let Some(mut user) = state_lock.get_user(peer.uuid.unwrap())? else {
             return Ok(GenericResponse(Status::NotFound));
         };
+        let now = chrono::offset::Utc::now().timestamp();
+        let keys = [LoginKey::Addr(peer.addr.ip()), LoginKey::Account(user.uuid)];
+        if state_lock.login_blocked(&keys, now) {
+            return Ok(GenericResponse(Status::TooManyRequests));
+        }
+        if user.password.is_empty() || !check_password(&self.old_password, &user.password)? {
+            state_lock.record_login_failure(&keys, now);
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        state_lock.clear_login_failures(LoginKey::Account(user.uuid));
+        if !password_allowed(&self.new_password) {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
         user.password = make_hash(&self.new_password)?;
         state_lock.update_user(&user)?;
 
-        Ok(GenericResponse(Status::Ok))
+        state_lock.delete_sessions_of(user.uuid)?;
+        let mut packet = serde_json::to_value(KickedResponse {
+            reason: "Your password was changed".into(),
+        })?;
+        packet["status"] = (Status::Ok as i32).into();
+        state_lock.disconnect_user_except(user.uuid, peer.addr, packet);
+
+        let token = start_session(state_lock, peer, user.uuid)?;
+        Ok(PasswordChangeResponse { token })
     }
 }
 
@@ -152,11 +232,13 @@ impl Request for EditRequest {
             return Ok(GenericResponse(Status::Forbidden));
         }
 
-        state_lock.edit_message(message.uuid, self.new_content.as_str())?;
+        let now = chrono::offset::Utc::now().timestamp();
+        state_lock.edit_message(message.uuid, self.new_content.as_str(), now)?;
 
         let msg = Response::MessageEditedResponse {
             message: self.message,
             new_content: self.new_content,
+            edited_at: now,
         };
 
         let mut msg_json = serde_json::to_value(msg)?;
@@ -167,6 +249,30 @@ impl Request for EditRequest {
     }
 }
 
+impl Request for GetEditHistoryRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        let Some(message) = state_lock.get_message(self.message)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let Some(channel) = state_lock.get_channel(&message.channel_uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+
+        let perms = channel_perms(state_lock, peer.uuid, &channel)?;
+        if Some(message.author_uuid) != peer.uuid && perms.manage_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        Ok(GetEditHistoryResponse {
+            message: message.uuid,
+            data: state_lock.get_edit_history(message.uuid)?,
+        })
+    }
+}
+
 impl Request for DeleteRequest {
     fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
         if !peer.logged_in() {
@@ -185,7 +291,19 @@ impl Request for DeleteRequest {
             return Ok(GenericResponse(Status::Forbidden));
         }
 
-        state_lock.delete_message(self.message)?;
+        let now = chrono::offset::Utc::now().timestamp();
+        state_lock.soft_delete_message(self.message, peer.uuid.unwrap(), now)?;
+        // people deleting their own messages isn't moderation
+        if Some(message.author_uuid) != peer.uuid {
+            record_audit(
+                state_lock,
+                peer,
+                AuditAction::DeleteMessage,
+                Some(message.uuid),
+                Some(serde_json::to_value(&message)?),
+                None,
+            )?;
+        }
 
         let msg = Response::MessageDeletedResponse {
             message: self.message,
@@ -199,6 +317,44 @@ impl Request for DeleteRequest {
     }
 }
 
+impl Request for RestoreMessageRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        let Some(message) = state_lock.get_deleted_message(self.message)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let Some(channel) = state_lock.get_channel(&message.channel_uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+
+        let perms = channel_perms(state_lock, peer.uuid, &channel)?;
+        if perms.manage_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        state_lock.restore_message(message.uuid)?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::RestoreMessage,
+            Some(message.uuid),
+            None,
+            None,
+        )?;
+
+        let Some(message) = state_lock.get_message(message.uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let mut msg_json = serde_json::to_value(MessageRestoredResponse { message })?;
+        msg_json["status"] = (Status::Ok as i32).into();
+        send_to_channel_readers(state_lock, &channel, &msg_json)?;
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
 impl Request for NickRequest {
     fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
         if !peer.logged_in() {
@@ -231,9 +387,7 @@ impl Request for OnlineRequest {
             return Ok(GenericResponse(Status::Unauthenticated));
         }
 
-        Ok(OnlineResponse {
-            data: state_lock.online.keys().copied().collect(),
-        })
+        Ok(online_response(state_lock)?)
     }
 }
 
@@ -242,8 +396,9 @@ impl Request for SendRequest {
         if !peer.logged_in() {
             return Ok(GenericResponse(Status::Unauthenticated));
         }
-        // Check for an empty message, or one that contains only whitespace
-        if self.content.chars().all(|c| c.is_whitespace()) {
+        // Check for an empty message, or one that contains only whitespace.
+        // Messages that are just attachments don't need any text though.
+        if self.content.chars().all(|c| c.is_whitespace()) && self.attachments.is_empty() {
             return Ok(GenericResponse(Status::BadRequest));
         }
 
@@ -253,10 +408,11 @@ impl Request for SendRequest {
         };
 
         // Make sure we have permission to send messages in this channel
-        let perms = channel_perms(state_lock, peer.uuid, &channel)?;
-        if perms.send_messages != Perm::Allow {
-            return Ok(GenericResponse(Status::Forbidden));
-        }
+        let now = chrono::offset::Utc::now().timestamp();
+        let perms = match check_can_send(state_lock, peer.uuid.unwrap(), &channel, now)? {
+            Ok(perms) => perms,
+            Err(status) => return Ok(GenericResponse(status)),
+        };
 
         // check if we're replying to a message that it exists
         if let Some(r) = self.reply {
@@ -266,36 +422,100 @@ impl Request for SendRequest {
             }
         }
 
+        // only allow attaching our own uploads which haven't been sent yet
+        if self.attachments.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        let mut attachments = Vec::new();
+        for a in &self.attachments {
+            let Some(attachment) = state_lock.get_attachment(*a)? else {
+                return Ok(GenericResponse(Status::NotFound));
+            };
+            if attachment.uploader_uuid != peer.uuid.unwrap()
+                || attachment.message_uuid.is_some()
+                || attachments.contains(&attachment)
+            {
+                return Ok(GenericResponse(Status::BadRequest));
+            }
+            attachments.push(attachment);
+        }
+
         let msg = Message {
             uuid: gen_uuid(),
             content: self.content,
             author_uuid: peer.uuid.unwrap(),
             channel_uuid: self.channel,
-            date: chrono::offset::Utc::now().timestamp() as i32,
+            date: now as i32,
             edited: false,
+            edited_at: None,
+            deleted_at: None,
+            deleted_by: None,
             reply: self.reply,
+            reactions: Vec::new(),
+            attachments,
         };
-        state_lock.add_to_history(&msg)?;
-
         let uuid = msg.uuid; // save for later
+        deliver_message(state_lock, &perms, &channel, msg)?;
+        Ok(SendResponse { message: uuid })
+    }
+}
 
-        let response = ContentResponse { message: msg };
-        let mut msg_json = serde_json::to_value(response)?;
-        msg_json["status"] = (Status::Ok as i32).into();
-        // TODO test!!!
-        // also stoopid
-        for (tx, _, uuid) in state_lock.peers.iter() {
-            // Ignore users who are logged out. They cannot receive messages!
-            if uuid.is_none() {
-                continue;
-            }
-            let perms = channel_perms(state_lock, *uuid, &channel)?;
-            if perms.read_messages == Perm::Allow {
-                tx.send(msg_json.clone())?;
+/// Check whether `user` is allowed to send a message to `channel` at time `now`. Returns their permissions in the
+/// channel if so, or the status to fail with if not. Threads are archived, slowed down and read-only for
+/// announcements along with the channel they were started in.
+pub fn check_can_send(
+    state_lock: &LockedState,
+    user: Uuid,
+    channel: &Channel,
+    now: i64,
+) -> Result<Result<Permissions, Status>, CmdError> {
+    let perms = channel_perms(state_lock, Some(user), channel)?;
+    let parent = match state_lock.get_thread_parent(channel.uuid)? {
+        Some(parent) => state_lock.get_channel(&parent)?,
+        None => None,
+    };
+    let settings = parent.as_ref().unwrap_or(channel);
+    if perms.send_messages != Perm::Allow || settings.archived {
+        return Ok(Err(Status::Forbidden));
+    }
+    if settings.announcement && perms.post_announcements != Perm::Allow {
+        return Ok(Err(Status::Forbidden));
+    }
+
+    // moderators aren't slowed down by slow mode
+    if settings.slow_mode > 0 && perms.manage_messages != Perm::Allow {
+        if let Some(last) = state_lock.last_message_date(user, channel.uuid)? {
+            if now < last + settings.slow_mode as i64 {
+                return Ok(Err(Status::TooManyRequests));
             }
         }
-        Ok(SendResponse { message: uuid })
     }
+    Ok(Ok(perms))
+}
+
+/// Store a new message and send it to everyone who can read `channel`. `perms` are the author's permissions in
+/// the channel, as returned by `check_can_send`.
+pub fn deliver_message(
+    state_lock: &mut LockedState,
+    perms: &Permissions,
+    channel: &Channel,
+    msg: Message,
+) -> Result<(), CmdError> {
+    state_lock.add_to_history(&msg)?;
+    // the message arriving is enough for clients to know they've stopped typing
+    state_lock.stop_typing(channel.uuid, msg.author_uuid);
+    for a in &msg.attachments {
+        state_lock.attach_to_message(a.uuid, msg.uuid)?;
+    }
+    if state_lock.get_thread_parent(channel.uuid)?.is_some() {
+        state_lock.add_thread_participant(channel.uuid, msg.author_uuid)?;
+    }
+    notify_mentions(state_lock, perms, channel, &msg)?;
+
+    let response = ContentResponse { message: msg };
+    let mut msg_json = serde_json::to_value(response)?;
+    msg_json["status"] = (Status::Ok as i32).into();
+    send_to_channel_readers(state_lock, channel, &msg_json)
 }
 
 impl Request for HistoryRequest {
@@ -322,6 +542,13 @@ impl Request for HistoryRequest {
             Err(e) => return Err(e.into()),
         };
         history.reverse();
+        // only moderators get to see what deleted messages said
+        if perms.manage_messages != Perm::Allow {
+            history
+                .iter_mut()
+                .filter(|m| m.deleted_at.is_some())
+                .for_each(Message::redact);
+        }
 
         // simulate some lag
         // std::thread::sleep(std::time::Duration::from_secs(2));
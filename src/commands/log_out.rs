@@ -1,29 +1,48 @@
 use crate::commands::{
-    send_metadata, send_online, CmdError,
+    check_second_factor, send_metadata, send_online, start_session, CmdError,
     Response::{self, *},
 };
 use crate::commands::{Request, Status};
-use crate::helper::{gen_uuid, LockedState};
-use crate::models::User;
+use crate::helper::{gen_uuid, LockedState, Uuid};
+use crate::models::{User, UserStatus};
+use crate::shared::LoginKey;
 use crate::Peer;
 use crate::CONF;
 
 use serde::Deserialize;
 
-use super::auth::{check_password, make_hash};
+use super::auth::{check_password, hash_token, make_hash, password_allowed};
+
+/// How long after `login` accepts the password a two-factor code can be given, in seconds.
+const PENDING_2FA_TIMEOUT: i64 = 5 * 60;
 
 /// Create a new account with the given username and password. Returns a packet of type register with a field "uuid"
-/// containing the uuid of the newly created account.  
+/// containing the uuid of the newly created account, and a field "token" containing a session token for it.  
 /// Error conditions:
 /// - 409 (conflict) if the username already exists within the server.
-/// - 400 (bad request) if the username is empty or entirely whitespace.
+/// - 400 (bad request) if the username is empty or entirely whitespace, or the password is too short or long.
 /// - 405 (method not allowed) if already logged in.
+/// - 403 (forbidden) if connecting from an address that a currently banned user connected from, unless
+///   `block_banned_addrs` is turned off.
 #[derive(Deserialize)]
 pub struct RegisterRequest {
     pub passwd: String,
     pub uname: String,
 }
 
+/// Log in with a username or uuid and password. Returns a packet of type login with a field "uuid" containing
+/// the user's uuid, and a field "token" containing a new session token which can be given to `login_token` to
+/// log in again later without the password.
+/// If the account has two-factor authentication turned on, the response has status 401 instead, and the login
+/// has to be finished by sending `login_2fa` with a code.
+/// Error conditions:
+/// - 400 (bad request) if neither `uname` nor `uuid` were given.
+/// - 401 (unauthenticated) if the password is right, but a two-factor code is needed as well.
+/// - 403 (forbidden) if the password is wrong or the user is banned.
+/// - 404 (not found) if the user does not exist.
+/// - 405 (method not allowed) if already logged in.
+/// - 429 (too many requests) if there have been too many failed logins to the account, or from this address,
+///   recently. Each failure doubles how long to wait before trying again, up to a temporary lockout.
 #[derive(Deserialize)]
 pub struct LoginRequest {
     pub passwd: String,
@@ -31,6 +50,48 @@ pub struct LoginRequest {
     pub uuid: Option<i64>,
 }
 
+/// Log in with a session token from a previous `login` or `register`. Returns a packet of type login_token with
+/// a field "uuid" containing the user's uuid.
+/// Error conditions:
+/// - 403 (forbidden) if the token is invalid, expired or revoked, or the user is banned.
+/// - 405 (method not allowed) if already logged in.
+/// - 429 (too many requests) if there have been too many failed logins from this address recently. Failed
+///   password logins to the account don't matter here, so locking an account out doesn't log out its devices.
+#[derive(Deserialize)]
+pub struct LoginTokenRequest {
+    pub token: String,
+}
+
+/// Finish logging in to an account with two-factor authentication, after `login` has accepted the password.
+/// `code` is either a code from the authenticator or one of the recovery codes. Returns a packet of type
+/// login_2fa with the same fields as `login`.
+/// Error conditions:
+/// - 403 (forbidden) if the code is wrong, or the user has been banned since `login`.
+/// - 405 (method not allowed) if already logged in, or `login` hasn't asked for a code in the last
+///   `PENDING_2FA_TIMEOUT` seconds.
+/// - 429 (too many requests) if there have been too many failed logins to the account, or from this address,
+///   recently. A wrong code counts as a failed login.
+#[derive(Deserialize)]
+pub struct Login2faRequest {
+    pub code: String,
+}
+
+/// Log the connection in as `user` and tell everyone they're online.
+fn log_in_as(state_lock: &mut LockedState, peer: &mut Peer, user: Uuid) {
+    peer.uuid = Some(user);
+    // stoopid
+    // (actually this just makes sure that the shared's peers list has the right uuid)
+    for p in &mut state_lock.peers {
+        if p.1 == peer.addr {
+            p.2 = Some(user);
+        }
+    }
+
+    state_lock.inc_online(user);
+    send_metadata(state_lock, peer);
+    send_online(state_lock);
+}
+
 impl Request for RegisterRequest {
     fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
         if peer.logged_in() {
@@ -39,10 +100,16 @@ impl Request for RegisterRequest {
         }
 
         // do not allow empty usernames
-        if self.uname.trim().is_empty() {
+        if self.uname.trim().is_empty() || !password_allowed(&self.passwd) {
             return Ok(GenericResponse(Status::BadRequest));
         }
 
+        // do not allow banned users to dodge their ban with a new account
+        let now = chrono::offset::Utc::now().timestamp();
+        if CONF.block_banned_addrs && state_lock.is_addr_banned(&peer.addr.ip(), now)? {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
         // do not allow registering a duplicate username
         if state_lock
             .get_user_by_name(&self.uname)
@@ -57,24 +124,18 @@ impl Request for RegisterRequest {
             uuid: gen_uuid(),
             password: make_hash(&self.passwd)?,
             groups: Vec::new(),
+            status: UserStatus::Online,
+            status_text: None,
         };
 
         state_lock.insert_user(&user)?;
-        peer.uuid = Some(user.uuid);
-
-        // stoopid
-        for p in &mut state_lock.peers {
-            if p.1 == peer.addr {
-                p.2 = Some(user.uuid);
-            }
-        }
-
-        state_lock.inc_online(user.uuid);
+        let token = start_session(state_lock, peer, user.uuid)?;
+        log_in_as(state_lock, peer, user.uuid);
 
-        send_metadata(state_lock, peer);
-        send_online(state_lock);
-
-        Ok(RegisterResponse { uuid: user.uuid })
+        Ok(RegisterResponse {
+            uuid: user.uuid,
+            token,
+        })
     }
 }
 
@@ -84,6 +145,14 @@ impl Request for LoginRequest {
             //logging in doesn't make sense when already logged in
             return Ok(GenericResponse(Status::MethodNotAllowed));
         }
+        // a new attempt replaces any earlier one that was waiting for a code
+        peer.pending_2fa = None;
+
+        let now = chrono::offset::Utc::now().timestamp();
+        let addr = LoginKey::Addr(peer.addr.ip());
+        if state_lock.login_blocked(&[addr], now) {
+            return Ok(GenericResponse(Status::TooManyRequests));
+        }
 
         let user = if let Some(uname) = &self.uname {
             state_lock.get_user_by_name(uname)?
@@ -95,30 +164,104 @@ impl Request for LoginRequest {
         };
 
         // check the user exists
-        let Some(mut user) = user else {
+        let Some(user) = user else {
+            state_lock.record_login_failure(&[addr], now);
             return Ok(GenericResponse(Status::NotFound));
         };
 
-        // TODO temporarily allow users without passwords to log in
-        if user.password.is_empty() {
-            user.password = make_hash(&self.passwd)?;
-            state_lock.update_user(&user)?;
-        } else if !check_password(&self.passwd, &user.password)? {
+        let account = LoginKey::Account(user.uuid);
+        if state_lock.login_blocked(&[account], now) {
+            return Ok(GenericResponse(Status::TooManyRequests));
+        }
+        // accounts without a password can't be logged in to
+        if user.password.is_empty() || !check_password(&self.passwd, &user.password)? {
+            state_lock.record_login_failure(&[addr, account], now);
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        if state_lock.get_ban(user.uuid, now)?.is_some() {
             return Ok(GenericResponse(Status::Forbidden));
         }
 
-        peer.uuid = Some(user.uuid);
-        // stoopid: the sequel
-        // (actually this just makes sure that the shared's peers list has the right uuid)
-        for p in &mut state_lock.peers {
-            if p.1 == peer.addr {
-                p.2 = Some(user.uuid);
-            }
+        // the failures aren't forgiven until the second factor is given too, or else the password could be
+        // used to keep guessing codes
+        if state_lock.get_totp(user.uuid)?.is_some_and(|t| t.enabled) {
+            peer.pending_2fa = Some((user.uuid, now));
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        // only the account is forgiven, so that logging in to one account can't be used to keep guessing
+        // the passwords of others
+        state_lock.clear_login_failures(account);
+
+        let token = start_session(state_lock, peer, user.uuid)?;
+        log_in_as(state_lock, peer, user.uuid);
+        Ok(LoginResponse {
+            uuid: user.uuid,
+            token,
+        })
+    }
+}
+
+impl Request for Login2faRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if peer.logged_in() {
+            return Ok(GenericResponse(Status::MethodNotAllowed));
+        }
+        let now = chrono::offset::Utc::now().timestamp();
+        let Some((user, _)) = peer
+            .pending_2fa
+            .filter(|&(_, since)| now - since <= PENDING_2FA_TIMEOUT)
+        else {
+            peer.pending_2fa = None;
+            return Ok(GenericResponse(Status::MethodNotAllowed));
+        };
+
+        let account = LoginKey::Account(user);
+        let keys = [LoginKey::Addr(peer.addr.ip()), account];
+        if state_lock.login_blocked(&keys, now) {
+            return Ok(GenericResponse(Status::TooManyRequests));
+        }
+        if !check_second_factor(state_lock, user, &self.code, now)? {
+            state_lock.record_login_failure(&keys, now);
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        state_lock.clear_login_failures(account);
+        peer.pending_2fa = None;
+        // the ban may have come after the password was accepted
+        if state_lock.get_ban(user, now)?.is_some() {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        let token = start_session(state_lock, peer, user)?;
+        log_in_as(state_lock, peer, user);
+        Ok(Login2faResponse { uuid: user, token })
+    }
+}
+
+impl Request for LoginTokenRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if peer.logged_in() {
+            return Ok(GenericResponse(Status::MethodNotAllowed));
+        }
+        peer.pending_2fa = None;
+
+        let now = chrono::offset::Utc::now().timestamp();
+        let addr = LoginKey::Addr(peer.addr.ip());
+        if state_lock.login_blocked(&[addr], now) {
+            return Ok(GenericResponse(Status::TooManyRequests));
+        }
+        let Some(session) = state_lock.get_session_by_token(&hash_token(&self.token), now)? else {
+            state_lock.record_login_failure(&[addr], now);
+            return Ok(GenericResponse(Status::Forbidden));
+        };
+        if state_lock.get_ban(session.user_uuid, now)?.is_some() {
+            return Ok(GenericResponse(Status::Forbidden));
         }
 
-        state_lock.inc_online(user.uuid);
-        send_metadata(state_lock, peer);
-        send_online(state_lock);
-        Ok(LoginResponse { uuid: user.uuid })
+        state_lock.touch_session(session.uuid, now)?;
+        peer.session = Some(session.uuid);
+        log_in_as(state_lock, peer, session.user_uuid);
+        Ok(LoginTokenResponse {
+            uuid: session.user_uuid,
+        })
     }
 }
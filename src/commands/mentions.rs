@@ -0,0 +1,128 @@
+use crate::commands::{
+    CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{LockedState, Uuid};
+use crate::message::{parse_mentions, Mention, Message};
+use crate::models::{Channel, User};
+use crate::permissions::{Perm, Permissions};
+use crate::Peer;
+use serde::Deserialize;
+
+/// The most mentions that will be returned by a single `get_mentions`, regardless of `num`.
+const MAX_MENTIONS: u32 = 100;
+
+/// Get the messages that mention the user, from every channel they can still read. Returns a packet of type
+/// get_mentions with a field "data" containing up to `num` (at most 100) messages, newest first. To get the
+/// next page, request again with `before_message` set to the uuid of the last (oldest) message.
+/// Error conditions:
+/// - 404 (not found) if `before_message` does not exist.
+#[derive(Deserialize)]
+pub struct GetMentionsRequest {
+    pub num: u32,
+    pub before_message: Option<Uuid>,
+}
+
+fn can_read(state_lock: &LockedState, user: &User, channel: &Channel) -> Result<bool, CmdError> {
+    Ok(state_lock
+        .resolve_channel_permissions(user, channel)?
+        .read_messages
+        == Perm::Allow)
+}
+
+/// Store who a newly sent message mentions, and send each of them a `mentioned` packet containing the message.
+/// Only users who can read the channel are mentioned, and never the author. Mentioning a group or `@everyone`
+/// needs the `mention_everyone` permission in the channel; without it, those mentions are left as plain text.
+/// Users who have muted the channel aren't sent the packet.
+pub fn notify_mentions(
+    state_lock: &LockedState,
+    perms: &Permissions,
+    channel: &Channel,
+    msg: &Message,
+) -> Result<(), CmdError> {
+    let mentions = parse_mentions(&msg.content);
+    if mentions.is_empty() {
+        return Ok(());
+    }
+    let mass_mentions = perms.mention_everyone == Perm::Allow;
+
+    let mut candidates = Vec::new();
+    for mention in mentions {
+        match mention {
+            Mention::User(uuid) => candidates.push(uuid),
+            Mention::Group(uuid) if mass_mentions => {
+                candidates.extend(state_lock.get_group_members(uuid)?)
+            }
+            Mention::Everyone if mass_mentions => {
+                candidates.extend(state_lock.get_users()?.iter().map(|u| u.uuid))
+            }
+            _ => {}
+        }
+    }
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    let mut mentioned = Vec::new();
+    for uuid in candidates {
+        if uuid == msg.author_uuid {
+            continue;
+        }
+        let Some(user) = state_lock.get_user(uuid)? else {
+            continue;
+        };
+        if can_read(state_lock, &user, channel)? {
+            mentioned.push(uuid);
+        }
+    }
+    state_lock.insert_mentions(msg.uuid, &mentioned)?;
+
+    // mentions in muted channels still go in the inbox, they just aren't pushed
+    let mut packet = serde_json::to_value(MentionedResponse {
+        message: msg.clone(),
+    })?;
+    packet["status"] = (Status::Ok as i32).into();
+    let now = chrono::offset::Utc::now().timestamp();
+    for uuid in mentioned {
+        if !state_lock
+            .get_channel_notify_settings(uuid, channel.uuid)?
+            .is_muted(now)
+        {
+            state_lock.send_to_user(uuid, packet.clone())?;
+        }
+    }
+    Ok(())
+}
+
+impl Request for GetMentionsRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let Some(user) = state_lock.get_user(uuid)? else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+
+        let num = self.num.min(MAX_MENTIONS);
+        let mentions = match state_lock.get_mentions(uuid, num, self.before_message) {
+            Ok(mentions) => mentions,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Ok(GenericResponse(Status::NotFound))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // the user may have lost access to some channels since they were mentioned
+        let mut data = Vec::new();
+        for msg in mentions {
+            let Some(channel) = state_lock.get_channel(&msg.channel_uuid)? else {
+                continue;
+            };
+            if can_read(state_lock, &user, &channel)? {
+                data.push(msg);
+            }
+        }
+
+        Ok(GetMentionsResponse { data })
+    }
+}
@@ -1,24 +1,67 @@
+mod announcements;
+mod attachments;
+mod audit;
 pub mod auth;
+mod categories;
+mod dm;
+mod emoji;
 mod log_any;
 mod log_in;
 mod log_out;
-
+mod mentions;
+mod moderation;
+mod notify;
+mod pins;
+mod presence;
+mod reactions;
+pub mod scheduled;
+mod search;
+mod server_config;
+mod sessions;
+mod threads;
+mod two_factor;
+pub mod typing;
+
+use announcements::*;
+use attachments::*;
+use audit::*;
+use categories::*;
+use dm::*;
+use emoji::*;
 use log_any::*;
 use log_in::*;
 use log_out::*;
+use mentions::*;
+use moderation::*;
+use notify::*;
+use pins::*;
+use presence::*;
+use reactions::*;
+use scheduled::*;
+use search::*;
+use server_config::*;
+use sessions::*;
+use threads::*;
+use two_factor::*;
+use typing::*;
 
 use crate::helper::{gen_uuid, JsonValue, LockedState, Uuid};
-use crate::message::Message;
+use crate::message::{Attachment, Message, MessageRevision, ReactionEmoji, ScheduledMessage};
 use crate::peer::Peer;
 
-use crate::models::{Channel, Emoji, Group, SyncData, SyncServer, User};
+use crate::models::{
+    AuditAction, AuditLogEntry, Ban, Category, Channel, Emoji, Group, NotifySettings, Presence,
+    Session, SyncData, SyncServer, Thread, User, UserStatus,
+};
 use crate::permissions::{Perm, Permissions};
 use crate::shared::DbError;
+use crate::CONF;
 use enum_dispatch::enum_dispatch;
 use serde::{Deserialize, Serialize, Serializer};
 use serde_json::json;
 use std::collections::HashMap;
 use std::error::Error;
+use std::time::Instant;
 
 #[derive(Clone, Copy, Debug)]
 pub enum Status {
@@ -30,6 +73,7 @@ pub enum Status {
     NotFound = 404,
     MethodNotAllowed = 405,
     Conflict = 409,
+    TooManyRequests = 429,
 }
 
 impl Serialize for Status {
@@ -117,6 +161,8 @@ pub struct GetNumUnreadRequest {
 pub enum Requests {
     #[serde(rename = "register")]         RegisterRequest,
     #[serde(rename = "login")]            LoginRequest,
+    #[serde(rename = "login_token")]      LoginTokenRequest,
+    #[serde(rename = "login_2fa")]        Login2faRequest,
     #[serde(rename = "ping")]             PingRequest,
     #[serde(rename = "nick")]             NickRequest,
     #[serde(rename = "online")]           OnlineRequest,
@@ -141,6 +187,11 @@ pub enum Requests {
     #[serde(rename = "create_channel")]   CreateChannelRequest,
     #[serde(rename = "delete_channel")]   DeleteChannelRequest,
     #[serde(rename = "update_channel")]   UpdateChannelRequest,
+    #[serde(rename = "move_channel")]     MoveChannelRequest,
+    #[serde(rename = "list_categories")]  ListCategoriesRequest,
+    #[serde(rename = "create_category")]  CreateCategoryRequest,
+    #[serde(rename = "update_category")]  UpdateCategoryRequest,
+    #[serde(rename = "delete_category")]  DeleteCategoryRequest,
     #[serde(rename = "create_group")]     CreateGroupRequest,
     #[serde(rename = "delete_group")]     DeleteGroupRequest,
     #[serde(rename = "update_group")]     UpdateGroupRequest,
@@ -150,6 +201,69 @@ pub enum Requests {
     #[serde(rename = "get_last_reads")]   GetLastReadsRequest,
     #[serde(rename = "mark_as_read")]     MarkAsReadRequest,
     #[serde(rename = "get_num_unread")]   GetNumUnreadRequest,
+
+    #[serde(rename = "open_dm")]          OpenDmRequest,
+    #[serde(rename = "list_dms")]         ListDmsRequest,
+
+    #[serde(rename = "ban")]              BanRequest,
+    #[serde(rename = "unban")]            UnbanRequest,
+    #[serde(rename = "kick")]             KickRequest,
+    #[serde(rename = "list_bans")]        ListBansRequest,
+
+    #[serde(rename = "set_server_name")]  SetServerNameRequest,
+    #[serde(rename = "set_server_icon")]  SetServerIconRequest,
+    #[serde(rename = "set_base_permissions")] SetBasePermissionsRequest,
+    #[serde(rename = "get_base_permissions")] GetBasePermissionsRequest,
+
+    #[serde(rename = "create_emoji")]     CreateEmojiRequest,
+    #[serde(rename = "rename_emoji")]     RenameEmojiRequest,
+    #[serde(rename = "delete_emoji")]     DeleteEmojiRequest,
+
+    #[serde(rename = "add_reaction")]     AddReactionRequest,
+    #[serde(rename = "remove_reaction")]  RemoveReactionRequest,
+
+    #[serde(rename = "upload_start")]     UploadStartRequest,
+    #[serde(rename = "upload_chunk")]     UploadChunkRequest,
+    #[serde(rename = "upload_finish")]    UploadFinishRequest,
+    #[serde(rename = "get_attachment")]   GetAttachmentRequest,
+
+    #[serde(rename = "search")]           SearchRequest,
+
+    #[serde(rename = "create_thread")]    CreateThreadRequest,
+    #[serde(rename = "thread_history")]   ThreadHistoryRequest,
+    #[serde(rename = "list_threads")]     ListThreadsRequest,
+
+    #[serde(rename = "pin_message")]      PinMessageRequest,
+    #[serde(rename = "unpin_message")]    UnpinMessageRequest,
+    #[serde(rename = "list_pins")]        ListPinsRequest,
+
+    #[serde(rename = "get_edit_history")] GetEditHistoryRequest,
+    #[serde(rename = "restore_message")]  RestoreMessageRequest,
+
+    #[serde(rename = "get_audit_log")]    GetAuditLogRequest,
+
+    #[serde(rename = "typing")]           TypingRequest,
+    #[serde(rename = "set_status")]       SetStatusRequest,
+
+    #[serde(rename = "get_mentions")]     GetMentionsRequest,
+
+    #[serde(rename = "follow_channel")]   FollowChannelRequest,
+    #[serde(rename = "unfollow_channel")] UnfollowChannelRequest,
+    #[serde(rename = "get_followers")]    GetFollowersRequest,
+    #[serde(rename = "publish_message")]  PublishMessageRequest,
+
+    #[serde(rename = "set_channel_notify")] SetChannelNotifyRequest,
+    #[serde(rename = "get_notify_settings")] GetNotifySettingsRequest,
+
+    #[serde(rename = "schedule_message")] ScheduleMessageRequest,
+    #[serde(rename = "list_scheduled")]   ListScheduledRequest,
+    #[serde(rename = "cancel_scheduled")] CancelScheduledRequest,
+
+    #[serde(rename = "list_sessions")]    ListSessionsRequest,
+    #[serde(rename = "revoke_session")]   RevokeSessionRequest,
+    #[serde(rename = "enable_2fa")]       Enable2faRequest,
+    #[serde(rename = "confirm_2fa")]      Confirm2faRequest,
+    #[serde(rename = "disable_2fa")]      Disable2faRequest,
 }
 
 #[derive(Serialize)]
@@ -157,26 +271,67 @@ pub enum Requests {
 #[rustfmt::skip]
 pub enum Response {  
     #[serde(rename = "API_version")]      APIVersionResponse { version: [u8; 3] },
-    #[serde(rename = "register")]         RegisterResponse { uuid: i64 },
-    #[serde(rename = "login")]            LoginResponse { uuid: i64 },
+    #[serde(rename = "register")]         RegisterResponse { uuid: i64, token: String },
+    #[serde(rename = "login")]            LoginResponse { uuid: i64, token: String },
+    #[serde(rename = "login_token")]      LoginTokenResponse { uuid: i64 },
+    #[serde(rename = "login_2fa")]        Login2faResponse { uuid: i64, token: String },
+    #[serde(rename = "change_password")]  PasswordChangeResponse { token: String },
+    #[serde(rename = "list_sessions")]    ListSessionsResponse { data: Vec<Session>, current: Option<Uuid> },
+    #[serde(rename = "enable_2fa")]       Enable2faResponse { secret: String, uri: String },
+    #[serde(rename = "confirm_2fa")]      Confirm2faResponse { recovery_codes: Vec<String>, token: String },
     #[serde(rename = "get_metadata")]     GetMetadataResponse { data: Vec<User> },
     #[serde(rename = "sync_get_servers")] SyncGetServersResponse { servers: Vec<SyncServer> },
-    #[serde(rename = "online")]           OnlineResponse { data: Vec<i64> },
+    #[serde(rename = "online")]           OnlineResponse { data: Vec<i64>, presence: Vec<Presence> },
     #[serde(rename = "history")]          HistoryResponse { data: Vec<Message> },
     #[serde(rename = "get_user")]         GetUserResponse { data: User },
     #[serde(rename = "get_icon")]         GetIconResponse { data: String },
     #[serde(rename = "get_name")]         GetNameResponse { data: String },
     #[serde(rename = "list_channels")]    ListChannelsResponse { data: Vec<Channel> },
+    #[serde(rename = "list_categories")]  ListCategoriesResponse { data: Vec<Category> },
+    #[serde(rename = "create_category")]  CreateCategoryResponse { uuid: Uuid },
     #[serde(rename = "get_emoji")]        GetEmojiResponse { data: Emoji },
     #[serde(rename = "list_emoji")]       ListEmojiResponse { data: Vec<(String, i64)> },
     #[serde(rename = "send")]             SendResponse { message: Uuid },
-    #[serde(rename = "message_edited")]   MessageEditedResponse { message: Uuid, new_content: String },
+    #[serde(rename = "message_edited")]   MessageEditedResponse { message: Uuid, new_content: String, edited_at: i64 },
     #[serde(rename = "message_deleted")]  MessageDeletedResponse { message: Uuid },
     #[serde(rename = "list_groups")]      ListGroupsResponse { data: Vec<Group> },
     #[serde(rename = "create_channel")]   CreateChannelResponse { uuid: Uuid },
 
-    #[serde(rename = "get_last_reads")]   GetLastReadsResponse { last_reads: HashMap<Uuid, (i64, u32)> },
+    #[serde(rename = "get_last_reads")]   GetLastReadsResponse { last_reads: HashMap<Uuid, (i64, u32)>, muted: Vec<Uuid> },
     #[serde(rename = "get_num_unread")]   GetNumUnreadResponse { num: u32 },
+    #[serde(rename = "open_dm")]          OpenDmResponse { data: Channel },
+    #[serde(rename = "list_dms")]         ListDmsResponse { data: Vec<Channel> },
+    #[serde(rename = "list_bans")]        ListBansResponse { data: Vec<Ban> },
+    #[serde(rename = "banned")]           BannedResponse { reason: String, expires: Option<i64> },
+    #[serde(rename = "kicked")]           KickedResponse { reason: String },
+    #[serde(rename = "get_base_permissions")] GetBasePermissionsResponse { data: Permissions },
+    #[serde(rename = "create_emoji")]     CreateEmojiResponse { uuid: Uuid },
+    #[serde(rename = "emoji_list_changed")] EmojiListChangedResponse { data: Vec<(String, i64)> },
+    #[serde(rename = "reaction_added")]   ReactionAddedResponse { message: Uuid, user: Uuid, emoji: ReactionEmoji },
+    #[serde(rename = "reaction_removed")] ReactionRemovedResponse { message: Uuid, user: Uuid, emoji: ReactionEmoji },
+    #[serde(rename = "upload_start")]     UploadStartResponse { upload: Uuid },
+    #[serde(rename = "upload_finish")]    UploadFinishResponse { data: Attachment },
+    #[serde(rename = "get_attachment")]   GetAttachmentResponse { uuid: Uuid, offset: usize, size: usize, data: String },
+    #[serde(rename = "search")]           SearchResponse { data: Vec<Message> },
+    #[serde(rename = "get_mentions")]     GetMentionsResponse { data: Vec<Message> },
+    #[serde(rename = "mentioned")]        MentionedResponse { message: Message },
+    #[serde(rename = "get_followers")]    GetFollowersResponse { data: Vec<Uuid> },
+    #[serde(rename = "publish_message")]  PublishMessageResponse { messages: Vec<Uuid> },
+    #[serde(rename = "get_notify_settings")] GetNotifySettingsResponse { data: Vec<NotifySettings> },
+    #[serde(rename = "schedule_message")] ScheduleMessageResponse { uuid: Uuid },
+    #[serde(rename = "list_scheduled")]   ListScheduledResponse { data: Vec<ScheduledMessage> },
+    #[serde(rename = "scheduled_failed")] ScheduledFailedResponse { uuid: Uuid, reason: Status },
+    #[serde(rename = "thread_created")]   ThreadCreatedResponse { data: Thread },
+    #[serde(rename = "thread_history")]   ThreadHistoryResponse { thread: Uuid, data: Vec<Message> },
+    #[serde(rename = "list_threads")]     ListThreadsResponse { data: Vec<Thread> },
+    #[serde(rename = "message_pinned")]   MessagePinnedResponse { channel: Uuid, message: Uuid, user: Uuid },
+    #[serde(rename = "message_unpinned")] MessageUnpinnedResponse { channel: Uuid, message: Uuid, user: Uuid },
+    #[serde(rename = "list_pins")]        ListPinsResponse { data: Vec<Message> },
+    #[serde(rename = "get_edit_history")] GetEditHistoryResponse { message: Uuid, data: Vec<MessageRevision> },
+    #[serde(rename = "message_restored")] MessageRestoredResponse { message: Message },
+    #[serde(rename = "get_audit_log")]    GetAuditLogResponse { data: Vec<AuditLogEntry> },
+    #[serde(rename = "user_typing")]      UserTypingResponse { channel: Uuid, user: Uuid, expires: i64 },
+    #[serde(rename = "user_stopped_typing")] UserStoppedTypingResponse { channel: Uuid, user: Uuid },
 
     #[serde(rename = "content")]
     ContentResponse {
@@ -215,6 +370,13 @@ impl HasOrder for Channel {
             name: self.name,
             position: pos,
             permissions: self.permissions,
+            dm_members: self.dm_members,
+            category: self.category,
+            topic: self.topic,
+            nsfw: self.nsfw,
+            archived: self.archived,
+            slow_mode: self.slow_mode,
+            announcement: self.announcement,
         }
     }
 }
@@ -291,9 +453,23 @@ fn get_viewable_channels(
     Ok(our_channels)
 }
 
+/// Get the server's channels in `category`, or the uncategorised channels if it's `None`, in order.
+fn channels_in_category(
+    state_lock: &LockedState,
+    category: Option<Uuid>,
+) -> Result<Vec<Channel>, DbError> {
+    let mut channels: Vec<_> = state_lock
+        .get_channels()?
+        .into_iter()
+        .filter(|c| c.category == category)
+        .collect();
+    channels.sort_unstable_by_key(|c| c.position);
+    Ok(channels)
+}
+
 fn update_channels(state_lock: &mut LockedState) -> Result<(), CmdError> {
     let channels = state_lock.get_channels()?;
-    for (tx, _, uuid) in state_lock.peers.iter() {
+    for (tx, _, uuid, _) in state_lock.peers.iter() {
         if let Some(uuid) = uuid {
             let user = state_lock.get_user_exists(*uuid)?;
             let our_channels = get_viewable_channels(state_lock, &channels, &user)?;
@@ -326,6 +502,29 @@ pub fn channel_perms(
     state_lock.resolve_channel_permissions(&user, channel)
 }
 
+/// Send a packet to every logged in peer who is allowed to read messages in `channel`.
+/// Direct message channels skip the permission resolution and only go to their two members.
+pub fn send_to_channel_readers(
+    state_lock: &LockedState,
+    channel: &Channel,
+    packet: &JsonValue,
+) -> Result<(), CmdError> {
+    for (tx, _, uuid, _) in state_lock.peers.iter() {
+        // Ignore users who are logged out. They cannot receive messages!
+        let Some(uuid) = uuid else {
+            continue;
+        };
+        let can_read = match channel.dm_members {
+            Some((a, b)) => *uuid == a || *uuid == b,
+            None => channel_perms(state_lock, Some(*uuid), channel)?.read_messages == Perm::Allow,
+        };
+        if can_read {
+            tx.send(packet.clone())?;
+        }
+    }
+    Ok(())
+}
+
 impl Request for GetLastReadsRequest {
     fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
         let Some(user_uuid) = peer.uuid else {
@@ -333,8 +532,15 @@ impl Request for GetLastReadsRequest {
         };
 
         let last_reads = state_lock.get_last_read_messages(user_uuid)?;
-
-        Ok(GetLastReadsResponse { last_reads })
+        let now = chrono::offset::Utc::now().timestamp();
+        let muted = state_lock
+            .get_notify_settings(user_uuid)?
+            .iter()
+            .filter(|s| s.is_muted(now))
+            .map(|s| s.channel_uuid)
+            .collect();
+
+        Ok(GetLastReadsResponse { last_reads, muted })
     }
 }
 
@@ -390,8 +596,17 @@ impl Request for UpdateUserGroupsRequest {
             }
         }
 
+        let before = serde_json::to_value(&user.groups)?;
         user.groups = self.groups;
         state_lock.update_user(&user)?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::UpdateUserGroups,
+            Some(user.uuid),
+            Some(before),
+            Some(serde_json::to_value(&user.groups)?),
+        )?;
 
         Ok(GenericResponse(Status::Ok))
     }
@@ -421,17 +636,26 @@ impl Request for CreateGroupRequest {
             return Ok(GenericResponse(Status::BadRequest));
         }
 
-        state_lock.insert_group(&Group {
+        let group = Group {
             uuid: gen_uuid(),
             permissions: self.permissions,
             name: self.name,
             colour: self.colour,
             position: next_position,
-        })?;
+        };
+        state_lock.insert_group(&group)?;
 
         moveto(next_position, self.position, groups, |g| {
             state_lock.update_group(&g)
         })?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::CreateGroup,
+            Some(group.uuid),
+            None,
+            Some(serde_json::to_value(group.with_pos(self.position))?),
+        )?;
 
         update_groups(state_lock)?;
         Ok(GenericResponse(Status::Ok))
@@ -457,6 +681,14 @@ impl Request for DeleteGroupRequest {
         }
 
         state_lock.delete_group(self.uuid)?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::DeleteGroup,
+            Some(group.uuid),
+            Some(serde_json::to_value(&group)?),
+            None,
+        )?;
         // shift down the groups
         for g in state_lock.get_groups()? {
             if g.position > group.position {
@@ -481,6 +713,7 @@ impl Request for UpdateGroupRequest {
         let Some(old) = state_lock.get_group(self.uuid)? else {
             return Ok(GenericResponse(Status::NotFound));
         };
+        let before = serde_json::to_value(&old)?;
 
         let position = self.position.unwrap_or(old.position);
         let name = self.name.unwrap_or(old.name);
@@ -507,7 +740,16 @@ impl Request for UpdateGroupRequest {
         })?;
 
         state_lock.update_group(&new_group)?;
-        todo!();
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::UpdateGroup,
+            Some(new_group.uuid),
+            Some(before),
+            Some(serde_json::to_value(&new_group)?),
+        )?;
+        update_groups(state_lock)?;
+        Ok(GenericResponse(Status::Ok))
     }
 }
 
@@ -522,7 +764,12 @@ impl Request for CreateChannelRequest {
         // TODO consider forbidding altering perms for higher groups
         // TODO consider forbidding altering perms if no modify_groups perm
 
-        let channels = state_lock.get_channels()?;
+        if let Some(category) = self.category {
+            if state_lock.get_category(category)?.is_none() {
+                return Ok(GenericResponse(Status::NotFound));
+            }
+        }
+        let channels = channels_in_category(state_lock, self.category)?;
 
         // Cannot add a channel (too far) past the end of existing channels
         let next_position = channels.iter().map(|c| c.position + 1).max().unwrap_or(0);
@@ -532,16 +779,32 @@ impl Request for CreateChannelRequest {
         }
 
         let uuid = gen_uuid();
-        state_lock.insert_channel(&Channel {
+        let channel = Channel {
             uuid,
             name: self.name,
             permissions: HashMap::new(),
+            dm_members: None,
+            category: self.category,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
             position: next_position,
-        })?;
+        };
+        state_lock.insert_channel(&channel)?;
 
         moveto(next_position, position, channels, |channel| {
             state_lock.update_channel(&channel)
         })?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::CreateChannel,
+            Some(uuid),
+            None,
+            Some(serde_json::to_value(channel.with_pos(position))?),
+        )?;
         update_channels(state_lock)?;
         Ok(CreateChannelResponse { uuid })
     }
@@ -558,10 +821,22 @@ impl Request for DeleteChannelRequest {
         let Some(channel) = state_lock.get_channel(&self.channel)? else {
             return Ok(GenericResponse(Status::NotFound));
         };
+        // direct message channels and threads aren't part of the server's channel list
+        if channel.is_dm() || state_lock.get_thread_parent(channel.uuid)?.is_some() {
+            return Ok(GenericResponse(Status::NotFound));
+        }
 
         state_lock.delete_channel(self.channel)?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::DeleteChannel,
+            Some(channel.uuid),
+            Some(serde_json::to_value(&channel)?),
+            None,
+        )?;
         // shift down the channels
-        for c in state_lock.get_channels()? {
+        for c in channels_in_category(state_lock, channel.category)? {
             if c.position > channel.position {
                 let new_pos = c.position - 1;
                 state_lock.update_channel(&c.with_pos(new_pos))?;
@@ -582,19 +857,45 @@ impl Request for UpdateChannelRequest {
         }
         // TODO consider forbidding altering perms for higher groups
         // TODO consider forbidding altering perms if no modify_groups perm
-        let channels = state_lock.get_channels()?;
         let Some(old_channel) = state_lock.get_channel(&self.uuid)? else {
             return Ok(GenericResponse(Status::NotFound));
         };
+        if old_channel.is_dm() || state_lock.get_thread_parent(old_channel.uuid)?.is_some() {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+        let channels = channels_in_category(state_lock, old_channel.category)?;
+        let before = serde_json::to_value(&old_channel)?;
 
         let position = self.position.unwrap_or(old_channel.position);
         let name = self.name.unwrap_or(old_channel.name);
         let permissions = self.permissions.unwrap_or(old_channel.permissions);
+        let topic = match self.topic {
+            Some(topic) if topic.trim().is_empty() => None,
+            Some(topic) => Some(topic.trim().to_owned()),
+            None => old_channel.topic,
+        };
+        if topic
+            .as_ref()
+            .is_some_and(|t| t.chars().count() > MAX_TOPIC_LEN)
+        {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        let slow_mode = self.slow_mode.unwrap_or(old_channel.slow_mode);
+        if slow_mode > MAX_SLOW_MODE {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
         let new_channel = Channel {
             uuid: self.uuid,
             name,
             permissions,
             position,
+            dm_members: None,
+            category: old_channel.category,
+            topic,
+            nsfw: self.nsfw.unwrap_or(old_channel.nsfw),
+            archived: self.archived.unwrap_or(old_channel.archived),
+            slow_mode,
+            announcement: self.announcement.unwrap_or(old_channel.announcement),
         };
 
         // unwrap ok, because we know there must be at least one channel as it exists
@@ -607,6 +908,14 @@ impl Request for UpdateChannelRequest {
         })?;
 
         state_lock.update_channel(&new_channel)?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::UpdateChannel,
+            Some(new_channel.uuid),
+            Some(before),
+            Some(serde_json::to_value(&new_channel)?),
+        )?;
 
         update_channels(state_lock)?;
         Ok(GenericResponse(Status::Ok))
@@ -617,7 +926,7 @@ fn send_metadata(state_lock: &mut LockedState, peer: &Peer) {
     if let Some(uuid) = peer.uuid {
         match state_lock.get_user(uuid) {
             Ok(Some(peer_meta)) => {
-                let meta = peer_meta;
+                let meta = peer_meta.seen_by(None);
                 let result =
                     json!({"command": "get_metadata", "data": [meta], "status": Status::Ok as i32});
                 state_lock.send_to_all(result).unwrap(); //TODO get rid of this unwrap
@@ -631,19 +940,36 @@ fn send_metadata(state_lock: &mut LockedState, peer: &Peer) {
     }
 }
 
-pub fn count_online(state_lock: &LockedState) -> Vec<i64> {
-    state_lock
-        .online
-        .iter()
-        .filter(|a| *a.1 > 0)
-        .map(|a| *a.0)
-        .collect()
+/// Get the presence of every connected user, leaving out those who are invisible.
+pub fn count_online(state_lock: &LockedState) -> Result<Vec<Presence>, DbError> {
+    let mut presence = Vec::new();
+    for (&uuid, _) in state_lock.online.iter().filter(|a| *a.1 > 0) {
+        match state_lock.get_presence(uuid)? {
+            Some(p) if p.status != UserStatus::Invisible => presence.push(p),
+            _ => {}
+        }
+    }
+    Ok(presence)
+}
+
+fn online_response(state_lock: &LockedState) -> Result<Response, DbError> {
+    let presence = count_online(state_lock)?;
+    Ok(OnlineResponse {
+        data: presence.iter().map(|p| p.uuid).collect(),
+        presence,
+    })
 }
 
 pub fn send_online(state_lock: &LockedState) {
-    let num_online = count_online(state_lock);
+    let response = match online_response(state_lock) {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("send_online: could not get user presence: {:?}", e);
+            return;
+        }
+    };
 
-    let mut final_json = serde_json::to_value(OnlineResponse { data: num_online }).unwrap(); // unwrap ok because OnlineResponse derives Serialize, and it does not contain any maps
+    let mut final_json = serde_json::to_value(response).unwrap(); // unwrap ok because OnlineResponse derives Serialize, and it does not contain any maps
     final_json["status"] = (Status::Ok as i32).into(); // to make sure the client doesn't panic...
     state_lock.send_to_all(final_json).unwrap(); //TODO get rid of this unwrap
 }
@@ -676,13 +1002,40 @@ fn execute_request(
     }
 }
 
-pub fn process_command(
+/// Charge a command costing `cost` to the peer's rate limit, and return whether it can go ahead. Peers which
+/// keep sending commands while they're over the limit are sent a `kicked` packet and disconnected.
+fn rate_limit(peer: &mut Peer, cost: f64) -> Result<bool, Box<dyn Error>> {
+    if peer.rate_limit.try_take(cost, Instant::now()) {
+        return Ok(true);
+    }
+    if peer.rate_limit.strikes >= CONF.rate_limit_strikes {
+        log::warn!("Disconnecting {} for sending too many requests", peer.addr);
+        let mut packet = serde_json::to_value(KickedResponse {
+            reason: "Sending too many requests".into(),
+        })?;
+        packet["status"] = (Status::Ok as i32).into();
+        peer.tx.send(packet)?;
+        peer.kick.notify_one();
+    }
+    Ok(false)
+}
+
+/// A request from a peer which has been read and allowed through the rate limit, ready to be executed.
+pub struct AdmittedRequest {
+    command: String,
+    request: Requests,
+    received: Instant,
+}
+
+/// Read a packet from a peer and charge it to the peer's rate limit. None of this needs the shared state, so
+/// packets that are refused or can't be read never hold up other connections. Returns the request if it
+/// should be given to `process_command`; otherwise the peer has already been sent an error.
+pub fn admit_command(
     msg: &str,
-    state: &mut LockedState,
     peer: &mut Peer,
-) -> Result<(), Box<dyn Error>> {
-    let a = std::time::Instant::now();
-    let response = match serde_json::from_str::<JsonValue>(msg) {
+) -> Result<Option<AdmittedRequest>, Box<dyn Error>> {
+    let received = Instant::now();
+    let (command, raw_request) = match serde_json::from_str::<JsonValue>(msg) {
         Ok(raw_request) => {
             let command = if raw_request["command"].is_string() {
                 raw_request["command"].as_str().unwrap().to_owned()
@@ -690,25 +1043,59 @@ pub fn process_command(
                 log::warn!("Command field missing: '{msg}'");
                 "unknown".to_owned()
             };
-            print!("Request {command}");
-
-            match serde_json::from_value::<Requests>(raw_request) {
-                Ok(request) => execute_request(request, state, peer, &command),
-                Err(_) => {
-                    log::warn!("Bad request for command: '{msg}'");
-                    json!({"command": command, "status": Status::BadRequest as i32})
-                }
-            }
+            (command, Some(raw_request))
         }
         Err(_) => {
             log::warn!("Unreadable json message: '{msg}'");
-            json!({"command": "unknown", "status": Status::BadRequest as i32})
+            ("unknown".to_owned(), None)
+        }
+    };
+    print!("Request {command}");
+
+    let cost = match raw_request {
+        Some(_) => CONF.command_cost(&command),
+        None => 1.0,
+    };
+    let status = if !rate_limit(peer, cost)? {
+        Status::TooManyRequests
+    } else {
+        match raw_request.map(serde_json::from_value::<Requests>) {
+            Some(Ok(request)) => {
+                return Ok(Some(AdmittedRequest {
+                    command,
+                    request,
+                    received,
+                }))
+            }
+            Some(Err(_)) => {
+                log::warn!("Bad request for command: '{msg}'");
+                Status::BadRequest
+            }
+            None => Status::BadRequest,
         }
     };
+    respond(
+        peer,
+        json!({"command": command, "status": status as i32}),
+        received,
+    )?;
+    Ok(None)
+}
+
+pub fn process_command(
+    request: AdmittedRequest,
+    state: &mut LockedState,
+    peer: &mut Peer,
+) -> Result<(), Box<dyn Error>> {
+    let response = execute_request(request.request, state, peer, &request.command);
+    respond(peer, response, request.received)
+}
+
+fn respond(peer: &Peer, response: JsonValue, received: Instant) -> Result<(), Box<dyn Error>> {
     // println!("Got request '{}' and responded with '{:?}'", msg, response);
     let status: i64 = response["status"].as_i64().unwrap();
     peer.tx.send(response)?;
-    let d = a.elapsed();
+    let d = received.elapsed();
     println!(" took {}µs to respond with code {}", d.as_micros(), status);
     Ok(())
 }
@@ -717,12 +1104,20 @@ pub fn process_command(
 mod tests {
     use std::collections::HashMap;
 
+    use std::sync::Arc;
+
     use crate::{
         helper::{gen_uuid, Uuid},
-        models::Channel,
+        message::Message,
+        models::{Channel, Group, Thread, User, UserStatus},
+        peer::Peer,
+        permissions::{Perm, Permissions},
+        shared::Shared,
     };
 
-    use super::moveto;
+    use super::{
+        check_can_send, moveto, CreateThreadRequest, Request, Response, Status, UpdateGroupRequest,
+    };
 
     #[test]
     fn reorder_channels() {
@@ -736,29 +1131,36 @@ mod tests {
                     name: format!("{}", i),
                     position: i,
                     permissions: HashMap::new(),
+                    dm_members: None,
+                    category: None,
+                    topic: None,
+                    nsfw: false,
+                    archived: false,
+                    slow_mode: 0,
+                    announcement: false,
                 },
             );
         }
 
-        let channels_vec: Vec<Channel> = ch_db.values().map(|c| c.clone()).collect();
+        let channels_vec: Vec<Channel> = ch_db.values().cloned().collect();
         moveto(3, 6, channels_vec.clone(), |thing: Channel| {
             ch_db.insert(thing.uuid, thing);
             Ok(())
         })
         .unwrap();
-        let channels_vec: Vec<Channel> = ch_db.values().map(|c| c.clone()).collect();
+        let channels_vec: Vec<Channel> = ch_db.values().cloned().collect();
         moveto(7, 2, channels_vec.clone(), |thing: Channel| {
             ch_db.insert(thing.uuid, thing);
             Ok(())
         })
         .unwrap();
-        let channels_vec: Vec<Channel> = ch_db.values().map(|c| c.clone()).collect();
+        let channels_vec: Vec<Channel> = ch_db.values().cloned().collect();
         moveto(5, 5, channels_vec.clone(), |thing: Channel| {
             ch_db.insert(thing.uuid, thing);
             Ok(())
         })
         .unwrap();
-        let mut new_channels: Vec<Channel> = ch_db.values().map(|c| c.clone()).collect();
+        let mut new_channels: Vec<Channel> = ch_db.values().cloned().collect();
         new_channels.sort_by_key(|c| c.position);
         println!(
             "{}",
@@ -777,4 +1179,239 @@ mod tests {
             vec!["0", "1", "7", "2", "4", "5", "6", "3", "8", "9",]
         );
     }
+
+    #[test]
+    fn update_group() {
+        let shared = Shared::new(rusqlite::Connection::open_in_memory().unwrap());
+        shared.init_db();
+        let admin = Group {
+            uuid: gen_uuid(),
+            permissions: Permissions {
+                modify_groups: Perm::Allow,
+                ..Default::default()
+            },
+            name: "admin".into(),
+            colour: 0,
+            position: 0,
+        };
+        let member = Group {
+            uuid: gen_uuid(),
+            permissions: Permissions::default(),
+            name: "member".into(),
+            colour: 0,
+            position: 1,
+        };
+        let user = User {
+            uuid: gen_uuid(),
+            name: "admin user".into(),
+            pfp: "".into(),
+            password: "".into(),
+            groups: vec![admin.uuid],
+            status: UserStatus::Online,
+            status_text: None,
+        };
+        shared.insert_group(&admin).unwrap();
+        shared.insert_group(&member).unwrap();
+        shared.insert_user(&user).unwrap();
+
+        let mut peer = Peer::new("127.0.0.1:1234".parse().unwrap());
+        peer.uuid = Some(user.uuid);
+        let state = tokio::sync::Mutex::new(shared);
+        let mut state_lock = state.blocking_lock();
+        state_lock.peers.push((
+            peer.tx.clone(),
+            peer.addr,
+            peer.uuid,
+            Arc::clone(&peer.kick),
+        ));
+
+        let request = UpdateGroupRequest {
+            uuid: member.uuid,
+            permissions: None,
+            name: Some("regular".into()),
+            colour: Some(0xff0000),
+            position: None,
+        };
+        let response = request.execute(&mut state_lock, &mut peer).unwrap();
+        assert!(matches!(response, Response::GenericResponse(Status::Ok)));
+
+        let updated = state_lock.get_group(member.uuid).unwrap().unwrap();
+        assert_eq!(updated.name, "regular");
+        assert_eq!(updated.colour, 0xff0000);
+        assert_eq!(updated.position, 1);
+
+        // everyone is sent the new list of groups
+        let packet = peer.rx.try_recv().unwrap();
+        assert_eq!(packet["command"], "list_groups");
+        assert_eq!(packet["data"].as_array().unwrap().len(), 2);
+    }
+
+    /// A server with a member who can read and send messages, `parent` with a message from them in it, and a
+    /// thread started from the message.
+    fn thread_setup(parent: &Channel) -> (Shared, User, Message, Thread) {
+        let shared = Shared::new(rusqlite::Connection::open_in_memory().unwrap());
+        shared.init_db();
+        let group = Group {
+            uuid: gen_uuid(),
+            permissions: Permissions {
+                view_channel: Perm::Allow,
+                read_messages: Perm::Allow,
+                send_messages: Perm::Allow,
+                ..Default::default()
+            },
+            name: "member".into(),
+            colour: 0,
+            position: 0,
+        };
+        let user = User {
+            uuid: gen_uuid(),
+            name: "member user".into(),
+            pfp: "".into(),
+            password: "".into(),
+            groups: vec![group.uuid],
+            status: UserStatus::Online,
+            status_text: None,
+        };
+        shared.insert_group(&group).unwrap();
+        shared.insert_user(&user).unwrap();
+        shared.insert_channel(parent).unwrap();
+
+        let message = Message {
+            uuid: gen_uuid(),
+            content: "hello".into(),
+            author_uuid: user.uuid,
+            channel_uuid: parent.uuid,
+            date: 1000,
+            edited: false,
+            reply: None,
+            edited_at: None,
+            deleted_at: None,
+            deleted_by: None,
+            reactions: Vec::new(),
+            attachments: Vec::new(),
+        };
+        shared.add_to_history(&message).unwrap();
+        let thread = Thread {
+            uuid: gen_uuid(),
+            name: "a thread".into(),
+            channel_uuid: parent.uuid,
+            message_uuid: message.uuid,
+            creator_uuid: user.uuid,
+            date: 1000,
+            participants: vec![user.uuid],
+        };
+        shared.insert_thread(&thread).unwrap();
+        (shared, user, message, thread)
+    }
+
+    fn test_channel() -> Channel {
+        Channel {
+            uuid: gen_uuid(),
+            name: "general".into(),
+            position: 0,
+            permissions: HashMap::new(),
+            dm_members: None,
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        }
+    }
+
+    #[test]
+    fn thread_in_archived_channel() {
+        let parent = Channel {
+            archived: true,
+            ..test_channel()
+        };
+        let (shared, user, message, thread) = thread_setup(&parent);
+        let state = tokio::sync::Mutex::new(shared);
+        let mut state_lock = state.blocking_lock();
+        let thread_channel = state_lock.get_channel(&thread.uuid).unwrap().unwrap();
+
+        // threads already in an archived channel are archived with it
+        let result = check_can_send(&state_lock, user.uuid, &thread_channel, 2000).unwrap();
+        assert!(matches!(result, Err(Status::Forbidden)));
+
+        // and new ones can't be started to get around it
+        let mut peer = Peer::new("127.0.0.1:1234".parse().unwrap());
+        peer.uuid = Some(user.uuid);
+        let other = Message {
+            uuid: gen_uuid(),
+            ..message
+        };
+        state_lock.add_to_history(&other).unwrap();
+        let request = CreateThreadRequest {
+            message: other.uuid,
+            name: "another thread".into(),
+        };
+        let response = request.execute(&mut state_lock, &mut peer).unwrap();
+        assert!(matches!(
+            response,
+            Response::GenericResponse(Status::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn thread_in_slow_mode_channel() {
+        let parent = Channel {
+            slow_mode: 60,
+            ..test_channel()
+        };
+        let (shared, user, message, thread) = thread_setup(&parent);
+        let state = tokio::sync::Mutex::new(shared);
+        let state_lock = state.blocking_lock();
+        let thread_channel = state_lock.get_channel(&thread.uuid).unwrap().unwrap();
+
+        let result = check_can_send(&state_lock, user.uuid, &thread_channel, 2000).unwrap();
+        assert!(result.is_ok());
+        let reply = Message {
+            uuid: gen_uuid(),
+            channel_uuid: thread.uuid,
+            date: 2000,
+            ..message
+        };
+        state_lock.add_to_history(&reply).unwrap();
+
+        // the parent's slow mode applies inside the thread
+        let result = check_can_send(&state_lock, user.uuid, &thread_channel, 2030).unwrap();
+        assert!(matches!(result, Err(Status::TooManyRequests)));
+        let result = check_can_send(&state_lock, user.uuid, &thread_channel, 2060).unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn thread_in_announcement_channel() {
+        let parent = Channel {
+            announcement: true,
+            ..test_channel()
+        };
+        let (shared, user, message, thread) = thread_setup(&parent);
+        let state = tokio::sync::Mutex::new(shared);
+        let mut state_lock = state.blocking_lock();
+        let thread_channel = state_lock.get_channel(&thread.uuid).unwrap().unwrap();
+
+        // without post_announcements, threads on announcements are as read-only as the channel
+        let result = check_can_send(&state_lock, user.uuid, &thread_channel, 2000).unwrap();
+        assert!(matches!(result, Err(Status::Forbidden)));
+
+        let mut peer = Peer::new("127.0.0.1:1234".parse().unwrap());
+        peer.uuid = Some(user.uuid);
+        let other = Message {
+            uuid: gen_uuid(),
+            ..message
+        };
+        state_lock.add_to_history(&other).unwrap();
+        let request = CreateThreadRequest {
+            message: other.uuid,
+            name: "another thread".into(),
+        };
+        let response = request.execute(&mut state_lock, &mut peer).unwrap();
+        assert!(matches!(
+            response,
+            Response::GenericResponse(Status::Forbidden)
+        ));
+    }
 }
@@ -0,0 +1,187 @@
+use crate::commands::{
+    record_audit, server_perms, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{LockedState, Uuid};
+use crate::models::{AuditAction, Ban};
+use crate::permissions::Perm;
+use crate::shared::DbError;
+use crate::Peer;
+use serde::Deserialize;
+use serde_json::json;
+
+/// Ban a user from the server. Their live connections are sent a `banned` packet and then disconnected,
+/// and they cannot log in again until the ban is lifted or expires. While the ban lasts, new accounts cannot
+/// be registered from the addresses they were connected from, unless `block_banned_addrs` is turned off.
+/// Requires the `ban_users` permission.
+/// Error conditions:
+/// - 400 (bad request) if the user is trying to ban themselves, or `duration` is not positive.
+/// - 403 (forbidden) if the user does not have permission, or the target is in an equal or higher group.
+/// - 404 (not found) if the target user does not exist.
+#[derive(Deserialize)]
+pub struct BanRequest {
+    pub user: Uuid,
+    #[serde(default)]
+    pub reason: String,
+    /// Length of the ban in seconds. The ban is permanent if this is not present.
+    pub duration: Option<i64>,
+}
+
+/// Lift the ban on a user. Requires the `ban_users` permission.
+/// Error conditions:
+/// - 403 (forbidden) if the user does not have permission.
+/// - 404 (not found) if the target user is not banned.
+#[derive(Deserialize)]
+pub struct UnbanRequest {
+    pub user: Uuid,
+}
+
+/// Disconnect all of a user's live connections, after sending them a `kicked` packet. Unlike `ban`, they
+/// are free to log straight back in. Requires the `ban_users` permission.
+/// Error conditions are the same as for `ban`.
+#[derive(Deserialize)]
+pub struct KickRequest {
+    pub user: Uuid,
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// List all bans currently in effect. Requires the `ban_users` permission.
+#[derive(Deserialize)]
+pub struct ListBansRequest;
+
+/// Whether the user `us` is in a strictly higher group than `them`, and so may moderate them.
+fn outranks(state_lock: &LockedState, us: Uuid, them: Uuid) -> Result<bool, DbError> {
+    Ok(state_lock.get_highest_group_pos_of(us)? < state_lock.get_highest_group_pos_of(them)?)
+}
+
+impl Request for BanRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(our_uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        if server_perms(state_lock, peer)?.ban_users != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        if self.user == our_uuid || self.duration.is_some_and(|d| d <= 0) {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        if state_lock.get_user(self.user)?.is_none() {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+        if !outranks(state_lock, our_uuid, self.user)? {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        let now = chrono::offset::Utc::now().timestamp();
+        let ban = Ban {
+            user_uuid: self.user,
+            issuer_uuid: our_uuid,
+            reason: self.reason,
+            date: now,
+            expires: self.duration.map(|d| now + d),
+        };
+        state_lock.insert_ban(&ban)?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::Ban,
+            Some(self.user),
+            None,
+            Some(serde_json::to_value(&ban)?),
+        )?;
+
+        for addr in state_lock.get_addrs_of(self.user) {
+            state_lock.insert_ban_addr(self.user, &addr)?;
+        }
+
+        let mut packet = serde_json::to_value(BannedResponse {
+            reason: ban.reason,
+            expires: ban.expires,
+        })?;
+        packet["status"] = (Status::Ok as i32).into();
+        state_lock.send_to_user(self.user, packet)?;
+        state_lock.disconnect_user(self.user);
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for UnbanRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.ban_users != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        let now = chrono::offset::Utc::now().timestamp();
+        let ban = state_lock.get_ban(self.user, now)?;
+        if state_lock.delete_ban(self.user)? == 0 {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::Unban,
+            Some(self.user),
+            ban.map(serde_json::to_value).transpose()?,
+            None,
+        )?;
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for KickRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(our_uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        if server_perms(state_lock, peer)?.ban_users != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        if self.user == our_uuid {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        if state_lock.get_user(self.user)?.is_none() {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+        if !outranks(state_lock, our_uuid, self.user)? {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::Kick,
+            Some(self.user),
+            None,
+            Some(json!({ "reason": self.reason })),
+        )?;
+
+        let mut packet = serde_json::to_value(KickedResponse {
+            reason: self.reason,
+        })?;
+        packet["status"] = (Status::Ok as i32).into();
+        state_lock.send_to_user(self.user, packet)?;
+        state_lock.disconnect_user(self.user);
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for ListBansRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.ban_users != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        let now = chrono::offset::Utc::now().timestamp();
+        Ok(ListBansResponse {
+            data: state_lock.get_bans(now)?,
+        })
+    }
+}
@@ -0,0 +1,65 @@
+use crate::commands::{
+    channel_perms, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{LockedState, Uuid};
+use crate::models::{NotifyLevel, NotifySettings};
+use crate::permissions::Perm;
+use crate::Peer;
+use serde::Deserialize;
+
+/// Set which messages in a channel the user wants to be notified about: "all" (the default), "mentions" or
+/// "none". The channel can also be muted until `muted_until` (in seconds since the epoch), after which it goes
+/// back to notifying at `level`; leave it out to unmute. Muted channels still count unread messages, but are
+/// listed in `get_last_reads` so clients can dim them, and mentions in them aren't pushed to the user.
+/// Error conditions:
+/// - 403 (forbidden) if the user cannot read messages in the channel.
+/// - 404 (not found) if the channel does not exist.
+#[derive(Deserialize)]
+pub struct SetChannelNotifyRequest {
+    pub channel: Uuid,
+    #[serde(default)]
+    pub level: NotifyLevel,
+    pub muted_until: Option<i64>,
+}
+
+/// Get the user's notification settings. Returns a packet of type get_notify_settings with a field "data"
+/// containing the settings of every channel which doesn't use the defaults.
+#[derive(Deserialize)]
+pub struct GetNotifySettingsRequest;
+
+impl Request for SetChannelNotifyRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let Some(channel) = state_lock.get_channel(&self.channel)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if channel_perms(state_lock, peer.uuid, &channel)?.read_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        state_lock.set_notify_settings(
+            user,
+            &NotifySettings {
+                channel_uuid: channel.uuid,
+                level: self.level,
+                muted_until: self.muted_until,
+            },
+        )?;
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for GetNotifySettingsRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        Ok(GetNotifySettingsResponse {
+            data: state_lock.get_notify_settings(user)?,
+        })
+    }
+}
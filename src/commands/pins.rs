@@ -0,0 +1,147 @@
+use crate::commands::{
+    channel_perms, record_audit, send_to_channel_readers, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{LockedState, Uuid};
+use crate::models::AuditAction;
+use crate::permissions::Perm;
+use crate::Peer;
+use serde::Deserialize;
+
+/// The most messages that can be pinned in a single channel.
+const MAX_PINS_PER_CHANNEL: usize = 50;
+
+/// Pin a message in its channel. Requires the `manage_messages` permission in that channel. On success, everyone
+/// who can read the channel is sent a `message_pinned` packet.
+/// Error conditions:
+/// - 400 (bad request) if the channel already has the maximum number of pins (50).
+/// - 403 (forbidden) if the user cannot manage messages in the channel.
+/// - 404 (not found) if the message does not exist.
+/// - 409 (conflict) if the message is already pinned.
+#[derive(Deserialize)]
+pub struct PinMessageRequest {
+    pub message: Uuid,
+}
+
+/// Unpin a message. Requires the `manage_messages` permission in the message's channel. On success, everyone who
+/// can read the channel is sent a `message_unpinned` packet.
+/// Error conditions:
+/// - 403 (forbidden) if the user cannot manage messages in the channel.
+/// - 404 (not found) if the message does not exist or is not pinned.
+#[derive(Deserialize)]
+pub struct UnpinMessageRequest {
+    pub message: Uuid,
+}
+
+/// List the pinned messages in a channel, most recently pinned first. Returns a packet of type list_pins with a
+/// field "data".
+/// Error conditions:
+/// - 403 (forbidden) if the user cannot read messages in the channel.
+/// - 404 (not found) if the channel does not exist.
+#[derive(Deserialize)]
+pub struct ListPinsRequest {
+    pub channel: Uuid,
+}
+
+impl Request for PinMessageRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user_uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+
+        let Some(message) = state_lock.get_message(self.message)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let Some(channel) = state_lock.get_channel(&message.channel_uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if channel_perms(state_lock, peer.uuid, &channel)?.manage_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        if state_lock.count_pins(channel.uuid)? >= MAX_PINS_PER_CHANNEL {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+
+        let now = chrono::offset::Utc::now().timestamp();
+        if state_lock.pin_message(&message, user_uuid, now)? == 0 {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::PinMessage,
+            Some(message.uuid),
+            None,
+            None,
+        )?;
+
+        let mut packet = serde_json::to_value(MessagePinnedResponse {
+            channel: channel.uuid,
+            message: message.uuid,
+            user: user_uuid,
+        })?;
+        packet["status"] = (Status::Ok as i32).into();
+        send_to_channel_readers(state_lock, &channel, &packet)?;
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for UnpinMessageRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user_uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+
+        let Some(message) = state_lock.get_message(self.message)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let Some(channel) = state_lock.get_channel(&message.channel_uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if channel_perms(state_lock, peer.uuid, &channel)?.manage_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        if state_lock.unpin_message(message.uuid)? == 0 {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::UnpinMessage,
+            Some(message.uuid),
+            None,
+            None,
+        )?;
+
+        let mut packet = serde_json::to_value(MessageUnpinnedResponse {
+            channel: channel.uuid,
+            message: message.uuid,
+            user: user_uuid,
+        })?;
+        packet["status"] = (Status::Ok as i32).into();
+        send_to_channel_readers(state_lock, &channel, &packet)?;
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for ListPinsRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        let Some(channel) = state_lock.get_channel(&self.channel)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if channel_perms(state_lock, peer.uuid, &channel)?.read_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        Ok(ListPinsResponse {
+            data: state_lock.get_pins(channel.uuid)?,
+        })
+    }
+}
@@ -0,0 +1,46 @@
+use crate::commands::{
+    send_online, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::LockedState;
+use crate::models::UserStatus;
+use crate::Peer;
+use serde::Deserialize;
+
+/// The longest a custom status text can be, in characters.
+pub const MAX_STATUS_TEXT_LEN: usize = 128;
+
+/// Set the user's status and custom status text. The status is one of "online", "idle", "do_not_disturb" or
+/// "invisible"; the text is optional, and leaving it out (or sending only whitespace) clears it. The status is
+/// kept across reconnects. On success, all connected clients are sent an updated `online` packet. Invisible
+/// users are left out of it entirely, and appear with the "online" status to everyone else.
+/// Error conditions:
+/// - 400 (bad request) if the text is longer than `MAX_STATUS_TEXT_LEN` characters.
+#[derive(Deserialize)]
+pub struct SetStatusRequest {
+    pub status: UserStatus,
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+impl Request for SetStatusRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let text = self
+            .text
+            .as_deref()
+            .map(str::trim)
+            .filter(|t| !t.is_empty());
+        if text.is_some_and(|t| t.chars().count() > MAX_STATUS_TEXT_LEN) {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+
+        state_lock.set_status(user, self.status, text)?;
+        send_online(state_lock);
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
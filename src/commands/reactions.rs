@@ -0,0 +1,113 @@
+use crate::commands::{
+    channel_perms, send_to_channel_readers, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{LockedState, Uuid};
+use crate::message::ReactionEmoji;
+use crate::permissions::Perm;
+use crate::Peer;
+use serde::Deserialize;
+
+/// React to a message with an emoji, which is either a unicode emoji as a string or the uuid of a custom
+/// emoji. Requires the `read_messages` permission in the message's channel. On success, everyone who can read
+/// the channel is sent a `reaction_added` packet.
+/// Error conditions:
+/// - 400 (bad request) if the unicode emoji is empty, contains whitespace, or is too long.
+/// - 403 (forbidden) if the user cannot read the channel.
+/// - 404 (not found) if the message or custom emoji does not exist.
+/// - 409 (conflict) if the user has already reacted to this message with this emoji.
+#[derive(Deserialize)]
+pub struct AddReactionRequest {
+    pub message: Uuid,
+    pub emoji: ReactionEmoji,
+}
+
+/// Remove one of the user's own reactions from a message. On success, everyone who can read the channel is
+/// sent a `reaction_removed` packet.
+/// Error conditions:
+/// - 403 (forbidden) if the user cannot read the channel.
+/// - 404 (not found) if the message does not exist, or the user has not reacted with this emoji.
+#[derive(Deserialize)]
+pub struct RemoveReactionRequest {
+    pub message: Uuid,
+    pub emoji: ReactionEmoji,
+}
+
+impl Request for AddReactionRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user_uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+
+        match &self.emoji {
+            ReactionEmoji::Unicode(e) => {
+                // a single emoji can be made up of quite a few codepoints, but not this many
+                if e.is_empty() || e.len() > 64 || e.chars().any(char::is_whitespace) {
+                    return Ok(GenericResponse(Status::BadRequest));
+                }
+            }
+            ReactionEmoji::Custom(uuid) => {
+                if state_lock.get_emoji(*uuid)?.is_none() {
+                    return Ok(GenericResponse(Status::NotFound));
+                }
+            }
+        }
+
+        let Some(message) = state_lock.get_message(self.message)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let Some(channel) = state_lock.get_channel(&message.channel_uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if channel_perms(state_lock, peer.uuid, &channel)?.read_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        if state_lock.add_reaction(message.uuid, user_uuid, &self.emoji)? == 0 {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+
+        let mut packet = serde_json::to_value(ReactionAddedResponse {
+            message: message.uuid,
+            user: user_uuid,
+            emoji: self.emoji,
+        })?;
+        packet["status"] = (Status::Ok as i32).into();
+        send_to_channel_readers(state_lock, &channel, &packet)?;
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for RemoveReactionRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user_uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+
+        let Some(message) = state_lock.get_message(self.message)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let Some(channel) = state_lock.get_channel(&message.channel_uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if channel_perms(state_lock, peer.uuid, &channel)?.read_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        if state_lock.remove_reaction(message.uuid, user_uuid, &self.emoji)? == 0 {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+
+        let mut packet = serde_json::to_value(ReactionRemovedResponse {
+            message: message.uuid,
+            user: user_uuid,
+            emoji: self.emoji,
+        })?;
+        packet["status"] = (Status::Ok as i32).into();
+        send_to_channel_readers(state_lock, &channel, &packet)?;
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
@@ -0,0 +1,204 @@
+use crate::commands::{
+    check_can_send, deliver_message, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{gen_uuid, LockedState, Uuid};
+use crate::message::{Message, ScheduledMessage};
+use crate::Peer;
+use serde::Deserialize;
+
+/// The most messages a user can have scheduled at once.
+pub const MAX_SCHEDULED_PER_USER: usize = 25;
+/// How far in advance a message can be scheduled, in seconds.
+pub const MAX_SCHEDULE_AHEAD: i64 = 365 * 24 * 60 * 60;
+
+/// Schedule a message to be sent to a channel at `send_at` (in seconds since the epoch). Returns a packet of
+/// type schedule_message with a field "uuid" containing the uuid of the scheduled message, which is not the
+/// uuid the message will have once it's sent. When it's due, the message goes through the same checks as
+/// `send`; if it can't be sent then, it's dropped and the author is sent a packet of type scheduled_failed
+/// with the fields "uuid" and "reason" (the status `send` would have failed with).
+/// Error conditions:
+/// - 400 (bad request) if the content is empty, `send_at` is not in the future or is more than a year away,
+///   or the user already has `MAX_SCHEDULED_PER_USER` messages scheduled.
+/// - 403 (forbidden) if the user cannot currently send messages in the channel.
+/// - 404 (not found) if the channel or the message being replied to does not exist.
+#[derive(Deserialize)]
+pub struct ScheduleMessageRequest {
+    pub content: String,
+    pub channel: Uuid,
+    pub reply: Option<Uuid>,
+    pub send_at: i64,
+}
+
+/// List the user's scheduled messages. Returns a packet of type list_scheduled with a field "data" containing
+/// them, soonest first.
+#[derive(Deserialize)]
+pub struct ListScheduledRequest;
+
+/// Cancel one of the user's scheduled messages.
+/// Error conditions:
+/// - 404 (not found) if the user has no scheduled message with that uuid.
+#[derive(Deserialize)]
+pub struct CancelScheduledRequest {
+    pub uuid: Uuid,
+}
+
+impl Request for ScheduleMessageRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let now = chrono::offset::Utc::now().timestamp();
+        if self.content.chars().all(|c| c.is_whitespace())
+            || self.send_at <= now
+            || self.send_at > now + MAX_SCHEDULE_AHEAD
+        {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        if state_lock.get_scheduled_of(user)?.len() >= MAX_SCHEDULED_PER_USER {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+
+        let Some(channel) = state_lock.get_channel(&self.channel)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        // slow mode only matters when the message is actually sent
+        match check_can_send(state_lock, user, &channel, now)? {
+            Ok(_) | Err(Status::TooManyRequests) => {}
+            Err(status) => return Ok(GenericResponse(status)),
+        }
+        if let Some(r) = self.reply {
+            if !state_lock.message_exists(&r)? {
+                return Ok(GenericResponse(Status::NotFound));
+            }
+        }
+
+        let msg = ScheduledMessage {
+            uuid: gen_uuid(),
+            content: self.content,
+            author_uuid: user,
+            channel_uuid: channel.uuid,
+            reply: self.reply,
+            send_at: self.send_at,
+        };
+        state_lock.insert_scheduled(&msg)?;
+        Ok(ScheduleMessageResponse { uuid: msg.uuid })
+    }
+}
+
+impl Request for ListScheduledRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        Ok(ListScheduledResponse {
+            data: state_lock.get_scheduled_of(user)?,
+        })
+    }
+}
+
+impl Request for CancelScheduledRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        match state_lock.get_scheduled(self.uuid)? {
+            Some(msg) if msg.author_uuid == user => {
+                state_lock.delete_scheduled(msg.uuid)?;
+                Ok(GenericResponse(Status::Ok))
+            }
+            _ => Ok(GenericResponse(Status::NotFound)),
+        }
+    }
+}
+
+fn send_scheduled(
+    state_lock: &mut LockedState,
+    scheduled: ScheduledMessage,
+    now: i64,
+) -> Result<Result<(), Status>, CmdError> {
+    let Some(channel) = state_lock.get_channel(&scheduled.channel_uuid)? else {
+        return Ok(Err(Status::NotFound));
+    };
+    // the author might have been removed from the channel, or banned, since scheduling it
+    if state_lock.get_user(scheduled.author_uuid)?.is_none()
+        || state_lock.get_ban(scheduled.author_uuid, now)?.is_some()
+    {
+        return Ok(Err(Status::Forbidden));
+    }
+    let perms = match check_can_send(state_lock, scheduled.author_uuid, &channel, now)? {
+        Ok(perms) => perms,
+        Err(status) => return Ok(Err(status)),
+    };
+    // the message being replied to may have been deleted in the meantime, which isn't worth dropping it over
+    let reply = match scheduled.reply {
+        Some(r) if state_lock.message_exists(&r)? => Some(r),
+        _ => None,
+    };
+
+    let msg = Message {
+        uuid: gen_uuid(),
+        content: scheduled.content,
+        author_uuid: scheduled.author_uuid,
+        channel_uuid: channel.uuid,
+        date: now as i32,
+        edited: false,
+        edited_at: None,
+        deleted_at: None,
+        deleted_by: None,
+        reply,
+        reactions: Vec::new(),
+        attachments: Vec::new(),
+    };
+    let uuid = msg.uuid;
+    if let Err(e) = deliver_message(state_lock, &perms, &channel, msg) {
+        // once the message is stored it has been sent, even if not everyone was told about it
+        if !state_lock.message_exists(&uuid)? {
+            return Err(e);
+        }
+        log::error!("Failed to deliver scheduled message {}: {:?}", uuid, e);
+    }
+    Ok(Ok(()))
+}
+
+fn notify_failed(
+    state_lock: &LockedState,
+    author: Uuid,
+    uuid: Uuid,
+    reason: Status,
+) -> Result<(), CmdError> {
+    let mut packet = serde_json::to_value(ScheduledFailedResponse { uuid, reason })?;
+    packet["status"] = (Status::Ok as i32).into();
+    state_lock.send_to_user(author, packet)?;
+    Ok(())
+}
+
+/// Send every scheduled message that is due, telling the authors of any that can no longer be sent.
+pub fn send_due_scheduled(state_lock: &mut LockedState) -> Result<(), CmdError> {
+    let now = chrono::offset::Utc::now().timestamp();
+    for scheduled in state_lock.get_due_scheduled(now)? {
+        let (uuid, author) = (scheduled.uuid, scheduled.author_uuid);
+        // one message failing shouldn't stop the rest from being sent
+        let result = send_scheduled(state_lock, scheduled, now);
+        // either way it has been dealt with, so it mustn't be tried again
+        state_lock.delete_scheduled(uuid)?;
+        let reason = match result {
+            Ok(Ok(())) => continue,
+            Ok(Err(reason)) => reason,
+            Err(e) => {
+                log::error!("Failed to send scheduled message {}: {:?}", uuid, e);
+                Status::InternalError
+            }
+        };
+        if let Err(e) = notify_failed(state_lock, author, uuid, reason) {
+            log::error!(
+                "Failed to tell {} scheduled message {} failed: {:?}",
+                author,
+                uuid,
+                e
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use crate::commands::{
+    channel_perms, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{LockedState, Uuid};
+use crate::message::MessageSearch;
+use crate::permissions::Perm;
+use crate::Peer;
+use serde::Deserialize;
+
+/// The most results that will be returned by a single search, regardless of `num`.
+const MAX_SEARCH_RESULTS: u32 = 100;
+
+/// Search the message history of every channel (and thread) the user can read. Returns a packet of type search with a field
+/// "data" containing up to `num` (at most 100) matching messages, newest first. To get the next page of results,
+/// search again with `before_message` set to the uuid of the last (oldest) message.
+/// `query` is a list of words, all of which must appear in the message. Every other filter is optional:
+/// - `channel`: only search this channel
+/// - `author`: only messages sent by this user
+/// - `after`, `before`: only messages sent within this time range (inclusive, in seconds since the epoch)
+/// - `has_reply`: only messages that are (true) or are not (false) replies
+///
+/// Error conditions:
+/// - 403 (forbidden) if `channel` is given and the user cannot read messages in it.
+/// - 404 (not found) if `channel` or `before_message` does not exist.
+#[derive(Deserialize)]
+pub struct SearchRequest {
+    #[serde(default)]
+    pub query: String,
+    pub num: u32,
+    pub before_message: Option<Uuid>,
+    pub channel: Option<Uuid>,
+    pub author: Option<Uuid>,
+    pub after: Option<i64>,
+    pub before: Option<i64>,
+    pub has_reply: Option<bool>,
+}
+
+impl Request for SearchRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+
+        let channels = if let Some(channel) = self.channel {
+            let Some(channel) = state_lock.get_channel(&channel)? else {
+                return Ok(GenericResponse(Status::NotFound));
+            };
+            if channel_perms(state_lock, peer.uuid, &channel)?.read_messages != Perm::Allow {
+                return Ok(GenericResponse(Status::Forbidden));
+            }
+            vec![channel.uuid]
+        } else {
+            let mut channels = state_lock.get_channels()?;
+            channels.extend(state_lock.get_dm_channels_of(uuid)?);
+            let mut readable = Vec::new();
+            for channel in channels {
+                if channel_perms(state_lock, peer.uuid, &channel)?.read_messages == Perm::Allow {
+                    readable.push(channel.uuid);
+                    // threads are readable by exactly the same people as their channel
+                    readable.extend(
+                        state_lock
+                            .get_threads_in(channel.uuid)?
+                            .iter()
+                            .map(|t| t.uuid),
+                    );
+                }
+            }
+            readable
+        };
+
+        let search = MessageSearch {
+            query: self.query,
+            channels,
+            author: self.author,
+            after: self.after,
+            before: self.before,
+            has_reply: self.has_reply,
+        };
+        let num = self.num.min(MAX_SEARCH_RESULTS);
+        // like history, this fails if before_message doesn't exist
+        let results = match state_lock.search_messages(&search, num, self.before_message) {
+            Ok(results) => results,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Ok(GenericResponse(Status::NotFound))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(SearchResponse { data: results })
+    }
+}
@@ -0,0 +1,152 @@
+use crate::commands::{
+    record_audit, server_perms, update_channels, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{is_png, LockedState};
+use crate::models::AuditAction;
+use crate::permissions::{Perm, Permissions};
+use crate::Peer;
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+
+/// Rename the server. Requires the `modify_icon_name` permission. On success, all connected clients
+/// are sent a `get_name` packet with the new name.
+/// Error conditions:
+/// - 400 (bad request) if the name is empty or entirely whitespace.
+/// - 403 (forbidden) if the user does not have permission.
+#[derive(Deserialize)]
+pub struct SetServerNameRequest {
+    pub name: String,
+}
+
+/// Change the server's icon to the given base64-encoded PNG image. Requires the `modify_icon_name`
+/// permission. On success, all connected clients are sent a `get_icon` packet with the new icon.
+/// Error conditions:
+/// - 400 (bad request) if the data is not a base64-encoded PNG image, or is too big.
+/// - 403 (forbidden) if the user does not have permission.
+#[derive(Deserialize)]
+pub struct SetServerIconRequest {
+    pub data: String,
+}
+
+/// Change the permissions every user starts with, before any groups are applied. Requires the
+/// `modify_groups` permission. On success, all connected clients are sent a `get_base_permissions`
+/// packet, and an updated `list_channels` packet as their visible channels may have changed.
+#[derive(Deserialize)]
+pub struct SetBasePermissionsRequest {
+    pub permissions: Permissions,
+}
+
+#[derive(Deserialize)]
+pub struct GetBasePermissionsRequest;
+
+fn send_to_all_ok(state_lock: &LockedState, response: Response) -> Result<(), CmdError> {
+    let mut packet = serde_json::to_value(response)?;
+    packet["status"] = (Status::Ok as i32).into();
+    state_lock.send_to_all(packet)?;
+    Ok(())
+}
+
+impl Request for SetServerNameRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.modify_icon_name != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        if self.name.trim().is_empty() {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+
+        let before = state_lock.get_name()?;
+        state_lock.set_name(&self.name)?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::SetServerName,
+            None,
+            Some(before.into()),
+            Some(self.name.as_str().into()),
+        )?;
+        send_to_all_ok(state_lock, GetNameResponse { data: self.name })?;
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for SetServerIconRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.modify_icon_name != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        // disallow icons over 128kb, for now
+        if self.data.len() > 128 * 1024 {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        let Ok(icon) = general_purpose::STANDARD.decode(&self.data) else {
+            return Ok(GenericResponse(Status::BadRequest));
+        };
+        if !is_png(&icon) {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+
+        state_lock.set_icon(&icon)?;
+        // the icon itself is too big to be worth keeping in the log
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::SetServerIcon,
+            None,
+            None,
+            None,
+        )?;
+        send_to_all_ok(state_lock, GetIconResponse { data: self.data })?;
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for SetBasePermissionsRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if server_perms(state_lock, peer)?.modify_groups != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        let before = serde_json::to_value(state_lock.get_base_perms()?)?;
+        state_lock.set_base_perms(self.permissions.clone())?;
+        record_audit(
+            state_lock,
+            peer,
+            AuditAction::SetBasePermissions,
+            None,
+            Some(before),
+            Some(serde_json::to_value(&self.permissions)?),
+        )?;
+        send_to_all_ok(
+            state_lock,
+            GetBasePermissionsResponse {
+                data: self.permissions,
+            },
+        )?;
+        update_channels(state_lock)?;
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+impl Request for GetBasePermissionsRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        Ok(GetBasePermissionsResponse {
+            data: state_lock.get_base_perms()?,
+        })
+    }
+}
@@ -0,0 +1,86 @@
+use crate::commands::{
+    CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{gen_uuid, LockedState, Uuid};
+use crate::models::Session;
+use crate::shared::DbError;
+use crate::Peer;
+use crate::CONF;
+use serde::Deserialize;
+
+use super::auth::{hash_token, make_token};
+
+/// List the user's sessions which haven't expired, most recently used first. Returns a packet of type
+/// list_sessions with a field "data" containing the sessions, and a field "current" containing the uuid of
+/// the session this connection logged in with, if any.
+#[derive(Deserialize)]
+pub struct ListSessionsRequest;
+
+/// Revoke one of the user's sessions, so its token can no longer be used to log in. Connections already
+/// logged in with it stay logged in.
+/// Error conditions:
+/// - 404 (not found) if the user has no session with that uuid.
+#[derive(Deserialize)]
+pub struct RevokeSessionRequest {
+    pub uuid: Uuid,
+}
+
+/// Start a new session for `user` on this connection, returning its token. The token can't be recovered
+/// later, so it has to be sent to the client straight away.
+pub fn start_session(
+    state_lock: &LockedState,
+    peer: &mut Peer,
+    user: Uuid,
+) -> Result<String, DbError> {
+    let now = chrono::offset::Utc::now().timestamp();
+    state_lock.delete_expired_sessions(now)?;
+
+    let token = make_token();
+    let session = Session {
+        uuid: gen_uuid(),
+        user_uuid: user,
+        created: now,
+        expires: now + CONF.session_lifetime,
+        last_used: now,
+    };
+    state_lock.insert_session(&session, &hash_token(&token))?;
+    peer.session = Some(session.uuid);
+    Ok(token)
+}
+
+impl Request for ListSessionsRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let now = chrono::offset::Utc::now().timestamp();
+        Ok(ListSessionsResponse {
+            data: state_lock.get_sessions_of(user, now)?,
+            current: peer.session,
+        })
+    }
+}
+
+impl Request for RevokeSessionRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let now = chrono::offset::Utc::now().timestamp();
+        if !state_lock
+            .get_sessions_of(user, now)?
+            .iter()
+            .any(|s| s.uuid == self.uuid)
+        {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+
+        state_lock.delete_session(self.uuid)?;
+        if peer.session == Some(self.uuid) {
+            peer.session = None;
+        }
+        Ok(GenericResponse(Status::Ok))
+    }
+}
@@ -0,0 +1,162 @@
+use crate::commands::{
+    channel_perms, send_to_channel_readers, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{gen_uuid, LockedState, Uuid};
+use crate::message::Message;
+use crate::models::Thread;
+use crate::permissions::Perm;
+use crate::Peer;
+use serde::Deserialize;
+
+const MAX_THREAD_NAME_LEN: usize = 100;
+
+/// Start a thread from a message. Messages are sent to the thread with `send`, using the thread's uuid as the
+/// channel, and don't appear in the history of the channel the thread was started in. Everyone who can read that
+/// channel is sent a packet of type thread_created with a field "data" containing the new thread.
+/// Error conditions:
+/// - 400 (bad request) if the name is empty or too long, or the message is itself in a thread.
+/// - 403 (forbidden) if the user cannot read and send messages in the message's channel, it is archived, or it is
+///   an announcement channel and the user does not have the `post_announcements` permission.
+/// - 404 (not found) if the message does not exist.
+/// - 409 (conflict) if a thread has already been started from this message.
+#[derive(Deserialize)]
+pub struct CreateThreadRequest {
+    pub message: Uuid,
+    pub name: String,
+}
+
+/// Get the most recent `num` messages in a thread, optionally before a given message, in the same way as
+/// `history`. Returns a packet of type thread_history with the fields "thread" and "data".
+/// Error conditions:
+/// - 403 (forbidden) if the user cannot read messages in the thread's channel.
+/// - 404 (not found) if the thread or `before_message` does not exist.
+#[derive(Deserialize)]
+pub struct ThreadHistoryRequest {
+    pub thread: Uuid,
+    pub num: u32,
+    pub before_message: Option<Uuid>,
+}
+
+/// List every thread started in a channel, oldest first. Returns a packet of type list_threads with a field
+/// "data". Unread counts for threads are available through `get_last_reads` and `get_num_unread`, just like
+/// channels.
+/// Error conditions:
+/// - 403 (forbidden) if the user cannot read messages in the channel.
+/// - 404 (not found) if the channel does not exist.
+#[derive(Deserialize)]
+pub struct ListThreadsRequest {
+    pub channel: Uuid,
+}
+
+impl Request for CreateThreadRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_THREAD_NAME_LEN {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+
+        let Some(message) = state_lock.get_message(self.message)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let Some(channel) = state_lock.get_channel(&message.channel_uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        let perms = channel_perms(state_lock, peer.uuid, &channel)?;
+        if perms.read_messages != Perm::Allow
+            || perms.send_messages != Perm::Allow
+            || channel.archived
+        {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        // anything sent in the thread goes to everyone reading the announcements
+        if channel.announcement && perms.post_announcements != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        // no threads inside of threads
+        if state_lock.get_thread_parent(channel.uuid)?.is_some() {
+            return Ok(GenericResponse(Status::BadRequest));
+        }
+        if state_lock.get_thread_of_message(message.uuid)?.is_some() {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+
+        let thread = Thread {
+            uuid: gen_uuid(),
+            name: name.into(),
+            channel_uuid: channel.uuid,
+            message_uuid: message.uuid,
+            creator_uuid: uuid,
+            date: chrono::offset::Utc::now().timestamp(),
+            participants: vec![uuid],
+        };
+        state_lock.insert_thread(&thread)?;
+
+        let response = ThreadCreatedResponse { data: thread };
+        let mut packet = serde_json::to_value(&response)?;
+        packet["status"] = (Status::Ok as i32).into();
+        send_to_channel_readers(state_lock, &channel, &packet)?;
+        Ok(response)
+    }
+}
+
+impl Request for ThreadHistoryRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        if state_lock.get_thread_parent(self.thread)?.is_none() {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+        let Some(channel) = state_lock.get_channel(&self.thread)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        // permissions are resolved through the thread's parent channel
+        let perms = channel_perms(state_lock, peer.uuid, &channel)?;
+        if perms.read_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        let mut history = match state_lock.get_history(self.thread, self.num, self.before_message) {
+            Ok(history) => history,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Ok(GenericResponse(Status::NotFound))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        history.reverse();
+        if perms.manage_messages != Perm::Allow {
+            history
+                .iter_mut()
+                .filter(|m| m.deleted_at.is_some())
+                .for_each(Message::redact);
+        }
+
+        Ok(ThreadHistoryResponse {
+            thread: self.thread,
+            data: history,
+        })
+    }
+}
+
+impl Request for ListThreadsRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        if !peer.logged_in() {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        }
+        let Some(channel) = state_lock.get_channel(&self.channel)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if channel_perms(state_lock, peer.uuid, &channel)?.read_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        Ok(ListThreadsResponse {
+            data: state_lock.get_threads_in(channel.uuid)?,
+        })
+    }
+}
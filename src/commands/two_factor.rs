@@ -0,0 +1,171 @@
+use crate::commands::{
+    start_session, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{LockedState, Uuid};
+use crate::shared::LoginKey;
+use crate::Peer;
+use serde::Deserialize;
+
+use super::auth::{
+    check_password, check_totp, make_hash, make_recovery_code, make_totp_secret, totp_uri,
+};
+
+/// How many recovery codes are given out when two-factor authentication is turned on.
+const NUM_RECOVERY_CODES: usize = 10;
+
+/// Start turning on TOTP two-factor authentication. Returns a packet of type enable_2fa with a field "secret"
+/// containing the base32 encoded secret, and a field "uri" containing an otpauth URI for it, which
+/// authenticator apps can import. Two-factor authentication isn't turned on until the secret is confirmed with
+/// `confirm_2fa`; until then, enabling again replaces the secret.
+/// Error conditions:
+/// - 409 (conflict) if two-factor authentication is already on.
+#[derive(Deserialize)]
+pub struct Enable2faRequest;
+
+/// Finish turning on two-factor authentication, by giving a code from the authenticator for the secret from
+/// `enable_2fa`. From then on, logging in with a password needs a code as well. Returns a packet of type
+/// confirm_2fa with a field "recovery_codes" containing single-use codes which can be given in place of an
+/// authenticator code, which are never shown again, and a field "token" containing a new session token.
+/// All of the user's other sessions are revoked, and their other connections are sent a `kicked` packet and
+/// closed, so nothing logged in with only the password stays logged in.
+/// Error conditions:
+/// - 403 (forbidden) if the code is wrong.
+/// - 404 (not found) if `enable_2fa` hasn't been sent.
+/// - 409 (conflict) if two-factor authentication is already on.
+#[derive(Deserialize)]
+pub struct Confirm2faRequest {
+    pub code: String,
+}
+
+/// Turn off two-factor authentication, given the account's password and a code from the authenticator or a
+/// recovery code.
+/// Error conditions:
+/// - 403 (forbidden) if the password or code is wrong.
+/// - 404 (not found) if two-factor authentication isn't on.
+/// - 429 (too many requests) if there have been too many failed logins to the account, or from this address,
+///   recently. A wrong password or code counts as a failed login.
+#[derive(Deserialize)]
+pub struct Disable2faRequest {
+    pub password: String,
+    pub code: String,
+}
+
+/// Check a code from the user's authenticator, or one of their recovery codes, at time `now`. Either kind of
+/// code can only be used once.
+pub fn check_second_factor(
+    state_lock: &LockedState,
+    user: Uuid,
+    code: &str,
+    now: i64,
+) -> Result<bool, CmdError> {
+    let Some(totp) = state_lock.get_totp(user)? else {
+        return Ok(false);
+    };
+    if let Some(step) = check_totp(&totp.secret, code, now, totp.last_step) {
+        state_lock.set_totp_last_step(user, step)?;
+        return Ok(true);
+    }
+
+    // recovery codes always have a dash in, so don't waste time hashing authenticator codes
+    let code = code.trim();
+    if code.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(false);
+    }
+    for hash in state_lock.get_recovery_codes(user)? {
+        if check_password(code, &hash)? {
+            state_lock.delete_recovery_code(user, &hash)?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+impl Request for Enable2faRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        if state_lock.get_totp(uuid)?.is_some_and(|t| t.enabled) {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+        let user = state_lock.get_user_exists(uuid)?;
+
+        let secret = make_totp_secret();
+        state_lock.set_totp_secret(uuid, &secret)?;
+        let uri = totp_uri(&secret, &user.name, &state_lock.get_name()?);
+        Ok(Enable2faResponse { secret, uri })
+    }
+}
+
+impl Request for Confirm2faRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let Some(totp) = state_lock.get_totp(uuid)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if totp.enabled {
+            return Ok(GenericResponse(Status::Conflict));
+        }
+        let now = chrono::offset::Utc::now().timestamp();
+        let Some(step) = check_totp(&totp.secret, &self.code, now, totp.last_step) else {
+            return Ok(GenericResponse(Status::Forbidden));
+        };
+
+        let recovery_codes: Vec<_> = (0..NUM_RECOVERY_CODES)
+            .map(|_| make_recovery_code())
+            .collect();
+        let hashes = recovery_codes
+            .iter()
+            .map(|c| make_hash(c))
+            .collect::<Result<Vec<_>, _>>()?;
+        state_lock.set_recovery_codes(uuid, &hashes)?;
+        state_lock.enable_totp(uuid, step)?;
+
+        state_lock.delete_sessions_of(uuid)?;
+        let mut packet = serde_json::to_value(KickedResponse {
+            reason: "Two-factor authentication was turned on".into(),
+        })?;
+        packet["status"] = (Status::Ok as i32).into();
+        state_lock.disconnect_user_except(uuid, peer.addr, packet);
+
+        let token = start_session(state_lock, peer, uuid)?;
+        Ok(Confirm2faResponse {
+            recovery_codes,
+            token,
+        })
+    }
+}
+
+impl Request for Disable2faRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(uuid) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        if !state_lock.get_totp(uuid)?.is_some_and(|t| t.enabled) {
+            return Ok(GenericResponse(Status::NotFound));
+        }
+
+        let now = chrono::offset::Utc::now().timestamp();
+        let keys = [LoginKey::Addr(peer.addr.ip()), LoginKey::Account(uuid)];
+        if state_lock.login_blocked(&keys, now) {
+            return Ok(GenericResponse(Status::TooManyRequests));
+        }
+        // a session alone isn't enough, since its token may have been stolen
+        let user = state_lock.get_user_exists(uuid)?;
+        if user.password.is_empty() || !check_password(&self.password, &user.password)? {
+            state_lock.record_login_failure(&keys, now);
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+        if !check_second_factor(state_lock, uuid, &self.code, now)? {
+            state_lock.record_login_failure(&keys, now);
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        state_lock.delete_totp(uuid)?;
+        Ok(GenericResponse(Status::Ok))
+    }
+}
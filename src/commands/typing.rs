@@ -0,0 +1,86 @@
+use crate::commands::{
+    channel_perms, send_to_channel_readers, CmdError, Request,
+    Response::{self, *},
+    Status,
+};
+use crate::helper::{LockedState, Uuid};
+use crate::permissions::Perm;
+use crate::Peer;
+use serde::Deserialize;
+
+/// How long a typing indicator lasts for, in seconds. Clients should send `typing` again before this runs out
+/// if the user is still typing.
+pub const TYPING_TIMEOUT: i64 = 8;
+
+/// Tell everyone who can read a channel that the user is typing in it. They are sent a packet of type
+/// user_typing with the fields "channel", "user" and "expires". The indicator stops when it expires, when the
+/// user sends a message in the channel, or when the user disconnects, at which point a user_stopped_typing
+/// packet is sent unless a message was sent. Typing indicators are never stored.
+/// Error conditions:
+/// - 403 (forbidden) if the user cannot send messages in the channel.
+/// - 404 (not found) if the channel does not exist.
+#[derive(Deserialize)]
+pub struct TypingRequest {
+    pub channel: Uuid,
+}
+
+impl Request for TypingRequest {
+    fn execute(self, state_lock: &mut LockedState, peer: &mut Peer) -> Result<Response, CmdError> {
+        let Some(user) = peer.uuid else {
+            return Ok(GenericResponse(Status::Unauthenticated));
+        };
+        let Some(channel) = state_lock.get_channel(&self.channel)? else {
+            return Ok(GenericResponse(Status::NotFound));
+        };
+        if channel_perms(state_lock, peer.uuid, &channel)?.send_messages != Perm::Allow {
+            return Ok(GenericResponse(Status::Forbidden));
+        }
+
+        let expires = chrono::offset::Utc::now().timestamp() + TYPING_TIMEOUT;
+        state_lock.set_typing(channel.uuid, user, expires);
+
+        let mut packet = serde_json::to_value(UserTypingResponse {
+            channel: channel.uuid,
+            user,
+            expires,
+        })?;
+        packet["status"] = (Status::Ok as i32).into();
+        send_to_channel_readers(state_lock, &channel, &packet)?;
+
+        Ok(GenericResponse(Status::Ok))
+    }
+}
+
+fn send_stopped_typing(
+    state_lock: &LockedState,
+    channel: Uuid,
+    user: Uuid,
+) -> Result<(), CmdError> {
+    // the channel might have been deleted while they were typing, in which case nobody cares
+    let Some(channel) = state_lock.get_channel(&channel)? else {
+        return Ok(());
+    };
+    let mut packet = serde_json::to_value(UserStoppedTypingResponse {
+        channel: channel.uuid,
+        user,
+    })?;
+    packet["status"] = (Status::Ok as i32).into();
+    send_to_channel_readers(state_lock, &channel, &packet)
+}
+
+/// Stop all the typing indicators that have run out, letting everyone involved know.
+pub fn expire_typing(state_lock: &mut LockedState) -> Result<(), CmdError> {
+    let now = chrono::offset::Utc::now().timestamp();
+    for (channel, user) in state_lock.take_expired_typing(now) {
+        send_stopped_typing(state_lock, channel, user)?;
+    }
+    Ok(())
+}
+
+/// Stop all of a user's typing indicators, e.g. because they have disconnected.
+pub fn stop_typing_of(state_lock: &mut LockedState, user: Uuid) -> Result<(), CmdError> {
+    for channel in state_lock.take_typing_of(user) {
+        send_stopped_typing(state_lock, channel, user)?;
+    }
+    Ok(())
+}
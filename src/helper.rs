@@ -1,10 +1,37 @@
 use crate::Shared;
 use rand::prelude::*;
+use sha2::{Digest, Sha256};
 
 pub fn gen_uuid() -> i64 {
     (random::<u64>() >> (64 - 53)) as i64 // generate 53 bit integer because javascript is fucking dumb
 }
 
+/// Check for the PNG file signature at the start of `data`.
+pub fn is_png(data: &[u8]) -> bool {
+    data.starts_with(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'])
+}
+
+/// The sha256 hash of `data`, as lowercase hex.
+pub fn hash_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
 pub type LockedState<'a> = tokio::sync::MutexGuard<'a, Shared>;
 pub type JsonValue = serde_json::Value;
 pub type Uuid = i64;
+
+#[cfg(test)]
+mod tests {
+    use super::hash_hex;
+
+    #[test]
+    fn hash_is_sha256() {
+        assert_eq!(
+            hash_hex(b"hello world"),
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+    }
+}
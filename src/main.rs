@@ -7,7 +7,7 @@ use base64::{engine::general_purpose, Engine as _};
 use commands::Response;
 use helper::gen_uuid;
 use lazy_static::lazy_static;
-use models::{Group, User};
+use models::{Group, User, UserStatus};
 use permissions::{Perm, Permissions};
 use serde::Deserialize;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
@@ -18,6 +18,7 @@ use tokio_stream::StreamExt;
 use tokio_util::codec::{Framed, LinesCodec};
 
 use futures::SinkExt;
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
 use std::fs::File;
@@ -60,6 +61,145 @@ pub struct Config {
     pub database_file: String,
     pub certificate_chain: String,
     pub private_key: String,
+    /// Largest file that can be uploaded as an attachment, in bytes
+    #[serde(default = "default_max_attachment_size")]
+    pub max_attachment_size: usize,
+    /// MIME types that are allowed to be uploaded as attachments
+    #[serde(default = "default_attachment_types")]
+    pub attachment_types: Vec<String>,
+    /// How much a user can have uploaded but not sent yet, in bytes
+    #[serde(default = "default_max_unattached_size")]
+    pub max_unattached_size: usize,
+    /// How long uploads which haven't been sent are kept for, in seconds
+    #[serde(default = "default_unattached_retention")]
+    pub unattached_retention: i64,
+    /// How long deleted messages are kept for before being purged, in seconds
+    #[serde(default = "default_deleted_message_retention")]
+    pub deleted_message_retention: i64,
+    /// How long a session token can be used to log in for after it is issued, in seconds
+    #[serde(default = "default_session_lifetime")]
+    pub session_lifetime: i64,
+    /// Shortest password that can be set, in characters
+    #[serde(default = "default_min_password_length")]
+    pub min_password_length: usize,
+    /// Longest password that can be set, in characters
+    #[serde(default = "default_max_password_length")]
+    pub max_password_length: usize,
+    /// How long to wait after the first failed login before trying again, in seconds. This doubles with
+    /// each failure
+    #[serde(default = "default_login_backoff_base")]
+    pub login_backoff_base: i64,
+    /// How many failed logins in a row an account or address can make before it is locked out
+    #[serde(default = "default_login_attempts_before_lockout")]
+    pub login_attempts_before_lockout: u32,
+    /// How long accounts and addresses are locked out for, in seconds
+    #[serde(default = "default_login_lockout_duration")]
+    pub login_lockout_duration: i64,
+    /// Whether to stop new accounts being registered from addresses banned users connected from
+    #[serde(default = "default_block_banned_addrs")]
+    pub block_banned_addrs: bool,
+    /// How many commands a connection can send in a burst, weighted by `rate_limit_costs`
+    #[serde(default = "default_rate_limit_capacity")]
+    pub rate_limit_capacity: f64,
+    /// How fast a connection's allowance refills, per second
+    #[serde(default = "default_rate_limit_refill")]
+    pub rate_limit_refill: f64,
+    /// How much of the allowance each command uses up, at most `rate_limit_capacity`. Commands not listed cost 1
+    #[serde(default = "default_rate_limit_costs")]
+    pub rate_limit_costs: HashMap<String, f64>,
+    /// How many commands in a row can be refused for being over the limit before the connection is closed
+    #[serde(default = "default_rate_limit_strikes")]
+    pub rate_limit_strikes: u32,
+}
+
+impl Config {
+    /// How much of a connection's rate limit allowance `command` uses up.
+    pub fn command_cost(&self, command: &str) -> f64 {
+        self.rate_limit_costs.get(command).copied().unwrap_or(1.0)
+    }
+}
+
+fn default_max_attachment_size() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_attachment_types() -> Vec<String> {
+    [
+        "image/png",
+        "image/jpeg",
+        "image/gif",
+        "image/webp",
+        "text/plain",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+fn default_max_unattached_size() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_unattached_retention() -> i64 {
+    24 * 60 * 60
+}
+
+fn default_deleted_message_retention() -> i64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_session_lifetime() -> i64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_min_password_length() -> usize {
+    8
+}
+
+fn default_max_password_length() -> usize {
+    256
+}
+
+fn default_login_backoff_base() -> i64 {
+    1
+}
+
+fn default_login_attempts_before_lockout() -> u32 {
+    10
+}
+
+fn default_login_lockout_duration() -> i64 {
+    15 * 60
+}
+
+fn default_block_banned_addrs() -> bool {
+    true
+}
+
+fn default_rate_limit_capacity() -> f64 {
+    50.0
+}
+
+fn default_rate_limit_refill() -> f64 {
+    10.0
+}
+
+fn default_rate_limit_costs() -> HashMap<String, f64> {
+    [
+        ("send", 2.0),
+        ("history", 5.0),
+        ("search", 5.0),
+        ("pfp", 10.0),
+        ("set_server_icon", 10.0),
+        ("create_emoji", 10.0),
+    ]
+    .iter()
+    .map(|(c, cost)| (c.to_string(), *cost))
+    .collect()
+}
+
+fn default_rate_limit_strikes() -> u32 {
+    20
 }
 
 fn read_b64(fname: &str) -> Option<String> {
@@ -89,6 +229,16 @@ lazy_static! {
 
                 cfg.icon = icon;
                 cfg.default_pfp = default_pfp;
+
+                // commands costing more than a full allowance could never be sent
+                for (command, cost) in &cfg.rate_limit_costs {
+                    if *cost > cfg.rate_limit_capacity {
+                        panic!(
+                            "Rate limit cost of '{}' ({}) is more than rate_limit_capacity ({})!",
+                            command, cost, cfg.rate_limit_capacity
+                        );
+                    }
+                }
                 cfg
             }
             Err(e) => {
@@ -120,43 +270,52 @@ async fn main() -> Result<(), Box<dyn Error>> {
     shared.init_db();
 
     // DEBUG lol
-    if args.len() >= 5 {
-        if args[2] == "--admin-user" {
-            let username = &args[3];
-            let password = &args[4];
-            let group = Group {
-                uuid: gen_uuid(),
-                permissions: Permissions {
-                    modify_channels: Perm::Allow,
-                    modify_icon_name: Perm::Allow,
-                    modify_groups: Perm::Allow,
-                    modify_user_groups: Perm::Allow,
-                    ban_users: Perm::Allow,
-                    send_messages: Perm::Allow,
-                    read_messages: Perm::Allow,
-                    manage_messages: Perm::Allow,
-                    join_voice: Perm::Allow,
-                    view_channel: Perm::Allow,
-                },
-                name: "admin".to_string(),
-                colour: 0,
-                position: 0,
-            };
-            let user = User {
-                name: username.to_owned(),
-                pfp: CONF.default_pfp.to_owned(),
-                uuid: gen_uuid(),
-                password: crate::commands::auth::make_hash(password)?,
-                groups: vec![group.uuid],
-            };
-
-            shared.insert_group(&group)?;
-            shared.insert_user(&user)?;
-        }
+    if args.len() >= 5 && args[2] == "--admin-user" {
+        let username = &args[3];
+        let password = &args[4];
+        let group = Group {
+            uuid: gen_uuid(),
+            permissions: Permissions {
+                modify_channels: Perm::Allow,
+                modify_icon_name: Perm::Allow,
+                modify_groups: Perm::Allow,
+                modify_user_groups: Perm::Allow,
+                ban_users: Perm::Allow,
+                send_messages: Perm::Allow,
+                read_messages: Perm::Allow,
+                manage_messages: Perm::Allow,
+                join_voice: Perm::Allow,
+                view_channel: Perm::Allow,
+                manage_emoji: Perm::Allow,
+                view_audit_log: Perm::Allow,
+                mention_everyone: Perm::Allow,
+                post_announcements: Perm::Allow,
+            },
+            name: "admin".to_string(),
+            colour: 0,
+            position: 0,
+        };
+        let user = User {
+            name: username.to_owned(),
+            pfp: CONF.default_pfp.to_owned(),
+            uuid: gen_uuid(),
+            password: crate::commands::auth::make_hash(password)?,
+            groups: vec![group.uuid],
+            status: UserStatus::Online,
+            status_text: None,
+        };
+
+        shared.insert_group(&group)?;
+        shared.insert_user(&user)?;
     }
     // debug end
     let state = Arc::new(Mutex::new(shared));
 
+    tokio::spawn(purge_deleted_messages(Arc::clone(&state)));
+    tokio::spawn(purge_unattached_uploads(Arc::clone(&state)));
+    tokio::spawn(expire_typing(Arc::clone(&state)));
+    tokio::spawn(send_scheduled_messages(Arc::clone(&state)));
+
     let addr = format!("{}:{}", &CONF.addr, CONF.port);
 
     let listener = TcpListener::bind(&addr).await?;
@@ -165,6 +324,56 @@ async fn main() -> Result<(), Box<dyn Error>> {
     mainloop(listener, state).await
 }
 
+/// Every so often, permanently delete messages that were deleted longer ago than the retention period.
+async fn purge_deleted_messages(state: Arc<Mutex<Shared>>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        let cutoff = chrono::offset::Utc::now().timestamp() - CONF.deleted_message_retention;
+        match state.lock().await.purge_deleted_messages(cutoff) {
+            Ok(0) => (),
+            Ok(n) => log::info!("Purged {} deleted messages", n),
+            Err(e) => log::error!("Failed to purge deleted messages: {:?}", e),
+        }
+    }
+}
+
+/// Every so often, delete uploads that were never sent in a message.
+async fn purge_unattached_uploads(state: Arc<Mutex<Shared>>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        let cutoff = chrono::offset::Utc::now().timestamp() - CONF.unattached_retention;
+        match state.lock().await.purge_unattached(cutoff) {
+            Ok(0) => (),
+            Ok(n) => log::info!("Purged {} unsent attachments", n),
+            Err(e) => log::error!("Failed to purge unsent attachments: {:?}", e),
+        }
+    }
+}
+
+/// Stop typing indicators once they run out, so that clients which disappear mid-message don't stay typing.
+async fn expire_typing(state: Arc<Mutex<Shared>>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if let Err(e) = commands::typing::expire_typing(&mut state.lock().await) {
+            log::error!("Failed to expire typing indicators: {:?}", e);
+        }
+    }
+}
+
+/// Send scheduled messages once they're due.
+async fn send_scheduled_messages(state: Arc<Mutex<Shared>>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if let Err(e) = commands::scheduled::send_due_scheduled(&mut state.lock().await) {
+            log::error!("Failed to send scheduled messages: {:?}", e);
+        }
+    }
+}
+
 #[cfg(feature = "notls")]
 async fn mainloop(listener: TcpListener, state: Arc<Mutex<Shared>>) -> Result<(), Box<dyn Error>> {
     loop {
@@ -226,6 +435,9 @@ async fn start_worker(stream: SocketStream, state: Arc<Mutex<Shared>>, addr: std
         }
         if count == 1 {
             send_online(&state);
+            if let Err(e) = commands::typing::stop_typing_of(&mut state, uuid) {
+                log::error!("Failed to stop typing indicators: {:?}", e);
+            }
         }
     }
     if let Some(index) = state.peers.iter().position(|x| x.1 == peer.addr) {
@@ -295,7 +507,12 @@ async fn process(
 
     {
         let mut state = state.lock().await;
-        state.peers.push((peer.tx.clone(), peer.addr, peer.uuid));
+        state.peers.push((
+            peer.tx.clone(),
+            peer.addr,
+            peer.uuid,
+            Arc::clone(&peer.kick),
+        ));
     }
     let mut json = serde_json::to_value(Response::APIVersionResponse {
         version: API_VERSION,
@@ -317,6 +534,8 @@ async fn process(
         s: stream,
     };
 
+    let kick = Arc::clone(&peer.kick);
+
     if first_char == '{' {
         // Start of a JSON packet, we're using raw sockets
         let mut lines = Framed::new(stream_with_first_char, LinesCodec::new());
@@ -324,13 +543,26 @@ async fn process(
         loop {
             tokio::select! {
                 result = lines.next() => match result {
-                    Some(Ok(msg)) =>
-                        commands::process_command(&msg, &mut state.lock().await, peer)?,
+                    Some(Ok(msg)) => {
+                        // only lock the state for requests that get past the rate limit
+                        let request = commands::admit_command(&msg, peer)?;
+                        if let Some(request) = request {
+                            commands::process_command(request, &mut state.lock().await, peer)?;
+                        }
+                    }
                     Some(Err(e)) => log::error!("Error receiving data: {}", e),
                     None => break,
                 },
 
                 Some(msg) = peer.rx.recv() => lines.send(msg.to_string()).await?,
+
+                _ = kick.notified() => {
+                    // make sure the client finds out why it's being disconnected
+                    while let Ok(msg) = peer.rx.try_recv() {
+                        lines.send(msg.to_string()).await?;
+                    }
+                    break;
+                }
             }
         }
     } else {
@@ -341,8 +573,12 @@ async fn process(
             tokio::select! {
                 result = lines.next() => match result {
                     Some(Ok(msg)) => match msg {
-                            Message::Text(msg) =>
-                                commands::process_command(&msg, &mut state.lock().await, peer)?,
+                            Message::Text(msg) => {
+                                let request = commands::admit_command(&msg, peer)?;
+                                if let Some(request) = request {
+                                    commands::process_command(request, &mut state.lock().await, peer)?;
+                                }
+                            }
                             _ => log::warn!("Got non-text websocket message: {:?}", msg), // TODO handle this properly
                         }
                     Some(Err(e)) => log::error!("Error receiving data: {}", e),
@@ -350,6 +586,13 @@ async fn process(
                 },
 
                 Some(msg) = peer.rx.recv() => lines.send(Message::Text(msg.to_string())).await?,
+
+                _ = kick.notified() => {
+                    while let Ok(msg) = peer.rx.try_recv() {
+                        lines.send(Message::Text(msg.to_string())).await?;
+                    }
+                    break;
+                }
             }
         }
     }
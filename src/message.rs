@@ -1,5 +1,8 @@
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use serde::{Deserialize, Serialize};
 
+use crate::helper::Uuid;
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Message {
     pub uuid: i64,
@@ -9,4 +12,210 @@ pub struct Message {
     pub date: i32,
     pub edited: bool,
     pub reply: Option<i64>,
+    /// When the message was last edited
+    #[serde(default)]
+    pub edited_at: Option<i64>,
+    /// When the message was deleted, if it has been. Deleted messages are kept around for a while
+    /// so that moderators can review or restore them.
+    #[serde(default)]
+    pub deleted_at: Option<i64>,
+    /// Who deleted the message
+    #[serde(default)]
+    pub deleted_by: Option<Uuid>,
+    #[serde(default)]
+    pub reactions: Vec<ReactionCount>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+impl Message {
+    /// Strip everything but the placeholder information from a deleted message,
+    /// for users who aren't allowed to see what was deleted.
+    pub fn redact(&mut self) {
+        self.content.clear();
+        self.reactions.clear();
+        self.attachments.clear();
+        self.deleted_by = None;
+    }
+}
+
+/// Someone mentioned in a message's content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mention {
+    /// Written as `<@uuid>`
+    User(Uuid),
+    /// Written as `<@&uuid>`, mentioning every member of the group
+    Group(Uuid),
+    /// Written as `@everyone`, mentioning everyone who can read the channel
+    Everyone,
+}
+
+/// Find every mention in some message content, in the order they appear. Each mention is only returned once,
+/// however many times it's repeated.
+pub fn parse_mentions(content: &str) -> Vec<Mention> {
+    let mut mentions = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find('@') {
+        let before = &rest[..start];
+        rest = &rest[start + 1..];
+
+        let mention = if before.ends_with('<') {
+            let (is_group, body) = match rest.strip_prefix('&') {
+                Some(body) => (true, body),
+                None => (false, rest),
+            };
+            let Some(end) = body.find('>') else {
+                continue;
+            };
+            let Ok(uuid) = body[..end].parse::<Uuid>() else {
+                continue;
+            };
+            rest = &body[end + 1..];
+            if is_group {
+                Mention::Group(uuid)
+            } else {
+                Mention::User(uuid)
+            }
+        } else if rest.starts_with("everyone")
+            && !before.ends_with(|c: char| c.is_alphanumeric())
+            && !rest["everyone".len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+        {
+            rest = &rest["everyone".len()..];
+            Mention::Everyone
+        } else {
+            continue;
+        };
+        if !mentions.contains(&mention) {
+            mentions.push(mention);
+        }
+    }
+    mentions
+}
+
+/// A message waiting to be sent at a later time.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub uuid: Uuid,
+    pub content: String,
+    pub author_uuid: Uuid,
+    pub channel_uuid: Uuid,
+    pub reply: Option<Uuid>,
+    /// When to send the message, in seconds since the epoch
+    pub send_at: i64,
+}
+
+/// The content of a message at some point in its edit history, and when it was written.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageRevision {
+    pub content: String,
+    pub date: i64,
+}
+
+/// A file uploaded to the server. Its contents are stored separately, keyed by their hash,
+/// and are fetched in chunks with `get_attachment`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub uuid: Uuid,
+    pub name: String,
+    pub mime: String,
+    pub size: usize,
+    #[serde(skip)]
+    pub hash: String,
+    #[serde(skip)]
+    pub uploader_uuid: Uuid,
+    /// The message this is attached to, or `None` if it has been uploaded but not sent yet.
+    #[serde(skip)]
+    pub message_uuid: Option<Uuid>,
+    /// When the upload finished, in seconds since the epoch.
+    #[serde(skip)]
+    pub uploaded_at: i64,
+}
+
+/// An emoji used to react to a message: either a unicode emoji as a string,
+/// or the uuid of one of the server's custom emoji.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ReactionEmoji {
+    Unicode(String),
+    Custom(Uuid),
+}
+
+/// How many users have reacted to a message with a given emoji.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReactionCount {
+    pub emoji: ReactionEmoji,
+    pub count: u32,
+}
+
+// stored in a single column, as text for unicode emoji and an integer for custom emoji
+impl ToSql for ReactionEmoji {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            ReactionEmoji::Unicode(s) => s.to_sql(),
+            ReactionEmoji::Custom(uuid) => uuid.to_sql(),
+        }
+    }
+}
+
+impl FromSql for ReactionEmoji {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(_) => Ok(ReactionEmoji::Unicode(String::column_result(value)?)),
+            ValueRef::Integer(uuid) => Ok(ReactionEmoji::Custom(uuid)),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Filters for searching message history. Every filter that is set must match.
+#[derive(Clone, Debug, Default)]
+pub struct MessageSearch {
+    /// Words that must all appear in the content. Empty to match any content.
+    pub query: String,
+    /// The channels to search in. Messages in any other channel are never returned.
+    pub channels: Vec<Uuid>,
+    pub author: Option<Uuid>,
+    /// Only messages sent at or after this time.
+    pub after: Option<i64>,
+    /// Only messages sent at or before this time.
+    pub before: Option<i64>,
+    /// Only messages that are (or are not) replies.
+    pub has_reply: Option<bool>,
+}
+
+impl MessageSearch {
+    /// Turn the query into an FTS5 match expression, quoting every word so that any
+    /// FTS5 syntax typed by the user is matched literally instead of being interpreted.
+    /// Returns `None` if the query has no words in it.
+    pub fn fts_query(&self) -> Option<String> {
+        let words: Vec<_> = self
+            .query
+            .split_whitespace()
+            .map(|w| format!("\"{}\"", w.replace('"', "\"\"")))
+            .collect();
+        if words.is_empty() {
+            None
+        } else {
+            Some(words.join(" "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mentions, Mention::*};
+
+    #[test]
+    fn mentions() {
+        assert_eq!(
+            parse_mentions("hi <@12> and <@&34>, <@12> again. @everyone!"),
+            vec![User(12), Group(34), Everyone]
+        );
+        assert_eq!(
+            parse_mentions("<@> <@&x> <@12 me@everyone.com @everyones"),
+            vec![]
+        );
+        assert_eq!(parse_mentions("<@<@5>"), vec![User(5)]);
+        assert_eq!(parse_mentions("no mentions here"), vec![]);
+    }
 }
@@ -2,6 +2,7 @@ use crate::{
     helper::Uuid,
     permissions::{PermableEntity, Permissions},
 };
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
@@ -12,6 +13,44 @@ pub struct Channel {
     pub name: String,
     pub position: usize,
     pub permissions: HashMap<PermableEntity, Permissions>,
+    /// The two members of a direct message channel, or `None` for a regular server channel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dm_members: Option<(Uuid, Uuid)>,
+    /// The category the channel is in. A channel's position is relative to the other channels in the same
+    /// category, or to the other uncategorised channels.
+    #[serde(default)]
+    pub category: Option<Uuid>,
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub nsfw: bool,
+    /// Archived channels are read-only: nobody can send messages in them.
+    #[serde(default)]
+    pub archived: bool,
+    /// How long users must wait between sending messages, in seconds, or 0 to not limit them.
+    /// Users with `manage_messages` in the channel are exempt.
+    #[serde(default)]
+    pub slow_mode: u32,
+    /// Only users with `post_announcements` can send in announcement channels, and their messages can be
+    /// published to the channels following it.
+    #[serde(default)]
+    pub announcement: bool,
+}
+
+impl Channel {
+    pub fn is_dm(&self) -> bool {
+        self.dm_members.is_some()
+    }
+}
+
+/// A named group of channels, shown together (and collapsible) in clients. Its permission overrides apply to
+/// every channel in it, beneath the channel's own overrides.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Category {
+    pub uuid: Uuid,
+    pub name: String,
+    pub position: usize,
+    pub permissions: HashMap<PermableEntity, Permissions>,
 }
 
 //message.rs for message models
@@ -25,6 +64,60 @@ pub struct User {
     #[serde(skip)]
     pub password: String, // hashed, don't you worry
     pub groups: Vec<Uuid>,
+    #[serde(default)]
+    pub status: UserStatus,
+    /// Custom text shown alongside the user's status
+    #[serde(default)]
+    pub status_text: Option<String>,
+}
+
+impl User {
+    /// Hide the fact that a user is invisible from anyone but themselves; to everyone else, an invisible
+    /// user looks just like an offline user with the default status and no status text.
+    pub fn seen_by(mut self, viewer: Option<Uuid>) -> Self {
+        if self.status == UserStatus::Invisible && viewer != Some(self.uuid) {
+            self.status = UserStatus::Online;
+            self.status_text = None;
+        }
+        self
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    #[default]
+    Online,
+    Idle,
+    DoNotDisturb,
+    /// Appear offline to everyone else, while still being connected
+    Invisible,
+}
+
+// stored as the same string it is serialised as
+impl ToSql for UserStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => Ok(ToSqlOutput::from(s)),
+            _ => unreachable!(), // unit variants always serialise to strings
+        }
+    }
+}
+
+impl FromSql for UserStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let s = String::column_result(value)?;
+        serde_json::from_value(serde_json::Value::String(s))
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+/// The status of a user who is currently online, as broadcast in `online` packets.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct Presence {
+    pub uuid: Uuid,
+    pub status: UserStatus,
+    pub status_text: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
@@ -57,7 +150,175 @@ pub struct SyncServer {
     pub idx: i32,
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct Ban {
+    pub user_uuid: Uuid,
+    pub issuer_uuid: Uuid,
+    pub reason: String,
+    pub date: i64,
+    /// When the ban lifts, or `None` if it is permanent.
+    pub expires: Option<i64>,
+}
+
+/// A user's TOTP two-factor authentication setup. It isn't required to log in until it has been confirmed
+/// with a code from the authenticator.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Totp {
+    pub user_uuid: Uuid,
+    /// Base32 encoded.
+    pub secret: String,
+    pub enabled: bool,
+    /// The time step of the last code used, so that codes can't be used twice.
+    pub last_step: i64,
+}
+
+/// A login session. Logging in with a password issues a session token, which can be used with `login_token`
+/// to log in again without the password until the session expires or is revoked. Only a hash of the token is
+/// stored, so it is never sent again after the login that created it.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct Session {
+    pub uuid: Uuid,
+    pub user_uuid: Uuid,
+    pub created: i64,
+    pub expires: i64,
+    /// When the session was last used to log in.
+    pub last_used: i64,
+}
+
+/// A conversation branching off a message. Its messages are sent to a channel of its own, with the same
+/// uuid as the thread, so they are kept out of the parent channel's history. Threads always use the
+/// permissions of the channel they were started in.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct Thread {
+    pub uuid: Uuid,
+    pub name: String,
+    /// The channel the thread was started in
+    pub channel_uuid: Uuid,
+    /// The message the thread was started from
+    pub message_uuid: Uuid,
+    pub creator_uuid: Uuid,
+    pub date: i64,
+    /// Everyone who has created or sent a message in the thread
+    pub participants: Vec<Uuid>,
+}
+
+/// A record of a privileged action taken by a user. The audit log is append-only.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct AuditLogEntry {
+    /// Increases with every entry, so can be used to page through the log
+    pub id: i64,
+    pub date: i64,
+    pub actor_uuid: Uuid,
+    pub action: AuditAction,
+    /// The uuid of whatever was acted on, if the action has a target
+    pub target: Option<Uuid>,
+    /// The relevant state before the action, if anything existed before it
+    pub before: Option<serde_json::Value>,
+    /// The relevant state after the action, if anything exists after it
+    pub after: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    CreateChannel,
+    DeleteChannel,
+    UpdateChannel,
+    CreateCategory,
+    DeleteCategory,
+    UpdateCategory,
+    CreateGroup,
+    DeleteGroup,
+    UpdateGroup,
+    UpdateUserGroups,
+    DeleteMessage,
+    RestoreMessage,
+    PinMessage,
+    UnpinMessage,
+    Ban,
+    Unban,
+    Kick,
+    SetServerName,
+    SetServerIcon,
+    SetBasePermissions,
+    CreateEmoji,
+    RenameEmoji,
+    DeleteEmoji,
+}
+
+// stored as the same string it is serialised as
+impl ToSql for AuditAction {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => Ok(ToSqlOutput::from(s)),
+            _ => unreachable!(), // unit variants always serialise to strings
+        }
+    }
+}
+
+impl FromSql for AuditAction {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let s = String::column_result(value)?;
+        serde_json::from_value(serde_json::Value::String(s))
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+/// Which messages in a channel a user wants to be notified about.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyLevel {
+    #[default]
+    All,
+    /// Only messages that mention the user
+    Mentions,
+    None,
+}
+
+// stored as the same string it is serialised as
+impl ToSql for NotifyLevel {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => Ok(ToSqlOutput::from(s)),
+            _ => unreachable!(), // unit variants always serialise to strings
+        }
+    }
+}
+
+impl FromSql for NotifyLevel {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let s = String::column_result(value)?;
+        serde_json::from_value(serde_json::Value::String(s))
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+/// A user's notification preferences for one channel. Channels without any settings stored notify on everything.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct NotifySettings {
+    pub channel_uuid: Uuid,
+    pub level: NotifyLevel,
+    /// Until when the channel is muted, in seconds since the epoch. While muted, the channel doesn't notify at all,
+    /// whatever its level.
+    pub muted_until: Option<i64>,
+}
+
+impl NotifySettings {
+    /// Whether the channel shouldn't notify the user about anything at time `now`.
+    pub fn is_muted(&self, now: i64) -> bool {
+        self.level == NotifyLevel::None || self.muted_until.is_some_and(|t| t > now)
+    }
+}
+
+/// Filters for reading the audit log. Every filter that is set must match.
+#[derive(Clone, Debug, Default)]
+pub struct AuditLogFilter {
+    pub actor: Option<Uuid>,
+    pub action: Option<AuditAction>,
+    pub target: Option<Uuid>,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
 pub struct Emoji {
     pub uuid: i64,
     pub name: String,
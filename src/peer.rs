@@ -1,11 +1,73 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{mpsc, Notify};
+
+use crate::CONF;
+
+/// An attachment that is part way through being uploaded over this connection.
+pub struct PendingUpload {
+    pub name: String,
+    pub mime: String,
+    pub size: usize,
+    pub data: Vec<u8>,
+}
+
+/// A token bucket limiting how many commands a connection can send. It holds up to `capacity` tokens, which
+/// refill at `refill` tokens per second, and each command takes out some number of tokens depending on how
+/// expensive it is.
+pub struct RateLimiter {
+    capacity: f64,
+    refill: f64,
+    tokens: f64,
+    last: Instant,
+    /// How many commands in a row have been refused.
+    pub strikes: u32,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: f64, refill: f64, now: Instant) -> RateLimiter {
+        RateLimiter {
+            capacity,
+            refill,
+            tokens: capacity,
+            last: now,
+            strikes: 0,
+        }
+    }
+
+    /// Take `cost` tokens at time `now` if there are enough, and return whether there were.
+    pub fn try_take(&mut self, cost: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill).min(self.capacity);
+        self.last = now;
+
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            self.strikes = 0;
+            true
+        } else {
+            self.strikes += 1;
+            false
+        }
+    }
+}
 
 pub struct Peer {
     pub rx: mpsc::UnboundedReceiver<serde_json::Value>,
     pub tx: mpsc::UnboundedSender<serde_json::Value>,
     pub uuid: Option<i64>,
+    /// The session this connection is logged in with.
+    pub session: Option<i64>,
+    /// The user whose password has been given, but who still has to give a two-factor code to log in, and
+    /// when the password was given.
+    pub pending_2fa: Option<(i64, i64)>,
     pub addr: SocketAddr,
+    /// Notified when the connection should be closed by the server, e.g. when the user is kicked.
+    pub kick: Arc<Notify>,
+    pub uploads: HashMap<i64, PendingUpload>,
+    pub rate_limit: RateLimiter,
 }
 
 impl Peer {
@@ -23,6 +85,15 @@ impl Peer {
             tx,
             addr,
             uuid: None,
+            session: None,
+            pending_2fa: None,
+            kick: Arc::new(Notify::new()),
+            uploads: HashMap::new(),
+            rate_limit: RateLimiter::new(
+                CONF.rate_limit_capacity,
+                CONF.rate_limit_refill,
+                Instant::now(),
+            ),
         }
     }
 
@@ -30,3 +101,31 @@ impl Peer {
         self.uuid.is_some()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn rate_limit() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(10.0, 2.0, start);
+        assert!(limiter.try_take(6.0, start));
+        assert!(limiter.try_take(4.0, start));
+        assert!(!limiter.try_take(1.0, start));
+        assert!(!limiter.try_take(1.0, start));
+        assert_eq!(limiter.strikes, 2);
+
+        // refills at 2 tokens per second
+        let later = start + Duration::from_secs(2);
+        assert!(!limiter.try_take(5.0, later));
+        assert!(limiter.try_take(4.0, later));
+        assert_eq!(limiter.strikes, 0);
+
+        // but never past the capacity
+        let much_later = later + Duration::from_secs(60);
+        assert!(!limiter.try_take(11.0, much_later));
+        assert!(limiter.try_take(10.0, much_later));
+    }
+}
@@ -27,6 +27,10 @@ pub struct Permissions {
     pub manage_messages: Perm,
     pub join_voice: Perm,
     pub view_channel: Perm,
+    pub manage_emoji: Perm,
+    pub view_audit_log: Perm,
+    pub mention_everyone: Perm,
+    pub post_announcements: Perm,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Hash, Serialize, Deserialize)]
@@ -56,6 +60,7 @@ impl From<Perm> for u8 {
     }
 }
 
+#[allow(clippy::identity_op)] // keep the shifts lined up
 fn byte_to_perms(b: u8) -> [Perm; 4] {
     [
         ((b >> 0) & 0b11).into(),
@@ -65,6 +70,7 @@ fn byte_to_perms(b: u8) -> [Perm; 4] {
     ]
 }
 
+#[allow(clippy::identity_op)]
 fn perms_to_byte(p: [Perm; 4]) -> u8 {
     (Into::<u8>::into(p[0]) << 0)
         | (Into::<u8>::into(p[1]) << 2)
@@ -93,11 +99,15 @@ impl From<&[u8]> for Permissions {
             manage_messages: get_perm(7),
             join_voice: get_perm(8),
             view_channel: get_perm(9),
+            manage_emoji: get_perm(10),
+            view_audit_log: get_perm(11),
+            mention_everyone: get_perm(12),
+            post_announcements: get_perm(13),
         }
     }
 }
 
-const PERM_N_BYTES: usize = 3;
+const PERM_N_BYTES: usize = 4;
 
 impl From<&Permissions> for Box<[u8]> {
     fn from(value: &Permissions) -> Self {
@@ -117,6 +127,12 @@ impl From<&Permissions> for Box<[u8]> {
             perms_to_byte([
                 value.join_voice,
                 value.view_channel,
+                value.manage_emoji,
+                value.view_audit_log,
+            ]),
+            perms_to_byte([
+                value.mention_everyone,
+                value.post_announcements,
                 Perm::Default,
                 Perm::Default,
             ]),
@@ -213,6 +229,10 @@ impl Permissions {
             manage_messages: self.manage_messages.combine(other.manage_messages),
             join_voice: self.join_voice.combine(other.join_voice),
             view_channel: self.view_channel.combine(other.view_channel),
+            manage_emoji: self.manage_emoji.combine(other.manage_emoji),
+            view_audit_log: self.view_audit_log.combine(other.view_audit_log),
+            mention_everyone: self.mention_everyone.combine(other.mention_everyone),
+            post_announcements: self.post_announcements.combine(other.post_announcements),
         }
     }
 }
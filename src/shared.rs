@@ -9,23 +9,51 @@ use crate::CONF;
 use base64::engine::general_purpose;
 use base64::Engine;
 use rusqlite::params;
+use rusqlite::types::Type;
 use rusqlite::Connection;
 use rusqlite::OptionalExtension;
 use std::collections::HashMap;
-use tokio::sync::mpsc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Notify};
+
+/// Everything other connections need to reach a peer: its outgoing message queue, its address,
+/// who it is logged in as, and a handle to disconnect it.
+pub type PeerHandle = (
+    mpsc::UnboundedSender<serde_json::Value>,
+    std::net::SocketAddr,
+    Option<Uuid>,
+    Arc<Notify>,
+);
+
+/// Something that failed logins are counted against.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum LoginKey {
+    Account(Uuid),
+    Addr(IpAddr),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LoginFailures {
+    pub count: u32,
+    /// When the most recent failure was.
+    pub last: i64,
+    /// No more attempts are allowed until this time.
+    pub blocked_until: i64,
+}
 
 pub struct Shared {
     pub online: HashMap<i64, u32>,
     pub conn: Connection,
-    pub peers: Vec<(
-        mpsc::UnboundedSender<serde_json::Value>,
-        std::net::SocketAddr,
-        Option<Uuid>,
-    )>,
+    pub peers: Vec<PeerHandle>,
+    /// When each (channel, user) pair's typing indicator expires. Never persisted.
+    pub typing: HashMap<(Uuid, Uuid), i64>,
+    /// Recent failed logins for each account and address. Never persisted.
+    pub login_failures: HashMap<LoginKey, LoginFailures>,
 }
 
 pub type DbError = rusqlite::Error;
-const LATEST_VERSION: i32 = 5;
+const LATEST_VERSION: i32 = 27;
 
 // TODO add unique constraints where applicable
 fn latest_schema() -> String {
@@ -44,9 +72,15 @@ CREATE TABLE server_config (
 CREATE TABLE channels (
     uuid BigInt PRIMARY KEY NOT NULL,
     name text NOT NULL,
-    position Integer NOT NULL
+    position Integer NOT NULL,
+    category_uuid BigInt,
+    topic text,
+    nsfw Integer NOT NULL DEFAULT 0,
+    archived Integer NOT NULL DEFAULT 0,
+    slow_mode Integer NOT NULL DEFAULT 0,
+    announcement Integer NOT NULL DEFAULT 0
 );
-INSERT INTO channels VALUES ({}, "general", 0);
+INSERT INTO channels (uuid, name, position) VALUES ({}, "general", 0);
 CREATE TABLE messages (
     uuid BigInt PRIMARY KEY NOT NULL,
     content text NOT NULL,
@@ -55,6 +89,9 @@ CREATE TABLE messages (
     date integer NOT NULL,
     edited Integer not null default 0,
     reply BigInt,
+    edited_at integer,
+    deleted_at integer,
+    deleted_by BigInt,
     FOREIGN KEY (author_uuid) REFERENCES users(uuid),
     FOREIGN KEY (channel_uuid) REFERENCES channels(uuid)
 );
@@ -62,7 +99,9 @@ CREATE TABLE users (
     uuid BigInt PRIMARY KEY NOT NULL,
     name text NOT NULL,
     pfp text NOT NULL,
-    password text NOT NULL
+    password text NOT NULL,
+    status text NOT NULL DEFAULT 'online',
+    status_text text
 );
 CREATE TABLE groups (
     uuid BigInt PRIMARY KEY NOT NULL,
@@ -116,6 +155,204 @@ CREATE TABLE last_read_messages (
     PRIMARY KEY (user_uuid, channel_uuid)
 );
 
+CREATE TABLE dm_channels (
+    channel_uuid BigInt PRIMARY KEY NOT NULL,
+    user_a BigInt NOT NULL,
+    user_b BigInt NOT NULL,
+    FOREIGN KEY (channel_uuid) REFERENCES channels(uuid),
+    FOREIGN KEY (user_a) REFERENCES users(uuid),
+    FOREIGN KEY (user_b) REFERENCES users(uuid)
+);
+
+CREATE TABLE bans (
+    user_uuid BigInt PRIMARY KEY NOT NULL,
+    issuer_uuid BigInt NOT NULL,
+    reason text NOT NULL,
+    date integer NOT NULL,
+    expires integer,
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+    FOREIGN KEY (issuer_uuid) REFERENCES users(uuid)
+);
+
+CREATE TABLE ban_addrs (
+    user_uuid BigInt NOT NULL,
+    addr text NOT NULL,
+    PRIMARY KEY (user_uuid, addr),
+    FOREIGN KEY (user_uuid) REFERENCES bans(user_uuid)
+);
+
+CREATE TABLE reactions (
+    message_uuid BigInt NOT NULL,
+    user_uuid BigInt NOT NULL,
+    emoji NOT NULL,
+    FOREIGN KEY (message_uuid) REFERENCES messages(uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+    PRIMARY KEY (message_uuid, user_uuid, emoji)
+);
+
+CREATE TABLE blobs (
+    hash text PRIMARY KEY NOT NULL,
+    data blob NOT NULL
+);
+
+CREATE TABLE attachments (
+    uuid BigInt PRIMARY KEY NOT NULL,
+    hash text NOT NULL,
+    name text NOT NULL,
+    mime text NOT NULL,
+    size integer NOT NULL,
+    uploader_uuid BigInt NOT NULL,
+    message_uuid BigInt,
+    uploaded_at integer NOT NULL,
+    FOREIGN KEY (hash) REFERENCES blobs(hash),
+    FOREIGN KEY (uploader_uuid) REFERENCES users(uuid),
+    FOREIGN KEY (message_uuid) REFERENCES messages(uuid)
+);
+
+CREATE VIRTUAL TABLE messages_fts USING fts5(
+    content,
+    content='messages',
+    content_rowid='uuid'
+);
+CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+    INSERT INTO messages_fts(rowid, content) VALUES (new.uuid, new.content);
+END;
+CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.uuid, old.content);
+END;
+CREATE TRIGGER messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+    INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.uuid, old.content);
+    INSERT INTO messages_fts(rowid, content) VALUES (new.uuid, new.content);
+END;
+
+CREATE TABLE threads (
+    channel_uuid BigInt PRIMARY KEY NOT NULL,
+    parent_uuid BigInt NOT NULL,
+    message_uuid BigInt NOT NULL UNIQUE,
+    creator_uuid BigInt NOT NULL,
+    date integer NOT NULL,
+    FOREIGN KEY (channel_uuid) REFERENCES channels(uuid),
+    FOREIGN KEY (parent_uuid) REFERENCES channels(uuid),
+    FOREIGN KEY (message_uuid) REFERENCES messages(uuid),
+    FOREIGN KEY (creator_uuid) REFERENCES users(uuid)
+);
+
+CREATE TABLE thread_participants (
+    thread_uuid BigInt NOT NULL,
+    user_uuid BigInt NOT NULL,
+    FOREIGN KEY (thread_uuid) REFERENCES threads(channel_uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+    PRIMARY KEY (thread_uuid, user_uuid)
+);
+
+CREATE TABLE pins (
+    message_uuid BigInt PRIMARY KEY NOT NULL,
+    channel_uuid BigInt NOT NULL,
+    pinner_uuid BigInt NOT NULL,
+    date integer NOT NULL,
+    FOREIGN KEY (message_uuid) REFERENCES messages(uuid),
+    FOREIGN KEY (channel_uuid) REFERENCES channels(uuid),
+    FOREIGN KEY (pinner_uuid) REFERENCES users(uuid)
+);
+
+CREATE TABLE message_edits (
+    message_uuid BigInt NOT NULL,
+    content text NOT NULL,
+    date integer NOT NULL,
+    FOREIGN KEY (message_uuid) REFERENCES messages(uuid)
+);
+
+CREATE TABLE audit_log (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    date integer NOT NULL,
+    actor_uuid BigInt NOT NULL,
+    action text NOT NULL,
+    target BigInt,
+    before text,
+    after text,
+    FOREIGN KEY (actor_uuid) REFERENCES users(uuid)
+);
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log BEGIN
+    SELECT RAISE(ABORT, 'the audit log is append-only');
+END;
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log BEGIN
+    SELECT RAISE(ABORT, 'the audit log is append-only');
+END;
+
+CREATE TABLE mentions (
+    message_uuid BigInt NOT NULL,
+    user_uuid BigInt NOT NULL,
+    FOREIGN KEY (message_uuid) REFERENCES messages(uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+    PRIMARY KEY (message_uuid, user_uuid)
+);
+
+CREATE TABLE notify_settings (
+    user_uuid BigInt NOT NULL,
+    channel_uuid BigInt NOT NULL,
+    level text NOT NULL,
+    muted_until integer,
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+    FOREIGN KEY (channel_uuid) REFERENCES channels(uuid),
+    PRIMARY KEY (user_uuid, channel_uuid)
+);
+
+CREATE TABLE categories (
+    uuid BigInt PRIMARY KEY NOT NULL,
+    name text NOT NULL,
+    position Integer NOT NULL
+);
+
+CREATE TABLE channel_follows (
+    source_uuid BigInt NOT NULL,
+    target_uuid BigInt NOT NULL,
+    FOREIGN KEY (source_uuid) REFERENCES channels(uuid),
+    FOREIGN KEY (target_uuid) REFERENCES channels(uuid),
+    PRIMARY KEY (source_uuid, target_uuid)
+);
+
+CREATE TABLE published_messages (
+    copy_uuid BigInt PRIMARY KEY NOT NULL,
+    source_uuid BigInt NOT NULL,
+    FOREIGN KEY (copy_uuid) REFERENCES messages(uuid),
+    FOREIGN KEY (source_uuid) REFERENCES messages(uuid)
+);
+
+CREATE TABLE scheduled_messages (
+    uuid BigInt PRIMARY KEY NOT NULL,
+    content text NOT NULL,
+    author_uuid BigInt NOT NULL,
+    channel_uuid BigInt NOT NULL,
+    reply BigInt,
+    send_at integer NOT NULL,
+    FOREIGN KEY (author_uuid) REFERENCES users(uuid),
+    FOREIGN KEY (channel_uuid) REFERENCES channels(uuid)
+);
+
+CREATE TABLE sessions (
+    uuid BigInt PRIMARY KEY NOT NULL,
+    user_uuid BigInt NOT NULL,
+    token_hash text UNIQUE NOT NULL,
+    created integer NOT NULL,
+    expires integer NOT NULL,
+    last_used integer NOT NULL,
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+);
+
+CREATE TABLE totp (
+    user_uuid BigInt PRIMARY KEY NOT NULL,
+    secret text NOT NULL,
+    enabled Boolean NOT NULL,
+    last_step integer NOT NULL,
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+);
+
+CREATE TABLE recovery_codes (
+    user_uuid BigInt NOT NULL,
+    code_hash text NOT NULL,
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+);
+
 COMMIT;"#,
         LATEST_VERSION,
         gen_uuid()
@@ -248,6 +485,10 @@ const MIGRATIONS: &[Migration] = &[
                 manage_messages: Perm::Deny,
                 join_voice: Perm::Allow,
                 view_channel: Perm::Allow,
+                manage_emoji: Perm::Deny,
+                view_audit_log: Perm::Deny,
+                mention_everyone: Perm::Deny,
+                post_announcements: Perm::Deny,
             };
             let perm_bytes: Box<[u8]> = default_base_perms.into();
             sqlitedb.execute("INSERT INTO server_config VALUES (?1, ?2, ?3)", params![&CONF.name, pfp_bytes, perm_bytes.into_vec()])?;
@@ -270,1498 +511,4206 @@ const MIGRATIONS: &[Migration] = &[
             commit;
         "#,
         f: None,
-    }
-];
+    },
 
-impl Shared {
-    pub fn new(sqlitedb: Connection) -> Self {
-        Shared {
-            online: HashMap::new(),
-            conn: sqlitedb,
-            peers: Vec::new(),
-        }
-    }
+    Migration {
+        from: 5, to: 6,
+        sql: r#"
+            begin;
+            CREATE TABLE dm_channels (
+                channel_uuid BigInt PRIMARY KEY NOT NULL,
+                user_a BigInt NOT NULL,
+                user_b BigInt NOT NULL,
+                FOREIGN KEY (channel_uuid) REFERENCES channels(uuid),
+                FOREIGN KEY (user_a) REFERENCES users(uuid),
+                FOREIGN KEY (user_b) REFERENCES users(uuid)
+            );
+            commit;
+        "#,
+        f: None,
+    },
 
-    /// Initialise by applying any migrations that are applicable, based on the version.
-    pub fn init_db(&self) {
-        let version = self.get_db_version();
-        let version = match version {
-            Some(version) => version,
-            None => {
-                self.init_tables(&latest_schema());
-                LATEST_VERSION
-            }
-        };
+    Migration {
+        from: 6, to: 7,
+        sql: r#"
+            begin;
+                CREATE TABLE bans (
+                    user_uuid BigInt PRIMARY KEY NOT NULL,
+                    issuer_uuid BigInt NOT NULL,
+                    reason text NOT NULL,
+                    date integer NOT NULL,
+                    expires integer,
+                    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+                    FOREIGN KEY (issuer_uuid) REFERENCES users(uuid)
+                );
 
-        self.apply_migrations(MIGRATIONS, version, LATEST_VERSION);
-    }
+                CREATE TABLE ban_addrs (
+                    user_uuid BigInt NOT NULL,
+                    addr text NOT NULL,
+                    FOREIGN KEY (user_uuid) REFERENCES bans(user_uuid)
+                );
+            commit;
+        "#,
+        f: None,
+    },
 
-    /// Query the version from the database. If the database has no version table,
-    /// it will guess based on the existence of the channels table - if it exists,
-    /// version `0` (i.e. before the version table was added) is assumed.
-    /// If not, it will return `None`, representing the absence of any version information.
-    fn get_db_version(&self) -> Option<i32> {
-        let table_exists = self
-            .conn
-            .prepare("SELECT name FROM sqlite_master WHERE type=?1 AND name=?2")
-            .unwrap()
-            .query_row(["table", "version"], |_| Ok(()))
-            .optional()
-            .unwrap()
-            .is_some();
-        if table_exists {
-            let mut version_query = self
-                .conn
-                .prepare("SELECT * FROM version")
-                .expect("Database failure to prepare version query");
-            let version: Result<i32, DbError> = version_query.query_row([], |row| row.get(0));
+    Migration {
+        from: 7, to: 8,
+        sql: r#"
+            begin;
+                CREATE TABLE reactions (
+                    message_uuid BigInt NOT NULL,
+                    user_uuid BigInt NOT NULL,
+                    emoji NOT NULL,
+                    FOREIGN KEY (message_uuid) REFERENCES messages(uuid),
+                    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+                    PRIMARY KEY (message_uuid, user_uuid, emoji)
+                );
+            commit;
+        "#,
+        f: None,
+    },
 
-            drop(version_query);
+    Migration {
+        from: 8, to: 9,
+        sql: r#"
+            begin;
+                CREATE TABLE blobs (
+                    hash text PRIMARY KEY NOT NULL,
+                    data blob NOT NULL
+                );
 
-            Some(version.unwrap_or_else(|e| {
-                panic!("Unable to read version for some other reason: {:?}", e)
-            }))
-        } else {
-            // If we have a channels table, assume it's version 0, i.e. before the version table was added.
-            let channels_exists = self
-                .conn
-                .prepare("SELECT name FROM sqlite_master WHERE type=?1 AND name=?2")
-                .unwrap()
-                .query_row(["table", "channels"], |_| Ok(()))
-                .optional()
-                .unwrap()
-                .is_some();
-            if channels_exists {
-                Some(0)
-            } else {
-                None
-            }
-        }
-    }
+                CREATE TABLE attachments (
+                    uuid BigInt PRIMARY KEY NOT NULL,
+                    hash text NOT NULL,
+                    name text NOT NULL,
+                    mime text NOT NULL,
+                    size integer NOT NULL,
+                    uploader_uuid BigInt NOT NULL,
+                    message_uuid BigInt,
+                    FOREIGN KEY (hash) REFERENCES blobs(hash),
+                    FOREIGN KEY (uploader_uuid) REFERENCES users(uuid),
+                    FOREIGN KEY (message_uuid) REFERENCES messages(uuid)
+                );
+            commit;
+        "#,
+        f: None,
+    },
 
-    /// From an empty database, create all the tables and insert any initial values.
-    fn init_tables(&self, init_sql: &str) {
-        self.conn.execute_batch(init_sql).unwrap();
-        let pfp_bytes = include_bytes!("../icon.png").to_vec();
-        let default_base_perms = Permissions {
-            modify_channels: Perm::Deny,
-            modify_icon_name: Perm::Deny,
-            modify_groups: Perm::Deny,
-            modify_user_groups: Perm::Deny,
-            ban_users: Perm::Deny,
-            send_messages: Perm::Allow,
-            read_messages: Perm::Allow,
-            manage_messages: Perm::Deny,
-            join_voice: Perm::Allow,
-            view_channel: Perm::Allow,
-        };
-        let perm_bytes: Box<[u8]> = default_base_perms.into();
-        self.conn
-            .execute(
-                "INSERT INTO server_config VALUES (?1, ?2, ?3)",
-                params!["Aster Server", pfp_bytes, perm_bytes.into_vec()],
-            )
-            .unwrap();
-    }
+    Migration {
+        from: 9, to: 10,
+        sql: r#"
+            begin;
+                CREATE VIRTUAL TABLE messages_fts USING fts5(
+                    content,
+                    content='messages',
+                    content_rowid='uuid'
+                );
+                CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+                    INSERT INTO messages_fts(rowid, content) VALUES (new.uuid, new.content);
+                END;
+                CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+                    INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.uuid, old.content);
+                END;
+                CREATE TRIGGER messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+                    INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.uuid, old.content);
+                    INSERT INTO messages_fts(rowid, content) VALUES (new.uuid, new.content);
+                END;
+                INSERT INTO messages_fts(messages_fts) VALUES ('rebuild');
+            commit;
+        "#,
+        f: None,
+    },
 
-    pub fn get_name(&self) -> Result<String, DbError> {
-        self.conn
-            .prepare("SELECT name FROM server_config")?
-            .query_row([], |row| row.get(0))
-    }
-    pub fn get_icon(&self) -> Result<Vec<u8>, DbError> {
-        self.conn
-            .prepare("SELECT icon FROM server_config")?
-            .query_row([], |row| row.get(0))
-    }
-    pub fn get_base_perms(&self) -> Result<Permissions, DbError> {
-        let bytes: Vec<u8> = self
-            .conn
-            .prepare("SELECT base_perms FROM server_config")?
-            .query_row([], |row| row.get(0))?;
-        Ok(bytes.as_slice().into())
-    }
-    pub fn set_name(&self, name: &str) -> Result<(), DbError> {
-        self.conn
-            .prepare("UPDATE server_config SET name = ?1")?
-            .execute([name])?;
-        Ok(())
-    }
-    pub fn set_icon(&self, icon: &[u8]) -> Result<(), DbError> {
-        self.conn
-            .prepare("UPDATE server_config SET icon = ?1")?
-            .execute([icon])?;
-        Ok(())
-    }
-    pub fn set_base_perms(&self, perms: Permissions) -> Result<(), DbError> {
-        let bytes: Box<[u8]> = perms.into();
-        self.conn
-            .prepare("UPDATE server_config SET base_perms = ?1")?
-            .execute([bytes.into_vec()])?;
-        Ok(())
-    }
+    Migration {
+        from: 10, to: 11,
+        sql: r#"
+            begin;
+                CREATE TABLE threads (
+                    channel_uuid BigInt PRIMARY KEY NOT NULL,
+                    parent_uuid BigInt NOT NULL,
+                    message_uuid BigInt NOT NULL UNIQUE,
+                    creator_uuid BigInt NOT NULL,
+                    date integer NOT NULL,
+                    FOREIGN KEY (channel_uuid) REFERENCES channels(uuid),
+                    FOREIGN KEY (parent_uuid) REFERENCES channels(uuid),
+                    FOREIGN KEY (message_uuid) REFERENCES messages(uuid),
+                    FOREIGN KEY (creator_uuid) REFERENCES users(uuid)
+                );
 
-    fn apply_migrations(
-        &self,
-        migrations: &[Migration],
-        mut current_version: i32,
-        latest_version: i32,
-    ) {
-        while current_version < latest_version {
-            let applicable_migrations: Vec<_> = migrations
-                .iter()
-                .filter(|m| m.from == current_version)
-                .collect();
-            if applicable_migrations.is_empty() {
-                panic!(
-                    "Unable to find a migration from db version {} (latest version is {})",
-                    current_version, latest_version
+                CREATE TABLE thread_participants (
+                    thread_uuid BigInt NOT NULL,
+                    user_uuid BigInt NOT NULL,
+                    FOREIGN KEY (thread_uuid) REFERENCES threads(channel_uuid),
+                    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+                    PRIMARY KEY (thread_uuid, user_uuid)
                 );
-            }
+            commit;
+        "#,
+        f: None,
+    },
 
-            let m = applicable_migrations[0];
-            log::info!("Applying migration from db version {} to {}", m.from, m.to);
-            self.conn.execute_batch(m.sql).unwrap_or_else(|e| {
-                panic!(
-                    "Failed to apply migration from db version {} to {}: {:?}",
-                    m.from, m.to, e
-                )
-            });
+    Migration {
+        from: 11, to: 12,
+        sql: r#"
+            begin;
+                CREATE TABLE pins (
+                    message_uuid BigInt PRIMARY KEY NOT NULL,
+                    channel_uuid BigInt NOT NULL,
+                    pinner_uuid BigInt NOT NULL,
+                    date integer NOT NULL,
+                    FOREIGN KEY (message_uuid) REFERENCES messages(uuid),
+                    FOREIGN KEY (channel_uuid) REFERENCES channels(uuid),
+                    FOREIGN KEY (pinner_uuid) REFERENCES users(uuid)
+                );
+            commit;
+        "#,
+        f: None,
+    },
 
-            if let Some(f) = m.f {
-                f(&self.conn).unwrap_or_else(|e| {
-                    panic!(
-                        "Failed to run post-migration hook from db version {} to {}: {:?}",
-                        m.from, m.to, e
-                    )
-                });
-            }
+    Migration {
+        from: 12, to: 13,
+        sql: r#"
+            begin;
+                ALTER TABLE messages ADD COLUMN edited_at integer;
+                CREATE TABLE message_edits (
+                    message_uuid BigInt NOT NULL,
+                    content text NOT NULL,
+                    date integer NOT NULL,
+                    FOREIGN KEY (message_uuid) REFERENCES messages(uuid)
+                );
+            commit;
+        "#,
+        f: None,
+    },
 
-            current_version = m.to;
-            self.conn
-                .execute_batch(&format!("UPDATE version SET version={current_version};"))
-                .expect("Unable to update vesion in table");
+    Migration {
+        from: 13, to: 14,
+        sql: r#"
+            begin;
+                ALTER TABLE messages ADD COLUMN deleted_at integer;
+                ALTER TABLE messages ADD COLUMN deleted_by BigInt;
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 14, to: 15,
+        sql: r#"
+            begin;
+                CREATE TABLE audit_log (
+                    id integer PRIMARY KEY AUTOINCREMENT,
+                    date integer NOT NULL,
+                    actor_uuid BigInt NOT NULL,
+                    action text NOT NULL,
+                    target BigInt,
+                    before text,
+                    after text,
+                    FOREIGN KEY (actor_uuid) REFERENCES users(uuid)
+                );
+                CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log BEGIN
+                    SELECT RAISE(ABORT, 'the audit log is append-only');
+                END;
+                CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log BEGIN
+                    SELECT RAISE(ABORT, 'the audit log is append-only');
+                END;
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 15, to: 16,
+        sql: r#"
+            begin;
+                ALTER TABLE users ADD COLUMN status text NOT NULL DEFAULT 'online';
+                ALTER TABLE users ADD COLUMN status_text text;
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 16, to: 17,
+        sql: r#"
+            begin;
+                CREATE TABLE mentions (
+                    message_uuid BigInt NOT NULL,
+                    user_uuid BigInt NOT NULL,
+                    FOREIGN KEY (message_uuid) REFERENCES messages(uuid),
+                    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+                    PRIMARY KEY (message_uuid, user_uuid)
+                );
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 17, to: 18,
+        sql: r#"
+            begin;
+                CREATE TABLE notify_settings (
+                    user_uuid BigInt NOT NULL,
+                    channel_uuid BigInt NOT NULL,
+                    level text NOT NULL,
+                    muted_until integer,
+                    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+                    FOREIGN KEY (channel_uuid) REFERENCES channels(uuid),
+                    PRIMARY KEY (user_uuid, channel_uuid)
+                );
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 18, to: 19,
+        sql: r#"
+            begin;
+                CREATE TABLE categories (
+                    uuid BigInt PRIMARY KEY NOT NULL,
+                    name text NOT NULL,
+                    position Integer NOT NULL
+                );
+                ALTER TABLE channels ADD COLUMN category_uuid BigInt;
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 19, to: 20,
+        sql: r#"
+            begin;
+                ALTER TABLE channels ADD COLUMN topic text;
+                ALTER TABLE channels ADD COLUMN nsfw Integer NOT NULL DEFAULT 0;
+                ALTER TABLE channels ADD COLUMN archived Integer NOT NULL DEFAULT 0;
+                ALTER TABLE channels ADD COLUMN slow_mode Integer NOT NULL DEFAULT 0;
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 20, to: 21,
+        sql: r#"
+            begin;
+                ALTER TABLE channels ADD COLUMN announcement Integer NOT NULL DEFAULT 0;
+                CREATE TABLE channel_follows (
+                    source_uuid BigInt NOT NULL,
+                    target_uuid BigInt NOT NULL,
+                    FOREIGN KEY (source_uuid) REFERENCES channels(uuid),
+                    FOREIGN KEY (target_uuid) REFERENCES channels(uuid),
+                    PRIMARY KEY (source_uuid, target_uuid)
+                );
+                CREATE TABLE published_messages (
+                    copy_uuid BigInt PRIMARY KEY NOT NULL,
+                    source_uuid BigInt NOT NULL,
+                    FOREIGN KEY (copy_uuid) REFERENCES messages(uuid),
+                    FOREIGN KEY (source_uuid) REFERENCES messages(uuid)
+                );
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 21, to: 22,
+        sql: r#"
+            begin;
+                CREATE TABLE scheduled_messages (
+                    uuid BigInt PRIMARY KEY NOT NULL,
+                    content text NOT NULL,
+                    author_uuid BigInt NOT NULL,
+                    channel_uuid BigInt NOT NULL,
+                    reply BigInt,
+                    send_at integer NOT NULL,
+                    FOREIGN KEY (author_uuid) REFERENCES users(uuid),
+                    FOREIGN KEY (channel_uuid) REFERENCES channels(uuid)
+                );
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 22, to: 23,
+        sql: r#"
+            begin;
+                CREATE TABLE sessions (
+                    uuid BigInt PRIMARY KEY NOT NULL,
+                    user_uuid BigInt NOT NULL,
+                    token_hash text UNIQUE NOT NULL,
+                    created integer NOT NULL,
+                    expires integer NOT NULL,
+                    last_used integer NOT NULL,
+                    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+                );
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 23, to: 24,
+        sql: r#"
+            begin;
+                CREATE TABLE totp (
+                    user_uuid BigInt PRIMARY KEY NOT NULL,
+                    secret text NOT NULL,
+                    enabled Boolean NOT NULL,
+                    last_step integer NOT NULL,
+                    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+                );
+                CREATE TABLE recovery_codes (
+                    user_uuid BigInt NOT NULL,
+                    code_hash text NOT NULL,
+                    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+                );
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 24, to: 25,
+        sql: r#"
+            begin;
+                -- groups used to be inserted with their columns in the wrong order
+                UPDATE groups SET name = colour, colour = permissions, permissions = name
+                    WHERE typeof(name) = 'blob';
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 25, to: 26,
+        sql: r#"
+            begin;
+                ALTER TABLE attachments ADD COLUMN uploaded_at integer NOT NULL DEFAULT 0;
+                UPDATE attachments SET uploaded_at = strftime('%s', 'now');
+            commit;
+        "#,
+        f: None,
+    },
+
+    Migration {
+        from: 26, to: 27,
+        sql: r#"
+            begin;
+                ALTER TABLE ban_addrs RENAME TO ban_addrs_;
+                CREATE TABLE ban_addrs (
+                    user_uuid BigInt NOT NULL,
+                    addr text NOT NULL,
+                    PRIMARY KEY (user_uuid, addr),
+                    FOREIGN KEY (user_uuid) REFERENCES bans(user_uuid)
+                );
+                INSERT INTO ban_addrs SELECT DISTINCT user_uuid, addr FROM ban_addrs_;
+                DROP TABLE ban_addrs_;
+            commit;
+        "#,
+        f: None,
+    },
+];
+
+impl Shared {
+    pub fn new(sqlitedb: Connection) -> Self {
+        Shared {
+            online: HashMap::new(),
+            conn: sqlitedb,
+            peers: Vec::new(),
+            typing: HashMap::new(),
+            login_failures: HashMap::new(),
         }
     }
 
-    pub fn send_to_all(
-        &self,
-        message: serde_json::Value,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<serde_json::Value>> {
-        for (tx, _, _) in self.peers.iter() {
-            tx.send(message.clone())?;
-        }
-        Ok(())
+    /// Record that `user` is typing in `channel`, until the time `until`.
+    pub fn set_typing(&mut self, channel: Uuid, user: Uuid, until: i64) {
+        self.typing.insert((channel, user), until);
     }
 
-    pub fn inc_online(&mut self, user: i64) {
-        let orig_count = match self.online.get(&user) {
-            Some(count) => *count,
-            None => 0,
-        };
-        self.online.insert(user, orig_count + 1);
+    /// Stop `user` typing in `channel`. Returns whether they were typing.
+    pub fn stop_typing(&mut self, channel: Uuid, user: Uuid) -> bool {
+        self.typing.remove(&(channel, user)).is_some()
     }
 
-    // TEST
-    /// Get the message IDs of the last messages read by a given user
-    /// Returns `Err(_)` if the database operation failed.
-    /// Returns an empty map if the user's UUID does not exist, or there is no
-    /// read message information for that user.
-    pub fn get_last_read_messages(&self, user: Uuid) -> Result<HashMap<Uuid, (i64, u32)>, DbError> {
-        let mut map = HashMap::<Uuid, (i64, u32)>::new();
+    /// Stop every typing indicator that has expired by `now`, returning their (channel, user) pairs.
+    pub fn take_expired_typing(&mut self, now: i64) -> Vec<(Uuid, Uuid)> {
+        let expired: Vec<_> = self
+            .typing
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(k, _)| *k)
+            .collect();
+        for k in &expired {
+            self.typing.remove(k);
+        }
+        expired
+    }
 
-        // let mut any_unread_q = self.conn.prepare(
-        //     "SELECT date FROM messages WHERE channel_uuid = ?1 ORDER BY rowid DESC LIMIT 1",
-        // )?;
+    /// Stop `user` typing everywhere, returning the channels they were typing in.
+    pub fn take_typing_of(&mut self, user: Uuid) -> Vec<Uuid> {
+        let channels: Vec<_> = self
+            .typing
+            .keys()
+            .filter(|(_, u)| *u == user)
+            .map(|(c, _)| *c)
+            .collect();
+        for c in &channels {
+            self.typing.remove(&(*c, user));
+        }
+        channels
+    }
 
-        for (channel, date) in self
-            .conn
-            .prepare("SELECT channel_uuid, date FROM last_read_messages WHERE user_uuid = ?1")?
-            .query_map([user], |row| {
-                Ok((row.get::<usize, Uuid>(0)?, row.get::<usize, i64>(1)?))
-            })?
-            .collect::<Result<Vec<_>, _>>()?
-        {
-            // let last_message_date: i64 = any_unread_q.query_row([channel], |row| row.get(0))?;
-            // let any_unread = last_message_date > date;
-            map.insert(
-                channel,
-                (date, self.get_num_unread_messages(user, channel)?),
-            );
+    /// Check whether any of `keys` have failed to log in too recently to try again at time `now`.
+    pub fn login_blocked(&self, keys: &[LoginKey], now: i64) -> bool {
+        keys.iter().any(|k| {
+            self.login_failures
+                .get(k)
+                .is_some_and(|f| f.blocked_until > now)
+        })
+    }
+
+    /// Count a failed login against each of `keys` at time `now`. Each failure doubles how long they have to
+    /// wait before trying again, until there have been `login_attempts_before_lockout` failures, at which point
+    /// they are locked out for `login_lockout_duration`. Failures are forgotten once there have been none for
+    /// that long.
+    pub fn record_login_failure(&mut self, keys: &[LoginKey], now: i64) {
+        let window = CONF.login_lockout_duration;
+        self.login_failures.retain(|_, f| now - f.last < window);
+        for key in keys {
+            let f = self.login_failures.entry(*key).or_insert(LoginFailures {
+                count: 0,
+                last: now,
+                blocked_until: now,
+            });
+            f.count += 1;
+            f.last = now;
+            let delay = if f.count >= CONF.login_attempts_before_lockout {
+                window
+            } else {
+                CONF.login_backoff_base
+                    .saturating_mul(1 << (f.count - 1).min(32))
+                    .min(window)
+            };
+            f.blocked_until = now + delay;
         }
-        Ok(map)
     }
 
-    // TEST
-    /// Update the database with the last read message in a (possibly new) channel
-    /// Returns `Err(_)` if the database operation failed.
-    pub fn update_last_read_for_user_in_channel(
-        &self,
-        user: Uuid,
-        channel: Uuid,
-        message: Uuid,
-    ) -> Result<usize, DbError> {
-        let date: i64 = self
-            .conn
-            .prepare("select date from messages where uuid=?1")?
-            .query_row([message], |row| row.get(0))?;
-        self.conn
-            .prepare("INSERT or replace INTO last_read_messages VALUES (?1, ?2, ?3)")?
-            .execute([user, channel, date])
+    pub fn clear_login_failures(&mut self, key: LoginKey) {
+        self.login_failures.remove(&key);
     }
 
-    // TEST
-    /// Get the number of unread messages in a specific channel for a specific user.
-    /// If any of the following are true, return zero unread messages
-    ///   (1) The user does not exist
-    ///   (2) The channel does not exist
-    ///   (3) The user has no last read information for this channel (e.g. they have never read any messages)
-    /// Returns `Err(_)` if the database operation failed.
-    pub fn get_num_unread_messages(&self, user: Uuid, channel: Uuid) -> Result<u32, DbError> {
-        let Some(last_read_date) = self
-            .conn
-            .prepare(
-                "SELECT date FROM last_read_messages WHERE user_uuid = ?1 AND channel_uuid = ?2",
-            )?
-            .query_row([user, channel], |row| row.get(0))
-            .optional()?
-        else {
-            return Ok(0);
+    /// Initialise by applying any migrations that are applicable, based on the version.
+    pub fn init_db(&self) {
+        let version = self.get_db_version();
+        let version = match version {
+            Some(version) => version,
+            None => {
+                self.init_tables(&latest_schema());
+                LATEST_VERSION
+            }
         };
 
-        self.conn
-            .prepare("SELECT COUNT(1) FROM messages WHERE channel_uuid = ?1 AND date > ?2")?
-            .query_row([channel, last_read_date], |row| row.get(0))
+        self.apply_migrations(MIGRATIONS, version, LATEST_VERSION);
     }
 
-    pub fn get_user_by_name(&self, name: &str) -> Result<Option<User>, DbError> {
-        let mut smt = self
+    /// Query the version from the database. If the database has no version table,
+    /// it will guess based on the existence of the channels table - if it exists,
+    /// version `0` (i.e. before the version table was added) is assumed.
+    /// If not, it will return `None`, representing the absence of any version information.
+    fn get_db_version(&self) -> Option<i32> {
+        let table_exists = self
             .conn
-            .prepare("SELECT * FROM users WHERE name = ?1 LIMIT 1")?;
-
-        smt.query_row([name], |row| {
-            let uuid = row.get(0)?;
-            Ok(User {
-                uuid,
-                name: row.get(1)?,
-                pfp: row.get(2)?,
-                password: row.get(3)?,
-                groups: self.get_group_uuids_of(uuid)?,
-            })
-        })
-        .optional()
-    }
-
-    /// Get the [`Group`] from the database with the given id
-    /// Returns `Err(_)` if the database operation failed,
-    /// and `Ok(None)` if the given id does not exist.
-    pub fn get_group(&self, group_uuid: Uuid) -> Result<Option<Group>, DbError> {
-        self.conn
-            .prepare("SELECT * FROM groups WHERE uuid = ?1")?
-            .query_row([group_uuid], |row| {
-                Ok(Group {
-                    uuid: row.get(0)?,
-                    permissions: row.get::<usize, Vec<u8>>(1)?.as_slice().into(),
-                    name: row.get(2)?,
-                    colour: row.get(3)?,
-                    position: row.get(4)?,
-                })
-            })
+            .prepare("SELECT name FROM sqlite_master WHERE type=?1 AND name=?2")
+            .unwrap()
+            .query_row(["table", "version"], |_| Ok(()))
             .optional()
-    }
-
-    /// Get the [`Group`] from the database with the given id
-    /// This function assumes the group exists - it returns `Err(rusqlite::Error::QueryReturnedNoRows)`
-    /// if the group does not exist.
-    pub fn get_group_exists(&self, group_uuid: Uuid) -> Result<Group, DbError> {
-        match self.get_group(group_uuid) {
-            Ok(Some(group)) => Ok(group),
-            Ok(None) => Err(DbError::QueryReturnedNoRows),
-            Err(e) => Err(e),
-        }
-    }
+            .unwrap()
+            .is_some();
+        if table_exists {
+            let mut version_query = self
+                .conn
+                .prepare("SELECT * FROM version")
+                .expect("Database failure to prepare version query");
+            let version: Result<i32, DbError> = version_query.query_row([], |row| row.get(0));
 
-    // TEST
-    /// Get the [`User`] from the database with the given id
-    /// This function assumes the user exists - it returns `Err(rusqlite::Error::QueryReturnedNoRows)`
-    /// if the user does not exist.
-    pub fn get_user_exists(&self, user_uuid: Uuid) -> Result<User, DbError> {
-        match self.get_user(user_uuid) {
-            Ok(Some(user)) => Ok(user),
-            Ok(None) => Err(DbError::QueryReturnedNoRows),
-            Err(e) => Err(e),
-        }
-    }
+            drop(version_query);
 
-    // TEST
-    pub fn get_highest_group_pos_of(&self, user: Uuid) -> Result<usize, DbError> {
-        let us = self.get_user_exists(user)?;
-        let mut highest_role = usize::MAX;
-        for g in &us.groups {
-            let g_pos = self.get_group_exists(*g)?.position;
-            if g_pos < highest_role {
-                highest_role = g_pos;
+            Some(version.unwrap_or_else(|e| {
+                panic!("Unable to read version for some other reason: {:?}", e)
+            }))
+        } else {
+            // If we have a channels table, assume it's version 0, i.e. before the version table was added.
+            let channels_exists = self
+                .conn
+                .prepare("SELECT name FROM sqlite_master WHERE type=?1 AND name=?2")
+                .unwrap()
+                .query_row(["table", "channels"], |_| Ok(()))
+                .optional()
+                .unwrap()
+                .is_some();
+            if channels_exists {
+                Some(0)
+            } else {
+                None
             }
         }
-        Ok(highest_role)
     }
 
-    // TEST
-    pub fn delete_group(&self, uuid: Uuid) -> Result<(), DbError> {
+    /// From an empty database, create all the tables and insert any initial values.
+    fn init_tables(&self, init_sql: &str) {
+        self.conn.execute_batch(init_sql).unwrap();
+        let pfp_bytes = include_bytes!("../icon.png").to_vec();
+        let default_base_perms = Permissions {
+            modify_channels: Perm::Deny,
+            modify_icon_name: Perm::Deny,
+            modify_groups: Perm::Deny,
+            modify_user_groups: Perm::Deny,
+            ban_users: Perm::Deny,
+            send_messages: Perm::Allow,
+            read_messages: Perm::Allow,
+            manage_messages: Perm::Deny,
+            join_voice: Perm::Allow,
+            view_channel: Perm::Allow,
+            manage_emoji: Perm::Deny,
+            view_audit_log: Perm::Deny,
+            mention_everyone: Perm::Deny,
+            post_announcements: Perm::Deny,
+        };
+        let perm_bytes: Box<[u8]> = default_base_perms.into();
         self.conn
-            .prepare("delete from groups where uuid = ?1")?
-            .execute([uuid])?;
-        Ok(())
+            .execute(
+                "INSERT INTO server_config VALUES (?1, ?2, ?3)",
+                params!["Aster Server", pfp_bytes, perm_bytes.into_vec()],
+            )
+            .unwrap();
     }
 
-    pub fn get_group_uuids_of(&self, user_uuid: Uuid) -> Result<Vec<Uuid>, DbError> {
+    pub fn get_name(&self) -> Result<String, DbError> {
         self.conn
-            .prepare("SELECT group_uuid FROM user_groups WHERE user_uuid = ?1")?
-            .query_map([user_uuid], |row| row.get(0))?
-            .collect()
+            .prepare("SELECT name FROM server_config")?
+            .query_row([], |row| row.get(0))
     }
-
-    pub fn get_users(&self) -> Result<Vec<User>, DbError> {
+    pub fn get_icon(&self) -> Result<Vec<u8>, DbError> {
         self.conn
-            .prepare("SELECT * FROM USERS")?
-            .query_map([], |row| {
-                let uuid = row.get(0)?;
-                Ok(User {
-                    uuid,
-                    name: row.get(1)?,
-                    pfp: row.get(2)?,
-                    password: row.get(3)?,
-                    groups: self.get_group_uuids_of(uuid)?,
-                })
-            })?
-            .collect()
+            .prepare("SELECT icon FROM server_config")?
+            .query_row([], |row| row.get(0))
     }
-
-    fn get_channel_permissions(
-        &self,
-        channel: Uuid,
-    ) -> Result<HashMap<PermableEntity, Permissions>, DbError> {
-        let mut map = HashMap::new();
-        for (k, v) in self
+    pub fn get_base_perms(&self) -> Result<Permissions, DbError> {
+        let bytes: Vec<u8> = self
             .conn
-            .prepare("SELECT * FROM channel_group_permissions WHERE channel_uuid = ?1")?
-            .query_map([channel], |row| {
-                let group_uuid: Option<Uuid> = row.get(1)?;
-                let user_uuid: Option<Uuid> = row.get(2)?;
-                let perms: Vec<u8> = row.get(3)?;
-                let perms: Permissions = perms.as_slice().into();
-                // TODO doesn't handle both being Some
-                if let Some(group_uuid) = group_uuid {
-                    Ok((PermableEntity::Group(group_uuid), perms))
-                } else if let Some(user_uuid) = user_uuid {
-                    Ok((PermableEntity::User(user_uuid), perms))
-                } else {
-                    Err(DbError::FromSqlConversionFailure(
-                        1,
-                        rusqlite::types::Type::Null,
-                        Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Both group_uuid and user_uuid are null",
-                        )),
-                    )) // TODO this is awful
-                }
-            })?
-            .collect::<Result<Vec<(PermableEntity, Permissions)>, DbError>>()?
-        {
-            map.insert(k, v);
-        }
-        Ok(map)
-    }
-
-    pub fn get_channels(&self) -> Result<Vec<Channel>, DbError> {
-        self.conn
-            .prepare("SELECT * FROM CHANNELS")?
-            .query_map([], |row| {
-                let uuid = row.get(0)?;
-                Ok(Channel {
-                    uuid,
-                    name: row.get(1)?,
-                    position: row.get(2)?,
-                    permissions: self.get_channel_permissions(uuid)?,
-                })
-            })?
-            .collect()
+            .prepare("SELECT base_perms FROM server_config")?
+            .query_row([], |row| row.get(0))?;
+        Ok(bytes.as_slice().into())
     }
-
-    // TEST
-    pub fn get_groups(&self) -> Result<Vec<Group>, DbError> {
+    pub fn set_name(&self, name: &str) -> Result<(), DbError> {
         self.conn
-            .prepare("SELECT * FROM groups")?
-            .query_map([], |row| {
-                let perms: Vec<u8> = row.get(3)?;
-                let permissions: Permissions = perms.as_slice().into();
-                Ok(Group {
-                    uuid: row.get(0)?,
-                    name: row.get(1)?,
-                    colour: row.get(2)?,
-                    permissions,
-                    position: row.get(4)?,
-                })
-            })?
-            .collect()
-    }
-
-    pub fn channel_exists(&self, uuid: &Uuid) -> Result<bool, DbError> {
-        // TODO this might be slow
-        Ok(self
-            .get_channels()?
-            .iter()
-            .any(|channel| channel.uuid == *uuid))
+            .prepare("UPDATE server_config SET name = ?1")?
+            .execute([name])?;
+        Ok(())
     }
-
-    pub fn update_group(&self, g: &Group) -> Result<(), DbError> {
-        let perms: Box<[u8]> = g.permissions.clone().into();
+    pub fn set_icon(&self, icon: &[u8]) -> Result<(), DbError> {
         self.conn
-            .prepare("update groups set name = ?1, colour = ?2, permissions = ?3, position = ?4 where uuid = ?5")?
-            .execute(params![g.name, g.colour, perms.to_vec(), g.position, g.uuid])?;
+            .prepare("UPDATE server_config SET icon = ?1")?
+            .execute([icon])?;
         Ok(())
     }
-
-    pub fn update_channel(&self, c: &Channel) -> Result<(), DbError> {
+    pub fn set_base_perms(&self, perms: Permissions) -> Result<(), DbError> {
+        let bytes: Box<[u8]> = perms.into();
         self.conn
-            .prepare("update channels set name = ?2, position = ?3 where uuid = ?1")?
-            .execute(params![c.uuid, c.name, c.position])?;
-
-        // again, probably not a good idea
-        self.conn.execute(
-            "delete from channel_group_permissions where channel_uuid = ?1",
-            [c.uuid],
-        )?;
-        self.insert_channel_perms(c)
+            .prepare("UPDATE server_config SET base_perms = ?1")?
+            .execute([bytes.into_vec()])?;
+        Ok(())
     }
 
-    fn insert_channel_perms(&self, c: &Channel) -> Result<(), DbError> {
-        let mut insert_query = self
-            .conn
-            .prepare("insert into channel_group_permissions values (?1, ?2, ?3, ?4)")?;
-        for (k, v) in c.permissions.iter() {
-            let (group_uuid, user_uuid) = match k {
-                PermableEntity::User(user) => (None, Some(user)),
-                PermableEntity::Group(group) => (Some(group), None),
-            };
-            let perms: Box<[u8]> = v.into();
-            let perms = perms.into_vec();
-            insert_query.execute(params![c.uuid, group_uuid, user_uuid, perms])?;
+    fn apply_migrations(
+        &self,
+        migrations: &[Migration],
+        mut current_version: i32,
+        latest_version: i32,
+    ) {
+        while current_version < latest_version {
+            let applicable_migrations: Vec<_> = migrations
+                .iter()
+                .filter(|m| m.from == current_version)
+                .collect();
+            if applicable_migrations.is_empty() {
+                panic!(
+                    "Unable to find a migration from db version {} (latest version is {})",
+                    current_version, latest_version
+                );
+            }
+
+            let m = applicable_migrations[0];
+            log::info!("Applying migration from db version {} to {}", m.from, m.to);
+            self.conn.execute_batch(m.sql).unwrap_or_else(|e| {
+                panic!(
+                    "Failed to apply migration from db version {} to {}: {:?}",
+                    m.from, m.to, e
+                )
+            });
+
+            if let Some(f) = m.f {
+                f(&self.conn).unwrap_or_else(|e| {
+                    panic!(
+                        "Failed to run post-migration hook from db version {} to {}: {:?}",
+                        m.from, m.to, e
+                    )
+                });
+            }
+
+            current_version = m.to;
+            self.conn
+                .execute_batch(&format!("UPDATE version SET version={current_version};"))
+                .expect("Unable to update vesion in table");
+        }
+    }
+
+    pub fn send_to_all(
+        &self,
+        message: serde_json::Value,
+    ) -> Result<(), tokio::sync::mpsc::error::SendError<serde_json::Value>> {
+        for (tx, _, _, _) in self.peers.iter() {
+            tx.send(message.clone())?;
         }
         Ok(())
     }
 
-    pub fn message_exists(&self, uuid: &Uuid) -> Result<bool, DbError> {
-        self.conn
-            .prepare("select exists(select 1 from messages where uuid=?1)")?
-            .query_row([uuid], |row| Ok(row.get::<usize, i32>(0)? == 1))
+    /// Send a message to every connection that is logged in as `user`.
+    pub fn send_to_user(
+        &self,
+        user: Uuid,
+        message: serde_json::Value,
+    ) -> Result<(), tokio::sync::mpsc::error::SendError<serde_json::Value>> {
+        for (tx, _, uuid, _) in self.peers.iter() {
+            if *uuid == Some(user) {
+                tx.send(message.clone())?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn get_user(&self, user: i64) -> Result<Option<User>, DbError> {
-        self.conn
-            .prepare("select * from users where uuid = ?1")?
-            .query_row([user], |row| {
-                let uuid = row.get(0)?;
-                Ok(User {
-                    uuid,
-                    name: row.get(1)?,
-                    pfp: row.get(2)?,
-                    password: row.get(3)?,
-                    groups: self.get_group_uuids_of(uuid)?,
-                })
-            })
-            .optional()
+    /// Close every connection that is logged in as `user`. Anything already sent to
+    /// those connections is flushed to the client before it is disconnected.
+    pub fn disconnect_user(&self, user: Uuid) {
+        for (_, _, uuid, kick) in self.peers.iter() {
+            if *uuid == Some(user) {
+                kick.notify_one();
+            }
+        }
     }
 
-    pub fn add_to_history(&self, msg: &Message) -> Result<(), DbError> {
-        self.conn
-            .prepare("insert into messages values (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?
-            .execute(rusqlite::params![
-                msg.uuid,
-                &msg.content,
-                msg.author_uuid,
-                msg.channel_uuid,
-                msg.date,
-                msg.edited,
-                msg.reply,
-            ])?;
-        Ok(())
+    /// Send `message` to, then close, every connection logged in as `user` except the one from `except`.
+    pub fn disconnect_user_except(
+        &self,
+        user: Uuid,
+        except: SocketAddr,
+        message: serde_json::Value,
+    ) {
+        for (tx, addr, uuid, kick) in self.peers.iter() {
+            if *uuid == Some(user) && *addr != except {
+                // the send only fails if the connection is already closing, which is what we want anyway
+                let _ = tx.send(message.clone());
+                kick.notify_one();
+            }
+        }
     }
 
-    pub fn get_channel(&self, channel: &Uuid) -> Result<Option<Channel>, DbError> {
-        self.conn
-            .prepare("select * from channels where uuid = ?1")?
-            .query_row([channel], |row| {
-                let uuid = row.get(0)?;
-                Ok(Channel {
-                    uuid,
-                    name: row.get(1)?,
-                    position: row.get(2)?,
-                    permissions: self.get_channel_permissions(uuid)?,
-                })
-            })
-            .optional()
+    /// Get the addresses that `user` is currently connected from.
+    pub fn get_addrs_of(&self, user: Uuid) -> Vec<IpAddr> {
+        self.peers
+            .iter()
+            .filter(|(_, _, uuid, _)| *uuid == Some(user))
+            .map(|(_, addr, _, _)| addr.ip())
+            .collect()
     }
 
-    pub fn get_message(&self, message: Uuid) -> Result<Option<Message>, DbError> {
-        self.conn
-            .prepare("select * from messages where uuid = ?1 limit 1")?
-            .query_row([message], |row| {
-                Ok(Message {
-                    uuid: row.get(0)?,
-                    content: row.get(1)?,
-                    author_uuid: row.get(2)?,
-                    channel_uuid: row.get(3)?,
-                    date: row.get(4)?,
-                    edited: row.get(5)?,
-                    reply: row.get(6)?,
-                })
-            })
-            .optional()
+    pub fn inc_online(&mut self, user: i64) {
+        let orig_count = match self.online.get(&user) {
+            Some(count) => *count,
+            None => 0,
+        };
+        self.online.insert(user, orig_count + 1);
     }
 
-    pub fn get_sync_data(&self, uuid: &Uuid) -> Result<Option<SyncData>, DbError> {
+    /// Store a user's notification settings for a channel. Going back to the defaults removes them.
+    pub fn set_notify_settings(
+        &self,
+        user: Uuid,
+        settings: &NotifySettings,
+    ) -> Result<(), DbError> {
+        if settings.level == NotifyLevel::All && settings.muted_until.is_none() {
+            self.conn
+                .prepare("delete from notify_settings where user_uuid = ?1 and channel_uuid = ?2")?
+                .execute([user, settings.channel_uuid])?;
+        } else {
+            self.conn
+                .prepare("insert or replace into notify_settings values (?1, ?2, ?3, ?4)")?
+                .execute(params![
+                    user,
+                    settings.channel_uuid,
+                    settings.level,
+                    settings.muted_until
+                ])?;
+        }
+        Ok(())
+    }
+
+    /// Get all of a user's notification settings which differ from the defaults.
+    pub fn get_notify_settings(&self, user: Uuid) -> Result<Vec<NotifySettings>, DbError> {
         self.conn
-            .prepare("select * from sync_data where user_uuid = ?1 limit 1")?
-            .query_row([uuid], |row| {
-                Ok(SyncData {
-                    user_uuid: row.get(0)?,
-                    uname: row.get(1)?,
-                    pfp: row.get(2)?,
+            .prepare(
+                "select channel_uuid, level, muted_until from notify_settings where user_uuid = ?1",
+            )?
+            .query_map([user], |row| {
+                Ok(NotifySettings {
+                    channel_uuid: row.get(0)?,
+                    level: row.get(1)?,
+                    muted_until: row.get(2)?,
                 })
-            })
-            .optional()
+            })?
+            .collect()
     }
 
-    pub fn get_channel_by_name(&self, channel: &str) -> Result<Option<Channel>, DbError> {
-        self.conn
-            .prepare("select * from channels where name = ?1 order by position")?
-            .query_row([channel], |row| {
-                let uuid = row.get(0)?;
-                Ok(Channel {
-                    uuid,
-                    name: row.get(1)?,
-                    position: row.get(2)?,
-                    permissions: self.get_channel_permissions(uuid)?,
+    /// Get a user's notification settings for one channel, or the defaults if they haven't set any.
+    pub fn get_channel_notify_settings(
+        &self,
+        user: Uuid,
+        channel: Uuid,
+    ) -> Result<NotifySettings, DbError> {
+        let settings = self
+            .conn
+            .prepare(
+                "select level, muted_until from notify_settings
+                where user_uuid = ?1 and channel_uuid = ?2",
+            )?
+            .query_row([user, channel], |row| {
+                Ok(NotifySettings {
+                    channel_uuid: channel,
+                    level: row.get(0)?,
+                    muted_until: row.get(1)?,
                 })
             })
-            .optional()
+            .optional()?;
+        Ok(settings.unwrap_or(NotifySettings {
+            channel_uuid: channel,
+            level: NotifyLevel::All,
+            muted_until: None,
+        }))
     }
 
-    pub fn insert_channel(&self, channel: &Channel) -> Result<(), DbError> {
-        self.conn
-            .prepare("insert into channels values (?1, ?2, ?3)")?
-            .execute(params![channel.uuid, channel.name, channel.position])?;
-        self.insert_channel_perms(channel)
-    }
+    // TEST
+    /// Get the message IDs of the last messages read by a given user
+    /// Returns `Err(_)` if the database operation failed.
+    /// Returns an empty map if the user's UUID does not exist, or there is no
+    /// read message information for that user.
+    pub fn get_last_read_messages(&self, user: Uuid) -> Result<HashMap<Uuid, (i64, u32)>, DbError> {
+        let mut map = HashMap::<Uuid, (i64, u32)>::new();
 
-    pub fn insert_user_groups(&self, user: &User) -> Result<(), DbError> {
-        let mut insert_group = self
+        // let mut any_unread_q = self.conn.prepare(
+        //     "SELECT date FROM messages WHERE channel_uuid = ?1 ORDER BY rowid DESC LIMIT 1",
+        // )?;
+
+        for (channel, date) in self
             .conn
-            .prepare("insert into user_groups (user_uuid, group_uuid) values (?1, ?2)")?;
-        for g in &user.groups {
-            insert_group.execute([user.uuid, *g])?;
+            .prepare("SELECT channel_uuid, date FROM last_read_messages WHERE user_uuid = ?1")?
+            .query_map([user], |row| {
+                Ok((row.get::<usize, Uuid>(0)?, row.get::<usize, i64>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?
+        {
+            // let last_message_date: i64 = any_unread_q.query_row([channel], |row| row.get(0))?;
+            // let any_unread = last_message_date > date;
+            map.insert(
+                channel,
+                (date, self.get_num_unread_messages(user, channel)?),
+            );
         }
-        Ok(())
+        Ok(map)
     }
 
-    pub fn insert_user(&self, user: &User) -> Result<(), DbError> {
+    // TEST
+    /// Update the database with the last read message in a (possibly new) channel
+    /// Returns `Err(_)` if the database operation failed.
+    pub fn update_last_read_for_user_in_channel(
+        &self,
+        user: Uuid,
+        channel: Uuid,
+        message: Uuid,
+    ) -> Result<usize, DbError> {
+        let date: i64 = self
+            .conn
+            .prepare("select date from messages where uuid=?1")?
+            .query_row([message], |row| row.get(0))?;
         self.conn
-            .prepare("insert into users values (?1, ?2, ?3, ?4)")?
-            .execute(params![user.uuid, user.name, user.pfp, user.password])?;
-        self.insert_user_groups(user)
+            .prepare("INSERT or replace INTO last_read_messages VALUES (?1, ?2, ?3)")?
+            .execute([user, channel, date])
     }
 
-    pub fn insert_sync_data(&self, data: &SyncData) -> Result<usize, DbError> {
-        self.conn
-            .prepare("insert into sync_data values (?1, ?2, ?3)")?
-            .execute(params![data.user_uuid, data.uname, data.pfp])
-    }
+    // TEST
+    /// Get the number of unread messages in a specific channel for a specific user.
+    /// If any of the following are true, return zero unread messages
+    ///   (1) The user does not exist
+    ///   (2) The channel does not exist
+    ///   (3) The user has no last read information for this channel (e.g. they have never read any messages)
+    /// Returns `Err(_)` if the database operation failed.
+    pub fn get_num_unread_messages(&self, user: Uuid, channel: Uuid) -> Result<u32, DbError> {
+        let Some(last_read_date) = self
+            .conn
+            .prepare(
+                "SELECT date FROM last_read_messages WHERE user_uuid = ?1 AND channel_uuid = ?2",
+            )?
+            .query_row([user, channel], |row| row.get(0))
+            .optional()?
+        else {
+            return Ok(0);
+        };
 
-    pub fn insert_sync_server(&self, data: SyncServer) -> Result<usize, DbError> {
         self.conn
-            .prepare("insert into sync_servers values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?
-            .execute(params![
-                data.user_uuid,
-                data.uuid,
-                data.uname,
-                data.ip,
-                data.port,
-                data.pfp,
-                data.name,
-                data.idx,
-            ])
+            .prepare(
+                "SELECT COUNT(1) FROM messages WHERE channel_uuid = ?1 AND date > ?2 AND deleted_at IS NULL",
+            )?
+            .query_row([channel, last_read_date], |row| row.get(0))
     }
 
-    pub fn update_user(&self, user: &User) -> Result<(), DbError> {
-        self.conn
-            .prepare("update users set name = ?1, pfp = ?2, password = ?3 where uuid = ?4")?
-            .execute(params![user.name, user.pfp, user.password, user.uuid])?;
+    pub fn get_user_by_name(&self, name: &str) -> Result<Option<User>, DbError> {
+        let mut smt = self
+            .conn
+            .prepare("SELECT * FROM users WHERE name = ?1 LIMIT 1")?;
 
-        // clear and re-add groups
-        // TODO maybe not the most efficient
-        self.conn
-            .execute("delete from user_groups where user_uuid = ?1", [user.uuid])?;
-        self.insert_user_groups(user)
+        smt.query_row([name], |row| self.user_from_row(row))
+            .optional()
     }
 
-    pub fn update_sync_data(&self, data: SyncData) -> Result<usize, DbError> {
-        self.conn
-            .prepare("update sync_data set uname = ?1, pfp = ?2 where user_uuid = ?3")?
-            .execute(params![data.uname, data.pfp, data.user_uuid])
-    }
-
-    pub fn get_emoji(&self, uuid: Uuid) -> Result<Option<Emoji>, DbError> {
+    /// Get the [`Group`] from the database with the given id
+    /// Returns `Err(_)` if the database operation failed,
+    /// and `Ok(None)` if the given id does not exist.
+    pub fn get_group(&self, group_uuid: Uuid) -> Result<Option<Group>, DbError> {
         self.conn
-            .prepare("select * from emojis where uuid = ?1")?
-            .query_row([uuid], |row| {
-                Ok(Emoji {
+            .prepare("SELECT * FROM groups WHERE uuid = ?1")?
+            .query_row([group_uuid], |row| {
+                Ok(Group {
                     uuid: row.get(0)?,
                     name: row.get(1)?,
-                    data: row.get(2)?,
+                    colour: row.get(2)?,
+                    permissions: row.get::<usize, Vec<u8>>(3)?.as_slice().into(),
+                    position: row.get(4)?,
                 })
             })
             .optional()
     }
 
-    pub fn list_emoji(&self) -> Result<Vec<(String, Uuid)>, DbError> {
+    /// Get the [`Group`] from the database with the given id
+    /// This function assumes the group exists - it returns `Err(rusqlite::Error::QueryReturnedNoRows)`
+    /// if the group does not exist.
+    pub fn get_group_exists(&self, group_uuid: Uuid) -> Result<Group, DbError> {
+        match self.get_group(group_uuid) {
+            Ok(Some(group)) => Ok(group),
+            Ok(None) => Err(DbError::QueryReturnedNoRows),
+            Err(e) => Err(e),
+        }
+    }
+
+    // TEST
+    /// Get the [`User`] from the database with the given id
+    /// This function assumes the user exists - it returns `Err(rusqlite::Error::QueryReturnedNoRows)`
+    /// if the user does not exist.
+    pub fn get_user_exists(&self, user_uuid: Uuid) -> Result<User, DbError> {
+        match self.get_user(user_uuid) {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(DbError::QueryReturnedNoRows),
+            Err(e) => Err(e),
+        }
+    }
+
+    // TEST
+    pub fn get_highest_group_pos_of(&self, user: Uuid) -> Result<usize, DbError> {
+        let us = self.get_user_exists(user)?;
+        let mut highest_role = usize::MAX;
+        for g in &us.groups {
+            let g_pos = self.get_group_exists(*g)?.position;
+            if g_pos < highest_role {
+                highest_role = g_pos;
+            }
+        }
+        Ok(highest_role)
+    }
+
+    // TEST
+    pub fn delete_group(&self, uuid: Uuid) -> Result<(), DbError> {
         self.conn
-            .prepare("select name, uuid from emojis")?
-            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .prepare("delete from groups where uuid = ?1")?
+            .execute([uuid])?;
+        Ok(())
+    }
+
+    pub fn get_group_uuids_of(&self, user_uuid: Uuid) -> Result<Vec<Uuid>, DbError> {
+        self.conn
+            .prepare("SELECT group_uuid FROM user_groups WHERE user_uuid = ?1")?
+            .query_map([user_uuid], |row| row.get(0))?
             .collect()
     }
 
-    pub fn edit_message(&self, uuid: Uuid, new_content: &str) -> Result<usize, DbError> {
+    pub fn get_group_members(&self, group_uuid: Uuid) -> Result<Vec<Uuid>, DbError> {
         self.conn
-            .prepare("update messages set content = ?1, edited = true where uuid = ?2")?
-            .execute(params![new_content, uuid])
+            .prepare("SELECT user_uuid FROM user_groups WHERE group_uuid = ?1")?
+            .query_map([group_uuid], |row| row.get(0))?
+            .collect()
     }
 
-    pub fn delete_message(&self, uuid: Uuid) -> Result<usize, DbError> {
+    pub fn get_users(&self) -> Result<Vec<User>, DbError> {
         self.conn
-            .prepare("delete from messages where uuid = ?1")?
-            .execute([uuid])
+            .prepare("SELECT * FROM USERS")?
+            .query_map([], |row| self.user_from_row(row))?
+            .collect()
     }
 
-    pub fn delete_channel(&self, uuid: Uuid) -> Result<usize, DbError> {
+    fn get_channel_permissions(
+        &self,
+        channel: Uuid,
+    ) -> Result<HashMap<PermableEntity, Permissions>, DbError> {
+        let mut map = HashMap::new();
+        for (k, v) in self
+            .conn
+            .prepare("SELECT * FROM channel_group_permissions WHERE channel_uuid = ?1")?
+            .query_map([channel], |row| {
+                let group_uuid: Option<Uuid> = row.get(1)?;
+                let user_uuid: Option<Uuid> = row.get(2)?;
+                let perms: Vec<u8> = row.get(3)?;
+                let perms: Permissions = perms.as_slice().into();
+                // TODO doesn't handle both being Some
+                if let Some(group_uuid) = group_uuid {
+                    Ok((PermableEntity::Group(group_uuid), perms))
+                } else if let Some(user_uuid) = user_uuid {
+                    Ok((PermableEntity::User(user_uuid), perms))
+                } else {
+                    Err(DbError::FromSqlConversionFailure(
+                        1,
+                        rusqlite::types::Type::Null,
+                        Box::new(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "Both group_uuid and user_uuid are null",
+                        )),
+                    )) // TODO this is awful
+                }
+            })?
+            .collect::<Result<Vec<(PermableEntity, Permissions)>, DbError>>()?
+        {
+            map.insert(k, v);
+        }
+        Ok(map)
+    }
+
+    fn get_dm_members(&self, channel: Uuid) -> Result<Option<(Uuid, Uuid)>, DbError> {
         self.conn
-            .prepare("delete from channels where uuid = ?1")?
-            .execute([uuid])
+            .prepare("SELECT user_a, user_b FROM dm_channels WHERE channel_uuid = ?1")?
+            .query_row([channel], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()
     }
 
-    pub fn clear_sync_servers_of(&self, user: Uuid) -> Result<usize, DbError> {
+    fn channel_from_row(&self, row: &rusqlite::Row) -> Result<Channel, DbError> {
+        let uuid = row.get(0)?;
+        Ok(Channel {
+            uuid,
+            name: row.get(1)?,
+            position: row.get(2)?,
+            permissions: self.get_channel_permissions(uuid)?,
+            dm_members: self.get_dm_members(uuid)?,
+            category: row.get(3)?,
+            topic: row.get(4)?,
+            nsfw: row.get(5)?,
+            archived: row.get(6)?,
+            slow_mode: row.get(7)?,
+            announcement: row.get(8)?,
+        })
+    }
+
+    /// Get all of the server's channels. Direct message channels and threads are not included,
+    /// see [`Shared::get_dm_channels_of`] and [`Shared::get_threads_in`] for those.
+    pub fn get_channels(&self) -> Result<Vec<Channel>, DbError> {
         self.conn
-            .prepare("delete from sync_servers where user_uuid = ?1")?
-            .execute([user])
+            .prepare(
+                "SELECT * FROM channels WHERE uuid NOT IN (SELECT channel_uuid FROM dm_channels)
+                AND uuid NOT IN (SELECT channel_uuid FROM threads)",
+            )?
+            .query_map([], |row| self.channel_from_row(row))?
+            .collect()
     }
 
-    pub fn get_sync_servers(&self, user: Uuid) -> Result<Vec<SyncServer>, DbError> {
+    /// Get all the direct message channels that the given user is a member of.
+    pub fn get_dm_channels_of(&self, user: Uuid) -> Result<Vec<Channel>, DbError> {
         self.conn
-            .prepare("select * from sync_servers where user_uuid = ?1 order by idx")?
-            .query_map([user], |row| {
-                Ok(SyncServer {
-                    user_uuid: row.get(0)?,
-                    uuid: row.get(1)?,
-                    uname: row.get(2)?,
-                    ip: row.get(3)?,
-                    port: row.get(4)?,
-                    pfp: row.get(5)?,
-                    name: row.get(6)?,
-                    idx: row.get(7)?,
-                })
-            })?
+            .prepare(
+                "SELECT channels.* FROM channels INNER JOIN dm_channels
+                ON channels.uuid = dm_channels.channel_uuid
+                WHERE dm_channels.user_a = ?1 OR dm_channels.user_b = ?1",
+            )?
+            .query_map([user], |row| self.channel_from_row(row))?
             .collect()
     }
 
-    pub fn get_history(
-        &self,
-        channel: Uuid,
-        num: u32,
-        before_message: Option<Uuid>,
-    ) -> Result<Vec<Message>, DbError> {
-        let init_rowid = if let Some(uuid) = before_message {
-            self.conn
-                .prepare("select rowid from messages where uuid = ?1")?
-                .query_row([uuid], |row| row.get(0))?
-        } else {
-            i32::MAX
-        };
-        self.conn.prepare("select * from messages where channel_uuid = ?1 and rowid < ?2 order by rowid desc limit ?3")?
-            .query_map(params![channel, init_rowid, num], |row|
-                Ok(Message {
+    /// Get the direct message channel between two users, regardless of which one opened it.
+    /// Returns `Ok(None)` if the two users do not yet have a direct message channel.
+    pub fn get_dm_channel_between(&self, a: Uuid, b: Uuid) -> Result<Option<Channel>, DbError> {
+        self.conn
+            .prepare(
+                "SELECT channels.* FROM channels INNER JOIN dm_channels
+                ON channels.uuid = dm_channels.channel_uuid
+                WHERE (dm_channels.user_a = ?1 AND dm_channels.user_b = ?2)
+                   OR (dm_channels.user_a = ?2 AND dm_channels.user_b = ?1)",
+            )?
+            .query_row([a, b], |row| self.channel_from_row(row))
+            .optional()
+    }
+
+    // TEST
+    pub fn get_groups(&self) -> Result<Vec<Group>, DbError> {
+        self.conn
+            .prepare("SELECT * FROM groups")?
+            .query_map([], |row| {
+                let perms: Vec<u8> = row.get(3)?;
+                let permissions: Permissions = perms.as_slice().into();
+                Ok(Group {
                     uuid: row.get(0)?,
-                    content: row.get(1)?,
-                    author_uuid: row.get(2)?,
-                    channel_uuid: row.get(3)?,
-                    date: row.get(4)?,
-                    edited: row.get(5)?,
-                    reply: row.get(6)?,
+                    name: row.get(1)?,
+                    colour: row.get(2)?,
+                    permissions,
+                    position: row.get(4)?,
                 })
-            )?.collect()
+            })?
+            .collect()
     }
 
-    pub fn insert_group(&self, group: &Group) -> Result<(), DbError> {
+    pub fn channel_exists(&self, uuid: &Uuid) -> Result<bool, DbError> {
+        // TODO this might be slow
+        Ok(self
+            .get_channels()?
+            .iter()
+            .any(|channel| channel.uuid == *uuid))
+    }
+
+    pub fn update_group(&self, g: &Group) -> Result<(), DbError> {
+        let perms: Box<[u8]> = g.permissions.clone().into();
         self.conn
-            .prepare("INSERT INTO groups VALUES (?1, ?2, ?3, ?4, ?5)")?
-            .execute(params![
-                group.uuid,
-                Into::<Box<[u8]>>::into(&group.permissions).to_vec(),
-                group.name,
-                group.colour,
-                group.position,
-            ])?;
+            .prepare("update groups set name = ?1, colour = ?2, permissions = ?3, position = ?4 where uuid = ?5")?
+            .execute(params![g.name, g.colour, perms.to_vec(), g.position, g.uuid])?;
         Ok(())
     }
 
-    // TODO in another struct?
-    pub fn resolve_server_permissions(&self, user: &User) -> Result<Permissions, DbError> {
-        let base = self.get_base_perms()?;
-        let mut groups = user
-            .groups
-            .iter()
-            .map(|guuid| self.get_group_exists(*guuid))
-            .collect::<Result<Vec<_>, _>>()?;
-        groups.sort_unstable_by_key(|g| g.position);
-        let perms = groups
-            .iter()
-            .rev()
-            .fold(base, |acc, g| acc.apply_over(&g.permissions));
-        Ok(perms)
+    pub fn update_channel(&self, c: &Channel) -> Result<(), DbError> {
+        self.conn
+            .prepare(
+                "update channels set name = ?2, position = ?3, category_uuid = ?4, topic = ?5,
+                nsfw = ?6, archived = ?7, slow_mode = ?8, announcement = ?9 where uuid = ?1",
+            )?
+            .execute(params![
+                c.uuid,
+                c.name,
+                c.position,
+                c.category,
+                c.topic,
+                c.nsfw,
+                c.archived,
+                c.slow_mode,
+                c.announcement
+            ])?;
+
+        // again, probably not a good idea
+        self.conn.execute(
+            "delete from channel_group_permissions where channel_uuid = ?1",
+            [c.uuid],
+        )?;
+        self.insert_perms(c.uuid, &c.permissions)
     }
-    pub fn resolve_channel_permissions(
+
+    /// Store the permission overrides of a channel or category.
+    fn insert_perms(
         &self,
-        user: &User,
-        channel_in: &Channel,
-    ) -> Result<Permissions, DbError> {
-        let base = self.resolve_server_permissions(user)?;
-        // TODO as much as I like iterators, this might be better as a for loop.
-        let mut to_apply = channel_in
-            .permissions
-            .iter()
-            .filter_map(|(entity, perm)| match entity {
-                PermableEntity::User(uuid) if *uuid == user.uuid => Some(Ok((perm, 0))),
-                PermableEntity::Group(guuid) if user.groups.contains(guuid) => {
-                    Some(self.get_group_exists(*guuid).map(|g| (perm, g.position)))
-                }
-                _ => None,
-            })
-            .collect::<Result<Vec<_>, DbError>>()?;
-        to_apply.sort_unstable_by_key(|(_, pos)| *pos);
-        let perms = to_apply
-            .iter()
-            .rev()
-            .fold(base, |acc, (perm, _)| acc.apply_over(perm));
-        Ok(perms)
+        uuid: Uuid,
+        permissions: &HashMap<PermableEntity, Permissions>,
+    ) -> Result<(), DbError> {
+        let mut insert_query = self
+            .conn
+            .prepare("insert into channel_group_permissions values (?1, ?2, ?3, ?4)")?;
+        for (k, v) in permissions.iter() {
+            let (group_uuid, user_uuid) = match k {
+                PermableEntity::User(user) => (None, Some(user)),
+                PermableEntity::Group(group) => (Some(group), None),
+            };
+            let perms: Box<[u8]> = v.into();
+            let perms = perms.into_vec();
+            insert_query.execute(params![uuid, group_uuid, user_uuid, perms])?;
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
+    /// When a user last sent a message in a channel, including messages that have since been deleted.
+    pub fn last_message_date(&self, user: Uuid, channel: Uuid) -> Result<Option<i64>, DbError> {
+        self.conn
+            .prepare("select max(date) from messages where author_uuid = ?1 and channel_uuid = ?2")?
+            .query_row([user, channel], |row| row.get(0))
+    }
+
+    pub fn message_exists(&self, uuid: &Uuid) -> Result<bool, DbError> {
+        self.conn
+            .prepare("select exists(select 1 from messages where uuid=?1 and deleted_at is null)")?
+            .query_row([uuid], |row| Ok(row.get::<usize, i32>(0)? == 1))
+    }
+
+    pub fn get_user(&self, user: i64) -> Result<Option<User>, DbError> {
+        self.conn
+            .prepare("select * from users where uuid = ?1")?
+            .query_row([user], |row| self.user_from_row(row))
+            .optional()
+    }
+
+    fn user_from_row(&self, row: &rusqlite::Row) -> Result<User, DbError> {
+        let uuid = row.get(0)?;
+        Ok(User {
+            uuid,
+            name: row.get(1)?,
+            pfp: row.get(2)?,
+            password: row.get(3)?,
+            groups: self.get_group_uuids_of(uuid)?,
+            status: row.get(4)?,
+            status_text: row.get(5)?,
+        })
+    }
+
+    pub fn set_status(
+        &self,
+        user: Uuid,
+        status: UserStatus,
+        text: Option<&str>,
+    ) -> Result<usize, DbError> {
+        self.conn
+            .prepare("update users set status = ?1, status_text = ?2 where uuid = ?3")?
+            .execute(params![status, text, user])
+    }
+
+    /// Get a user's current status, or `None` if they don't exist.
+    pub fn get_presence(&self, user: Uuid) -> Result<Option<Presence>, DbError> {
+        self.conn
+            .prepare("select status, status_text from users where uuid = ?1")?
+            .query_row([user], |row| {
+                Ok(Presence {
+                    uuid: user,
+                    status: row.get(0)?,
+                    status_text: row.get(1)?,
+                })
+            })
+            .optional()
+    }
+
+    pub fn add_to_history(&self, msg: &Message) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert into messages values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)")?
+            .execute(rusqlite::params![
+                msg.uuid,
+                &msg.content,
+                msg.author_uuid,
+                msg.channel_uuid,
+                msg.date,
+                msg.edited,
+                msg.reply,
+                msg.edited_at,
+                msg.deleted_at,
+                msg.deleted_by,
+            ])?;
+        Ok(())
+    }
+
+    pub fn get_channel(&self, channel: &Uuid) -> Result<Option<Channel>, DbError> {
+        self.conn
+            .prepare("select * from channels where uuid = ?1")?
+            .query_row([channel], |row| self.channel_from_row(row))
+            .optional()
+    }
+
+    fn message_from_row(&self, row: &rusqlite::Row) -> Result<Message, DbError> {
+        let uuid = row.get(0)?;
+        Ok(Message {
+            uuid,
+            content: row.get(1)?,
+            author_uuid: row.get(2)?,
+            channel_uuid: row.get(3)?,
+            date: row.get(4)?,
+            edited: row.get(5)?,
+            reply: row.get(6)?,
+            edited_at: row.get(7)?,
+            deleted_at: row.get(8)?,
+            deleted_by: row.get(9)?,
+            reactions: self.get_reactions(uuid)?,
+            attachments: self.get_attachments_of(uuid)?,
+        })
+    }
+
+    /// Get a message, unless it doesn't exist or has been deleted.
+    pub fn get_message(&self, message: Uuid) -> Result<Option<Message>, DbError> {
+        self.conn
+            .prepare("select * from messages where uuid = ?1 and deleted_at is null limit 1")?
+            .query_row([message], |row| self.message_from_row(row))
+            .optional()
+    }
+
+    /// Get a message that has been deleted but not yet purged.
+    pub fn get_deleted_message(&self, message: Uuid) -> Result<Option<Message>, DbError> {
+        self.conn
+            .prepare("select * from messages where uuid = ?1 and deleted_at is not null limit 1")?
+            .query_row([message], |row| self.message_from_row(row))
+            .optional()
+    }
+
+    pub fn get_sync_data(&self, uuid: &Uuid) -> Result<Option<SyncData>, DbError> {
+        self.conn
+            .prepare("select * from sync_data where user_uuid = ?1 limit 1")?
+            .query_row([uuid], |row| {
+                Ok(SyncData {
+                    user_uuid: row.get(0)?,
+                    uname: row.get(1)?,
+                    pfp: row.get(2)?,
+                })
+            })
+            .optional()
+    }
+
+    pub fn get_channel_by_name(&self, channel: &str) -> Result<Option<Channel>, DbError> {
+        self.conn
+            .prepare("select * from channels where name = ?1 order by position")?
+            .query_row([channel], |row| self.channel_from_row(row))
+            .optional()
+    }
+
+    pub fn insert_channel(&self, channel: &Channel) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert into channels values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)")?
+            .execute(params![
+                channel.uuid,
+                channel.name,
+                channel.position,
+                channel.category,
+                channel.topic,
+                channel.nsfw,
+                channel.archived,
+                channel.slow_mode,
+                channel.announcement
+            ])?;
+        if let Some((a, b)) = channel.dm_members {
+            self.conn
+                .prepare("insert into dm_channels values (?1, ?2, ?3)")?
+                .execute([channel.uuid, a, b])?;
+        }
+        self.insert_perms(channel.uuid, &channel.permissions)
+    }
+
+    pub fn insert_category(&self, category: &Category) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert into categories values (?1, ?2, ?3)")?
+            .execute(params![category.uuid, category.name, category.position])?;
+        self.insert_perms(category.uuid, &category.permissions)
+    }
+
+    fn category_from_row(&self, row: &rusqlite::Row) -> Result<Category, DbError> {
+        let uuid = row.get(0)?;
+        Ok(Category {
+            uuid,
+            name: row.get(1)?,
+            position: row.get(2)?,
+            permissions: self.get_channel_permissions(uuid)?,
+        })
+    }
+
+    pub fn get_category(&self, uuid: Uuid) -> Result<Option<Category>, DbError> {
+        self.conn
+            .prepare("select * from categories where uuid = ?1")?
+            .query_row([uuid], |row| self.category_from_row(row))
+            .optional()
+    }
+
+    pub fn get_categories(&self) -> Result<Vec<Category>, DbError> {
+        self.conn
+            .prepare("select * from categories order by position")?
+            .query_map([], |row| self.category_from_row(row))?
+            .collect()
+    }
+
+    pub fn update_category(&self, c: &Category) -> Result<(), DbError> {
+        self.conn
+            .prepare("update categories set name = ?2, position = ?3 where uuid = ?1")?
+            .execute(params![c.uuid, c.name, c.position])?;
+        self.conn.execute(
+            "delete from channel_group_permissions where channel_uuid = ?1",
+            [c.uuid],
+        )?;
+        self.insert_perms(c.uuid, &c.permissions)
+    }
+
+    /// Delete a category. The channels in it are left uncategorised, but keep their positions, so
+    /// the caller should put them somewhere sensible first.
+    pub fn delete_category(&self, uuid: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("update channels set category_uuid = NULL where category_uuid = ?1")?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from channel_group_permissions where channel_uuid = ?1")?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from categories where uuid = ?1")?
+            .execute([uuid])
+    }
+
+    pub fn insert_user_groups(&self, user: &User) -> Result<(), DbError> {
+        let mut insert_group = self
+            .conn
+            .prepare("insert into user_groups (user_uuid, group_uuid) values (?1, ?2)")?;
+        for g in &user.groups {
+            insert_group.execute([user.uuid, *g])?;
+        }
+        Ok(())
+    }
+
+    pub fn insert_user(&self, user: &User) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert into users values (?1, ?2, ?3, ?4, ?5, ?6)")?
+            .execute(params![
+                user.uuid,
+                user.name,
+                user.pfp,
+                user.password,
+                user.status,
+                user.status_text
+            ])?;
+        self.insert_user_groups(user)
+    }
+
+    pub fn insert_sync_data(&self, data: &SyncData) -> Result<usize, DbError> {
+        self.conn
+            .prepare("insert into sync_data values (?1, ?2, ?3)")?
+            .execute(params![data.user_uuid, data.uname, data.pfp])
+    }
+
+    pub fn insert_sync_server(&self, data: SyncServer) -> Result<usize, DbError> {
+        self.conn
+            .prepare("insert into sync_servers values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?
+            .execute(params![
+                data.user_uuid,
+                data.uuid,
+                data.uname,
+                data.ip,
+                data.port,
+                data.pfp,
+                data.name,
+                data.idx,
+            ])
+    }
+
+    pub fn update_user(&self, user: &User) -> Result<(), DbError> {
+        self.conn
+            .prepare("update users set name = ?1, pfp = ?2, password = ?3 where uuid = ?4")?
+            .execute(params![user.name, user.pfp, user.password, user.uuid])?;
+
+        // clear and re-add groups
+        // TODO maybe not the most efficient
+        self.conn
+            .execute("delete from user_groups where user_uuid = ?1", [user.uuid])?;
+        self.insert_user_groups(user)
+    }
+
+    pub fn update_sync_data(&self, data: SyncData) -> Result<usize, DbError> {
+        self.conn
+            .prepare("update sync_data set uname = ?1, pfp = ?2 where user_uuid = ?3")?
+            .execute(params![data.uname, data.pfp, data.user_uuid])
+    }
+
+    pub fn get_emoji(&self, uuid: Uuid) -> Result<Option<Emoji>, DbError> {
+        self.conn
+            .prepare("select * from emojis where uuid = ?1")?
+            .query_row([uuid], |row| {
+                Ok(Emoji {
+                    uuid: row.get(0)?,
+                    name: row.get(1)?,
+                    data: row.get(2)?,
+                })
+            })
+            .optional()
+    }
+
+    pub fn list_emoji(&self) -> Result<Vec<(String, Uuid)>, DbError> {
+        self.conn
+            .prepare("select name, uuid from emojis")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    }
+
+    pub fn get_emoji_by_name(&self, name: &str) -> Result<Option<Emoji>, DbError> {
+        self.conn
+            .prepare("select * from emojis where name = ?1")?
+            .query_row([name], |row| {
+                Ok(Emoji {
+                    uuid: row.get(0)?,
+                    name: row.get(1)?,
+                    data: row.get(2)?,
+                })
+            })
+            .optional()
+    }
+
+    pub fn insert_emoji(&self, emoji: &Emoji) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert into emojis values (?1, ?2, ?3)")?
+            .execute(params![emoji.uuid, emoji.name, emoji.data])?;
+        Ok(())
+    }
+
+    pub fn rename_emoji(&self, uuid: Uuid, name: &str) -> Result<usize, DbError> {
+        self.conn
+            .prepare("update emojis set name = ?1 where uuid = ?2")?
+            .execute(params![name, uuid])
+    }
+
+    /// Delete a custom emoji, along with every reaction that used it.
+    pub fn delete_emoji(&self, uuid: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from reactions where emoji = ?1")?
+            .execute(params![ReactionEmoji::Custom(uuid)])?;
+        self.conn
+            .prepare("delete from emojis where uuid = ?1")?
+            .execute([uuid])
+    }
+
+    /// Ban a user, replacing any existing ban on them.
+    pub fn insert_ban(&self, ban: &Ban) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert or replace into bans values (?1, ?2, ?3, ?4, ?5)")?
+            .execute(params![
+                ban.user_uuid,
+                ban.issuer_uuid,
+                ban.reason,
+                ban.date,
+                ban.expires
+            ])?;
+        Ok(())
+    }
+
+    /// Record an address a banned user has connected from, so that new accounts
+    /// can't be registered from it while the ban lasts. Recording the same address twice does nothing.
+    pub fn insert_ban_addr(&self, user: Uuid, addr: &IpAddr) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert or ignore into ban_addrs values (?1, ?2)")?
+            .execute(params![user, addr.to_string()])?;
+        Ok(())
+    }
+
+    /// Get the ban on a user, if they are banned at time `now`.
+    /// Returns `Ok(None)` if the user has never been banned or their ban has expired.
+    pub fn get_ban(&self, user: Uuid, now: i64) -> Result<Option<Ban>, DbError> {
+        self.conn
+            .prepare(
+                "select * from bans where user_uuid = ?1 and (expires is null or expires > ?2)",
+            )?
+            .query_row([user, now], |row| {
+                Ok(Ban {
+                    user_uuid: row.get(0)?,
+                    issuer_uuid: row.get(1)?,
+                    reason: row.get(2)?,
+                    date: row.get(3)?,
+                    expires: row.get(4)?,
+                })
+            })
+            .optional()
+    }
+
+    /// Get all bans that are in effect at time `now`, oldest first.
+    pub fn get_bans(&self, now: i64) -> Result<Vec<Ban>, DbError> {
+        self.conn
+            .prepare("select * from bans where expires is null or expires > ?1 order by date")?
+            .query_map([now], |row| {
+                Ok(Ban {
+                    user_uuid: row.get(0)?,
+                    issuer_uuid: row.get(1)?,
+                    reason: row.get(2)?,
+                    date: row.get(3)?,
+                    expires: row.get(4)?,
+                })
+            })?
+            .collect()
+    }
+
+    /// Check whether `addr` belongs to any user that is banned at time `now`.
+    pub fn is_addr_banned(&self, addr: &IpAddr, now: i64) -> Result<bool, DbError> {
+        self.conn
+            .prepare(
+                "select exists(select 1 from ban_addrs inner join bans
+                on ban_addrs.user_uuid = bans.user_uuid
+                where ban_addrs.addr = ?1 and (bans.expires is null or bans.expires > ?2))",
+            )?
+            .query_row(params![addr.to_string(), now], |row| {
+                Ok(row.get::<usize, i32>(0)? == 1)
+            })
+    }
+
+    /// Lift the ban on a user, along with any addresses recorded for them.
+    /// Returns the number of bans removed, i.e. `Ok(0)` if the user wasn't banned.
+    pub fn delete_ban(&self, user: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from ban_addrs where user_uuid = ?1")?
+            .execute([user])?;
+        self.conn
+            .prepare("delete from bans where user_uuid = ?1")?
+            .execute([user])
+    }
+
+    pub fn insert_session(&self, session: &Session, token_hash: &str) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert into sessions values (?1, ?2, ?3, ?4, ?5, ?6)")?
+            .execute(params![
+                session.uuid,
+                session.user_uuid,
+                token_hash,
+                session.created,
+                session.expires,
+                session.last_used
+            ])?;
+        Ok(())
+    }
+
+    fn session_from_row(row: &rusqlite::Row) -> Result<Session, DbError> {
+        Ok(Session {
+            uuid: row.get(0)?,
+            user_uuid: row.get(1)?,
+            created: row.get(3)?,
+            expires: row.get(4)?,
+            last_used: row.get(5)?,
+        })
+    }
+
+    /// Get the session with the given token hash, if it hasn't expired at time `now`.
+    pub fn get_session_by_token(
+        &self,
+        token_hash: &str,
+        now: i64,
+    ) -> Result<Option<Session>, DbError> {
+        self.conn
+            .prepare("select * from sessions where token_hash = ?1 and expires > ?2")?
+            .query_row(params![token_hash, now], Self::session_from_row)
+            .optional()
+    }
+
+    /// Get a user's sessions that haven't expired at time `now`, most recently used first.
+    pub fn get_sessions_of(&self, user: Uuid, now: i64) -> Result<Vec<Session>, DbError> {
+        self.conn
+            .prepare(
+                "select * from sessions where user_uuid = ?1 and expires > ?2
+                order by last_used desc",
+            )?
+            .query_map([user, now], Self::session_from_row)?
+            .collect()
+    }
+
+    pub fn touch_session(&self, uuid: Uuid, now: i64) -> Result<usize, DbError> {
+        self.conn
+            .prepare("update sessions set last_used = ?2 where uuid = ?1")?
+            .execute([uuid, now])
+    }
+
+    pub fn delete_session(&self, uuid: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from sessions where uuid = ?1")?
+            .execute([uuid])
+    }
+
+    /// Revoke every session of a user. Returns the number of sessions removed.
+    pub fn delete_sessions_of(&self, user: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from sessions where user_uuid = ?1")?
+            .execute([user])
+    }
+
+    /// Remove every session which has expired at time `now`.
+    pub fn delete_expired_sessions(&self, now: i64) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from sessions where expires <= ?1")?
+            .execute([now])
+    }
+
+    /// Start setting up TOTP for a user, replacing any setup they haven't confirmed yet.
+    pub fn set_totp_secret(&self, user: Uuid, secret: &str) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert or replace into totp values (?1, ?2, false, 0)")?
+            .execute(params![user, secret])?;
+        Ok(())
+    }
+
+    pub fn get_totp(&self, user: Uuid) -> Result<Option<Totp>, DbError> {
+        self.conn
+            .prepare("select * from totp where user_uuid = ?1")?
+            .query_row([user], |row| {
+                Ok(Totp {
+                    user_uuid: row.get(0)?,
+                    secret: row.get(1)?,
+                    enabled: row.get(2)?,
+                    last_step: row.get(3)?,
+                })
+            })
+            .optional()
+    }
+
+    pub fn enable_totp(&self, user: Uuid, last_step: i64) -> Result<usize, DbError> {
+        self.conn
+            .prepare("update totp set enabled = true, last_step = ?2 where user_uuid = ?1")?
+            .execute([user, last_step])
+    }
+
+    pub fn set_totp_last_step(&self, user: Uuid, last_step: i64) -> Result<usize, DbError> {
+        self.conn
+            .prepare("update totp set last_step = ?2 where user_uuid = ?1")?
+            .execute([user, last_step])
+    }
+
+    /// Turn off TOTP for a user, along with their recovery codes.
+    pub fn delete_totp(&self, user: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from recovery_codes where user_uuid = ?1")?
+            .execute([user])?;
+        self.conn
+            .prepare("delete from totp where user_uuid = ?1")?
+            .execute([user])
+    }
+
+    /// Replace a user's recovery codes with new ones, given as password hashes.
+    pub fn set_recovery_codes(&self, user: Uuid, hashes: &[String]) -> Result<(), DbError> {
+        self.conn
+            .prepare("delete from recovery_codes where user_uuid = ?1")?
+            .execute([user])?;
+        let mut smt = self
+            .conn
+            .prepare("insert into recovery_codes values (?1, ?2)")?;
+        for hash in hashes {
+            smt.execute(params![user, hash])?;
+        }
+        Ok(())
+    }
+
+    pub fn get_recovery_codes(&self, user: Uuid) -> Result<Vec<String>, DbError> {
+        self.conn
+            .prepare("select code_hash from recovery_codes where user_uuid = ?1")?
+            .query_map([user], |row| row.get(0))?
+            .collect()
+    }
+
+    pub fn delete_recovery_code(&self, user: Uuid, hash: &str) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from recovery_codes where user_uuid = ?1 and code_hash = ?2")?
+            .execute(params![user, hash])
+    }
+
+    /// Change the content of a message, keeping the old content in its edit history.
+    pub fn edit_message(&self, uuid: Uuid, new_content: &str, date: i64) -> Result<usize, DbError> {
+        // the previous revision was written when the message was last edited, or sent if it never has been
+        self.conn
+            .prepare(
+                "insert into message_edits
+                select uuid, content, coalesce(edited_at, date) from messages where uuid = ?1",
+            )?
+            .execute([uuid])?;
+        self.conn
+            .prepare(
+                "update messages set content = ?1, edited = true, edited_at = ?2 where uuid = ?3",
+            )?
+            .execute(params![new_content, date, uuid])
+    }
+
+    /// Get every revision of a message, oldest first, ending with its current content.
+    /// Returns an empty list if the message does not exist.
+    pub fn get_edit_history(&self, message: Uuid) -> Result<Vec<MessageRevision>, DbError> {
+        self.conn
+            .prepare(
+                "select content, date from (
+                    select content, date, rowid as idx from message_edits where message_uuid = ?1
+                    union all
+                    select content, coalesce(edited_at, date), null from messages where uuid = ?1
+                ) order by idx is null, idx",
+            )?
+            .query_map([message], |row| {
+                Ok(MessageRevision {
+                    content: row.get(0)?,
+                    date: row.get(1)?,
+                })
+            })?
+            .collect()
+    }
+
+    /// Mark a message as deleted. Its content is kept until it is purged, so that it can still be
+    /// reviewed and restored by moderators. Returns 0 if the message was already deleted.
+    pub fn soft_delete_message(&self, uuid: Uuid, by: Uuid, date: i64) -> Result<usize, DbError> {
+        self.conn
+            .prepare(
+                "update messages set deleted_at = ?1, deleted_by = ?2
+                where uuid = ?3 and deleted_at is null",
+            )?
+            .execute([date, by, uuid])
+    }
+
+    /// Undo [`Shared::soft_delete_message`]. Returns 0 if the message was not deleted.
+    pub fn restore_message(&self, uuid: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare(
+                "update messages set deleted_at = null, deleted_by = null
+                where uuid = ?1 and deleted_at is not null",
+            )?
+            .execute([uuid])
+    }
+
+    /// Permanently delete all the messages that were deleted at or before `cutoff`.
+    /// Returns how many were purged.
+    pub fn purge_deleted_messages(&self, cutoff: i64) -> Result<usize, DbError> {
+        let to_purge = self
+            .conn
+            .prepare("select uuid from messages where deleted_at <= ?1")?
+            .query_map([cutoff], |row| row.get(0))?
+            .collect::<Result<Vec<Uuid>, _>>()?;
+        for uuid in &to_purge {
+            self.delete_message(*uuid)?;
+        }
+        Ok(to_purge.len())
+    }
+
+    /// Permanently delete a message, along with everything attached to it.
+    pub fn delete_message(&self, uuid: Uuid) -> Result<usize, DbError> {
+        self.unpin_message(uuid)?;
+        self.conn
+            .prepare("delete from mentions where message_uuid = ?1")?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from published_messages where copy_uuid = ?1 or source_uuid = ?1")?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from message_edits where message_uuid = ?1")?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from reactions where message_uuid = ?1")?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from attachments where message_uuid = ?1")?
+            .execute([uuid])?;
+        self.delete_unused_blobs()?;
+        self.conn
+            .prepare("delete from messages where uuid = ?1")?
+            .execute([uuid])
+    }
+
+    /// Pin a message in its channel. Returns the number of rows changed, which is 0 if it was already pinned.
+    pub fn pin_message(
+        &self,
+        message: &Message,
+        pinner: Uuid,
+        date: i64,
+    ) -> Result<usize, DbError> {
+        self.conn
+            .prepare("insert or ignore into pins values (?1, ?2, ?3, ?4)")?
+            .execute(params![message.uuid, message.channel_uuid, pinner, date])
+    }
+
+    pub fn unpin_message(&self, message: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from pins where message_uuid = ?1")?
+            .execute([message])
+    }
+
+    /// Get the pinned messages in a channel, most recently pinned first.
+    pub fn get_pins(&self, channel: Uuid) -> Result<Vec<Message>, DbError> {
+        self.conn
+            .prepare(
+                "select messages.* from pins inner join messages on messages.uuid = pins.message_uuid
+                where pins.channel_uuid = ?1 and messages.deleted_at is null
+                order by pins.date desc, pins.rowid desc",
+            )?
+            .query_map([channel], |row| self.message_from_row(row))?
+            .collect()
+    }
+
+    /// Make `target` follow the announcement channel `source`. Returns the number of rows changed, which is 0
+    /// if it was already following.
+    pub fn follow_channel(&self, source: Uuid, target: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("insert or ignore into channel_follows values (?1, ?2)")?
+            .execute([source, target])
+    }
+
+    pub fn unfollow_channel(&self, source: Uuid, target: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from channel_follows where source_uuid = ?1 and target_uuid = ?2")?
+            .execute([source, target])
+    }
+
+    /// Get the channels following an announcement channel.
+    pub fn get_followers(&self, source: Uuid) -> Result<Vec<Uuid>, DbError> {
+        self.conn
+            .prepare("select target_uuid from channel_follows where source_uuid = ?1")?
+            .query_map([source], |row| row.get(0))?
+            .collect()
+    }
+
+    /// Record that `copy` was made by publishing `source` to a following channel.
+    pub fn insert_published(&self, copy: Uuid, source: Uuid) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert into published_messages values (?1, ?2)")?
+            .execute([copy, source])?;
+        Ok(())
+    }
+
+    pub fn is_published(&self, message: Uuid) -> Result<bool, DbError> {
+        self.conn
+            .prepare("select exists(select 1 from published_messages where source_uuid = ?1)")?
+            .query_row([message], |row| row.get(0))
+    }
+
+    pub fn insert_scheduled(&self, msg: &ScheduledMessage) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert into scheduled_messages values (?1, ?2, ?3, ?4, ?5, ?6)")?
+            .execute(params![
+                msg.uuid,
+                msg.content,
+                msg.author_uuid,
+                msg.channel_uuid,
+                msg.reply,
+                msg.send_at
+            ])?;
+        Ok(())
+    }
+
+    fn scheduled_from_row(row: &rusqlite::Row) -> Result<ScheduledMessage, DbError> {
+        Ok(ScheduledMessage {
+            uuid: row.get(0)?,
+            content: row.get(1)?,
+            author_uuid: row.get(2)?,
+            channel_uuid: row.get(3)?,
+            reply: row.get(4)?,
+            send_at: row.get(5)?,
+        })
+    }
+
+    pub fn get_scheduled(&self, uuid: Uuid) -> Result<Option<ScheduledMessage>, DbError> {
+        self.conn
+            .prepare("select * from scheduled_messages where uuid = ?1")?
+            .query_row([uuid], Self::scheduled_from_row)
+            .optional()
+    }
+
+    /// Get all of a user's scheduled messages, soonest first.
+    pub fn get_scheduled_of(&self, user: Uuid) -> Result<Vec<ScheduledMessage>, DbError> {
+        self.conn
+            .prepare("select * from scheduled_messages where author_uuid = ?1 order by send_at")?
+            .query_map([user], Self::scheduled_from_row)?
+            .collect()
+    }
+
+    pub fn delete_scheduled(&self, uuid: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from scheduled_messages where uuid = ?1")?
+            .execute([uuid])
+    }
+
+    /// Get every scheduled message which is due to be sent at time `now`, oldest first.
+    pub fn get_due_scheduled(&self, now: i64) -> Result<Vec<ScheduledMessage>, DbError> {
+        self.conn
+            .prepare("select * from scheduled_messages where send_at <= ?1 order by send_at")?
+            .query_map([now], Self::scheduled_from_row)?
+            .collect()
+    }
+
+    /// Record that a message mentions some users.
+    pub fn insert_mentions(&self, message: Uuid, users: &[Uuid]) -> Result<(), DbError> {
+        let mut smt = self
+            .conn
+            .prepare("insert or ignore into mentions values (?1, ?2)")?;
+        for user in users {
+            smt.execute([message, *user])?;
+        }
+        Ok(())
+    }
+
+    /// Get the messages which mention a user, newest first. Like `get_history`, `before_message`
+    /// can be given to get the page of mentions before a particular message.
+    pub fn get_mentions(
+        &self,
+        user: Uuid,
+        num: u32,
+        before_message: Option<Uuid>,
+    ) -> Result<Vec<Message>, DbError> {
+        let init_rowid = if let Some(uuid) = before_message {
+            self.conn
+                .prepare("select rowid from messages where uuid = ?1")?
+                .query_row([uuid], |row| row.get(0))?
+        } else {
+            i64::MAX
+        };
+        self.conn
+            .prepare(
+                "select messages.* from mentions inner join messages on messages.uuid = mentions.message_uuid
+                where mentions.user_uuid = ?1 and messages.rowid < ?2 and messages.deleted_at is null
+                order by messages.rowid desc limit ?3",
+            )?
+            .query_map(params![user, init_rowid, num], |row| self.message_from_row(row))?
+            .collect()
+    }
+
+    pub fn count_pins(&self, channel: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("select count(1) from pins where channel_uuid = ?1")?
+            .query_row([channel], |row| row.get(0))
+    }
+
+    /// Store some data under its hash. Storing the same data twice is a no-op.
+    pub fn insert_blob(&self, hash: &str, data: &[u8]) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert or ignore into blobs values (?1, ?2)")?
+            .execute(params![hash, data])?;
+        Ok(())
+    }
+
+    /// Get up to `len` bytes of the data stored under `hash`, starting from byte `offset`.
+    pub fn get_blob_range(
+        &self,
+        hash: &str,
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        // substr is 1-indexed
+        self.conn
+            .prepare("select substr(data, ?2, ?3) from blobs where hash = ?1")?
+            .query_row(params![hash, offset + 1, len], |row| row.get(0))
+            .optional()
+    }
+
+    /// Remove any stored data that is no longer referenced by an attachment.
+    pub fn delete_unused_blobs(&self) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from blobs where hash not in (select hash from attachments)")?
+            .execute([])
+    }
+
+    fn attachment_from_row(row: &rusqlite::Row) -> Result<Attachment, DbError> {
+        Ok(Attachment {
+            uuid: row.get(0)?,
+            hash: row.get(1)?,
+            name: row.get(2)?,
+            mime: row.get(3)?,
+            size: row.get(4)?,
+            uploader_uuid: row.get(5)?,
+            message_uuid: row.get(6)?,
+            uploaded_at: row.get(7)?,
+        })
+    }
+
+    pub fn insert_attachment(&self, a: &Attachment) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert into attachments values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?
+            .execute(params![
+                a.uuid,
+                a.hash,
+                a.name,
+                a.mime,
+                a.size,
+                a.uploader_uuid,
+                a.message_uuid,
+                a.uploaded_at
+            ])?;
+        Ok(())
+    }
+
+    /// The total size of the attachments a user has uploaded but not sent yet, in bytes.
+    pub fn get_unattached_size(&self, user: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare(
+                "select coalesce(sum(size), 0) from attachments
+                where uploader_uuid = ?1 and message_uuid is null",
+            )?
+            .query_row([user], |row| row.get(0))
+    }
+
+    /// Delete attachments which were uploaded before `cutoff` but never sent, along with their data if
+    /// nothing else uses it. Returns the number of attachments deleted.
+    pub fn purge_unattached(&self, cutoff: i64) -> Result<usize, DbError> {
+        let n = self
+            .conn
+            .prepare("delete from attachments where message_uuid is null and uploaded_at < ?1")?
+            .execute([cutoff])?;
+        self.delete_unused_blobs()?;
+        Ok(n)
+    }
+
+    pub fn get_attachment(&self, uuid: Uuid) -> Result<Option<Attachment>, DbError> {
+        self.conn
+            .prepare("select * from attachments where uuid = ?1")?
+            .query_row([uuid], Self::attachment_from_row)
+            .optional()
+    }
+
+    pub fn get_attachments_of(&self, message: Uuid) -> Result<Vec<Attachment>, DbError> {
+        self.conn
+            .prepare("select * from attachments where message_uuid = ?1 order by rowid")?
+            .query_map([message], Self::attachment_from_row)?
+            .collect()
+    }
+
+    /// Give message `to` its own copy of each attachment on message `from`, sharing the same data.
+    /// Returns the new attachments.
+    pub fn copy_attachments(&self, from: Uuid, to: Uuid) -> Result<Vec<Attachment>, DbError> {
+        let copies = self
+            .get_attachments_of(from)?
+            .into_iter()
+            .map(|a| Attachment {
+                uuid: gen_uuid(),
+                message_uuid: Some(to),
+                ..a
+            })
+            .collect::<Vec<_>>();
+        for a in &copies {
+            self.insert_attachment(a)?;
+        }
+        Ok(copies)
+    }
+
+    /// Mark an uploaded attachment as belonging to a message.
+    pub fn attach_to_message(&self, attachment: Uuid, message: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("update attachments set message_uuid = ?1 where uuid = ?2")?
+            .execute([message, attachment])
+    }
+
+    fn thread_from_row(&self, row: &rusqlite::Row) -> Result<Thread, DbError> {
+        let uuid = row.get(0)?;
+        Ok(Thread {
+            uuid,
+            name: row.get(1)?,
+            channel_uuid: row.get(2)?,
+            message_uuid: row.get(3)?,
+            creator_uuid: row.get(4)?,
+            date: row.get(5)?,
+            participants: self.get_thread_participants(uuid)?,
+        })
+    }
+
+    /// Create a thread, along with the channel its messages are sent to. The creator is its first participant.
+    pub fn insert_thread(&self, thread: &Thread) -> Result<(), DbError> {
+        self.conn
+            .prepare("insert into channels (uuid, name, position) values (?1, ?2, 0)")?
+            .execute(params![thread.uuid, thread.name])?;
+        self.conn
+            .prepare("insert into threads values (?1, ?2, ?3, ?4, ?5)")?
+            .execute(params![
+                thread.uuid,
+                thread.channel_uuid,
+                thread.message_uuid,
+                thread.creator_uuid,
+                thread.date,
+            ])?;
+        for user in &thread.participants {
+            self.add_thread_participant(thread.uuid, *user)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_thread(&self, thread: Uuid) -> Result<Option<Thread>, DbError> {
+        self.conn
+            .prepare(
+                "select threads.channel_uuid, channels.name, parent_uuid, message_uuid, creator_uuid, date
+                from threads inner join channels on channels.uuid = threads.channel_uuid
+                where threads.channel_uuid = ?1",
+            )?
+            .query_row([thread], |row| self.thread_from_row(row))
+            .optional()
+    }
+
+    /// Get the thread started from a message, if there is one.
+    pub fn get_thread_of_message(&self, message: Uuid) -> Result<Option<Thread>, DbError> {
+        self.conn
+            .prepare(
+                "select threads.channel_uuid, channels.name, parent_uuid, message_uuid, creator_uuid, date
+                from threads inner join channels on channels.uuid = threads.channel_uuid
+                where message_uuid = ?1",
+            )?
+            .query_row([message], |row| self.thread_from_row(row))
+            .optional()
+    }
+
+    /// Get all the threads started in a channel, oldest first.
+    pub fn get_threads_in(&self, channel: Uuid) -> Result<Vec<Thread>, DbError> {
+        self.conn
+            .prepare(
+                "select threads.channel_uuid, channels.name, parent_uuid, message_uuid, creator_uuid, date
+                from threads inner join channels on channels.uuid = threads.channel_uuid
+                where parent_uuid = ?1 order by threads.rowid",
+            )?
+            .query_map([channel], |row| self.thread_from_row(row))?
+            .collect()
+    }
+
+    /// Get the channel a thread was started in, or `None` if `channel` is not a thread.
+    pub fn get_thread_parent(&self, channel: Uuid) -> Result<Option<Uuid>, DbError> {
+        self.conn
+            .prepare("select parent_uuid from threads where channel_uuid = ?1")?
+            .query_row([channel], |row| row.get(0))
+            .optional()
+    }
+
+    pub fn get_thread_participants(&self, thread: Uuid) -> Result<Vec<Uuid>, DbError> {
+        self.conn
+            .prepare(
+                "select user_uuid from thread_participants where thread_uuid = ?1 order by rowid",
+            )?
+            .query_map([thread], |row| row.get(0))?
+            .collect()
+    }
+
+    /// Record that a user has taken part in a thread. Returns the number of rows changed,
+    /// which is 0 if they were already a participant.
+    pub fn add_thread_participant(&self, thread: Uuid, user: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("insert or ignore into thread_participants values (?1, ?2)")?
+            .execute([thread, user])
+    }
+
+    /// Add an entry to the audit log, returning its id. The id of `entry` is ignored.
+    pub fn insert_audit_log(&self, entry: &AuditLogEntry) -> Result<i64, DbError> {
+        self.conn
+            .prepare(
+                "insert into audit_log (date, actor_uuid, action, target, before, after)
+                values (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?
+            .execute(params![
+                entry.date,
+                entry.actor_uuid,
+                entry.action,
+                entry.target,
+                entry.before.as_ref().map(|v| v.to_string()),
+                entry.after.as_ref().map(|v| v.to_string()),
+            ])?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Get up to `num` entries of the audit log matching `filter`, newest first, optionally only those older
+    /// than the entry with id `before`.
+    pub fn get_audit_log(
+        &self,
+        filter: &AuditLogFilter,
+        num: u32,
+        before: Option<i64>,
+    ) -> Result<Vec<AuditLogEntry>, DbError> {
+        let json = |row: &rusqlite::Row, idx| -> Result<Option<serde_json::Value>, DbError> {
+            row.get::<_, Option<String>>(idx)?
+                .map(|s| serde_json::from_str(&s))
+                .transpose()
+                .map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e))
+                })
+        };
+        self.conn
+            .prepare(
+                "select * from audit_log where id < ?1
+                and (?2 is null or actor_uuid = ?2)
+                and (?3 is null or action = ?3)
+                and (?4 is null or target = ?4)
+                order by id desc limit ?5",
+            )?
+            .query_map(
+                params![
+                    before.unwrap_or(i64::MAX),
+                    filter.actor,
+                    filter.action,
+                    filter.target,
+                    num
+                ],
+                |row| {
+                    Ok(AuditLogEntry {
+                        id: row.get(0)?,
+                        date: row.get(1)?,
+                        actor_uuid: row.get(2)?,
+                        action: row.get(3)?,
+                        target: row.get(4)?,
+                        before: json(row, 5)?,
+                        after: json(row, 6)?,
+                    })
+                },
+            )?
+            .collect()
+    }
+
+    /// Get the number of reactions with each emoji on a message, in the order they were first used.
+    pub fn get_reactions(&self, message: Uuid) -> Result<Vec<ReactionCount>, DbError> {
+        self.conn
+            .prepare(
+                "select emoji, count(1) from reactions where message_uuid = ?1
+                group by emoji order by min(rowid)",
+            )?
+            .query_map([message], |row| {
+                Ok(ReactionCount {
+                    emoji: row.get(0)?,
+                    count: row.get(1)?,
+                })
+            })?
+            .collect()
+    }
+
+    /// Record a user's reaction to a message.
+    /// Returns `Ok(0)` if the user had already reacted with that emoji.
+    pub fn add_reaction(
+        &self,
+        message: Uuid,
+        user: Uuid,
+        emoji: &ReactionEmoji,
+    ) -> Result<usize, DbError> {
+        self.conn
+            .prepare("insert or ignore into reactions values (?1, ?2, ?3)")?
+            .execute(params![message, user, emoji])
+    }
+
+    /// Remove a user's reaction from a message.
+    /// Returns `Ok(0)` if the user had not reacted with that emoji.
+    pub fn remove_reaction(
+        &self,
+        message: Uuid,
+        user: Uuid,
+        emoji: &ReactionEmoji,
+    ) -> Result<usize, DbError> {
+        self.conn
+            .prepare(
+                "delete from reactions where message_uuid = ?1 and user_uuid = ?2 and emoji = ?3",
+            )?
+            .execute(params![message, user, emoji])
+    }
+
+    pub fn delete_channel(&self, uuid: Uuid) -> Result<usize, DbError> {
+        // threads can't outlive the channel they inherit their permissions from, and neither can anything
+        // posted in them
+        let thread_messages = self
+            .conn
+            .prepare(
+                "select uuid from messages where channel_uuid in
+                (select channel_uuid from threads where parent_uuid = ?1)",
+            )?
+            .query_map([uuid], |row| row.get(0))?
+            .collect::<Result<Vec<Uuid>, _>>()?;
+        for message in thread_messages {
+            self.delete_message(message)?;
+        }
+        self.conn
+            .prepare(
+                "delete from last_read_messages where channel_uuid in
+                (select channel_uuid from threads where parent_uuid = ?1)",
+            )?
+            .execute([uuid])?;
+        self.conn
+            .prepare(
+                "delete from notify_settings where channel_uuid in
+                (select channel_uuid from threads where parent_uuid = ?1)",
+            )?
+            .execute([uuid])?;
+        self.conn
+            .prepare(
+                "delete from thread_participants where thread_uuid in
+                (select channel_uuid from threads where parent_uuid = ?1)",
+            )?
+            .execute([uuid])?;
+        self.conn
+            .prepare(
+                "delete from channels where uuid in
+                (select channel_uuid from threads where parent_uuid = ?1)",
+            )?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from threads where parent_uuid = ?1")?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from notify_settings where channel_uuid = ?1")?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from channel_follows where source_uuid = ?1 or target_uuid = ?1")?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from scheduled_messages where channel_uuid = ?1")?
+            .execute([uuid])?;
+        self.conn
+            .prepare("delete from channels where uuid = ?1")?
+            .execute([uuid])
+    }
+
+    pub fn clear_sync_servers_of(&self, user: Uuid) -> Result<usize, DbError> {
+        self.conn
+            .prepare("delete from sync_servers where user_uuid = ?1")?
+            .execute([user])
+    }
+
+    pub fn get_sync_servers(&self, user: Uuid) -> Result<Vec<SyncServer>, DbError> {
+        self.conn
+            .prepare("select * from sync_servers where user_uuid = ?1 order by idx")?
+            .query_map([user], |row| {
+                Ok(SyncServer {
+                    user_uuid: row.get(0)?,
+                    uuid: row.get(1)?,
+                    uname: row.get(2)?,
+                    ip: row.get(3)?,
+                    port: row.get(4)?,
+                    pfp: row.get(5)?,
+                    name: row.get(6)?,
+                    idx: row.get(7)?,
+                })
+            })?
+            .collect()
+    }
+
+    pub fn get_history(
+        &self,
+        channel: Uuid,
+        num: u32,
+        before_message: Option<Uuid>,
+    ) -> Result<Vec<Message>, DbError> {
+        let init_rowid = if let Some(uuid) = before_message {
+            self.conn
+                .prepare("select rowid from messages where uuid = ?1")?
+                .query_row([uuid], |row| row.get(0))?
+        } else {
+            i32::MAX
+        };
+        self.conn.prepare("select * from messages where channel_uuid = ?1 and rowid < ?2 order by rowid desc limit ?3")?
+            .query_map(params![channel, init_rowid, num], |row| self.message_from_row(row))?
+            .collect()
+    }
+
+    /// Search for messages matching `search`, newest first. Like `get_history`, `before_message`
+    /// can be given to get the page of results before a particular message.
+    pub fn search_messages(
+        &self,
+        search: &MessageSearch,
+        num: u32,
+        before_message: Option<Uuid>,
+    ) -> Result<Vec<Message>, DbError> {
+        use rusqlite::types::Value;
+
+        if search.channels.is_empty() {
+            return Ok(Vec::new());
+        }
+        let init_rowid = if let Some(uuid) = before_message {
+            self.conn
+                .prepare("select rowid from messages where uuid = ?1")?
+                .query_row([uuid], |row| row.get(0))?
+        } else {
+            i64::MAX
+        };
+
+        let mut sql = String::from("select * from messages where rowid < ? and deleted_at is null");
+        let mut args = vec![Value::Integer(init_rowid)];
+
+        sql += &format!(
+            " and channel_uuid in ({})",
+            vec!["?"; search.channels.len()].join(", ")
+        );
+        args.extend(search.channels.iter().map(|c| Value::Integer(*c)));
+
+        if let Some(query) = search.fts_query() {
+            sql += " and uuid in (select rowid from messages_fts where messages_fts match ?)";
+            args.push(Value::Text(query));
+        }
+        if let Some(author) = search.author {
+            sql += " and author_uuid = ?";
+            args.push(Value::Integer(author));
+        }
+        if let Some(after) = search.after {
+            sql += " and date >= ?";
+            args.push(Value::Integer(after));
+        }
+        if let Some(before) = search.before {
+            sql += " and date <= ?";
+            args.push(Value::Integer(before));
+        }
+        match search.has_reply {
+            Some(true) => sql += " and reply is not null",
+            Some(false) => sql += " and reply is null",
+            None => (),
+        }
+        sql += " order by rowid desc limit ?";
+        args.push(Value::Integer(num.into()));
+
+        self.conn
+            .prepare(&sql)?
+            .query_map(rusqlite::params_from_iter(args), |row| {
+                self.message_from_row(row)
+            })?
+            .collect()
+    }
+
+    pub fn insert_group(&self, group: &Group) -> Result<(), DbError> {
+        self.conn
+            .prepare("INSERT INTO groups VALUES (?1, ?2, ?3, ?4, ?5)")?
+            .execute(params![
+                group.uuid,
+                group.name,
+                group.colour,
+                Into::<Box<[u8]>>::into(&group.permissions).to_vec(),
+                group.position,
+            ])?;
+        Ok(())
+    }
+
+    // TODO in another struct?
+    pub fn resolve_server_permissions(&self, user: &User) -> Result<Permissions, DbError> {
+        let base = self.get_base_perms()?;
+        let mut groups = user
+            .groups
+            .iter()
+            .map(|guuid| self.get_group_exists(*guuid))
+            .collect::<Result<Vec<_>, _>>()?;
+        groups.sort_unstable_by_key(|g| g.position);
+        let perms = groups
+            .iter()
+            .rev()
+            .fold(base, |acc, g| acc.apply_over(&g.permissions));
+        Ok(perms)
+    }
+    pub fn resolve_channel_permissions(
+        &self,
+        user: &User,
+        channel_in: &Channel,
+    ) -> Result<Permissions, DbError> {
+        // Direct message channels ignore groups and overrides entirely: the two members can
+        // see, read and send, and nobody else (not even moderators) can do anything.
+        if let Some((a, b)) = channel_in.dm_members {
+            let member = if user.uuid == a || user.uuid == b {
+                Perm::Allow
+            } else {
+                Perm::Deny
+            };
+            return Ok(Permissions {
+                send_messages: member,
+                read_messages: member,
+                view_channel: member,
+                ..Default::default()
+            });
+        }
+        // Threads don't have any permissions of their own
+        if let Some(parent) = self.get_thread_parent(channel_in.uuid)? {
+            if let Some(parent) = self.get_channel(&parent)? {
+                return self.resolve_channel_permissions(user, &parent);
+            }
+        }
+        let mut perms = self.resolve_server_permissions(user)?;
+        // the category's overrides come first, so the channel's own overrides win
+        if let Some(category) = channel_in.category {
+            if let Some(category) = self.get_category(category)? {
+                perms = self.apply_overrides(user, perms, &category.permissions)?;
+            }
+        }
+        self.apply_overrides(user, perms, &channel_in.permissions)
+    }
+
+    /// Work out a user's permissions in a category, for deciding whether they can see it.
+    pub fn resolve_category_permissions(
+        &self,
+        user: &User,
+        category: &Category,
+    ) -> Result<Permissions, DbError> {
+        let base = self.resolve_server_permissions(user)?;
+        self.apply_overrides(user, base, &category.permissions)
+    }
+
+    /// Apply the overrides that affect `user` over `base`, lowest group first and the user's own overrides last.
+    fn apply_overrides(
+        &self,
+        user: &User,
+        base: Permissions,
+        overrides: &HashMap<PermableEntity, Permissions>,
+    ) -> Result<Permissions, DbError> {
+        // TODO as much as I like iterators, this might be better as a for loop.
+        let mut to_apply = overrides
+            .iter()
+            .filter_map(|(entity, perm)| match entity {
+                PermableEntity::User(uuid) if *uuid == user.uuid => Some(Ok((perm, 0))),
+                PermableEntity::Group(guuid) if user.groups.contains(guuid) => {
+                    Some(self.get_group_exists(*guuid).map(|g| (perm, g.position)))
+                }
+                _ => None,
+            })
+            .collect::<Result<Vec<_>, DbError>>()?;
+        to_apply.sort_unstable_by_key(|(_, pos)| *pos);
+        let perms = to_apply
+            .iter()
+            .rev()
+            .fold(base, |acc, (perm, _)| acc.apply_over(perm));
+        Ok(perms)
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use crate::permissions::Perm;
 
-    use super::*;
+    use super::*;
+
+    // #[test]
+    // fn user_groups() {
+    //     let s = init();
+    //     let (mut u1, mut u2) = test_users();
+    // }
+
+    // TODO !!!!!! test new user/channel/group/groups_of/perms stuff
+    // TODO !!!!! improve / increase migration testing
+    // TODO !!! test that non-channel perms being given in a specific channel are ignored
+
+    #[test]
+    fn permissions_simple() {
+        use std::default::Default as Df;
+        use Perm::*;
+        let s = init();
+        s.set_base_perms(Permissions {
+            modify_channels: Deny,
+            modify_icon_name: Deny,
+            modify_groups: Deny,
+            modify_user_groups: Deny,
+            ban_users: Deny,
+            send_messages: Allow,
+            read_messages: Allow,
+            manage_messages: Deny,
+            join_voice: Deny,
+            view_channel: Deny,
+            manage_emoji: Deny,
+            view_audit_log: Deny,
+            mention_everyone: Deny,
+            post_announcements: Deny,
+        })
+        .unwrap();
+
+        let g1 = Group {
+            uuid: gen_uuid(),
+            permissions: Permissions {
+                modify_groups: Deny,
+                send_messages: Deny,
+                ..Df::default()
+            },
+            name: "G1".to_owned(),
+            colour: 0xFF0000,
+            position: 1,
+        };
+        let g2 = Group {
+            uuid: gen_uuid(),
+            permissions: Permissions {
+                modify_groups: Allow,
+                join_voice: Allow,
+                ..Df::default()
+            },
+            name: "G2".to_owned(),
+            colour: 0xF0F000,
+            position: 2,
+        };
+
+        s.insert_group(&g1).unwrap();
+        s.insert_group(&g2).unwrap();
+
+        let u1 = User {
+            uuid: gen_uuid(),
+            name: "u1".into(),
+            pfp: "".into(),
+            password: "".into(),
+            groups: vec![g2.uuid, g1.uuid],
+            status: UserStatus::Online,
+            status_text: None,
+        };
+        let u2 = User {
+            uuid: gen_uuid(),
+            name: "u4".into(),
+            pfp: "".into(),
+            password: "".into(),
+            groups: vec![g1.uuid, g2.uuid],
+            status: UserStatus::Online,
+            status_text: None,
+        };
+        let u3 = User {
+            uuid: gen_uuid(),
+            name: "u2".into(),
+            pfp: "".into(),
+            password: "".into(),
+            groups: vec![g1.uuid],
+            status: UserStatus::Online,
+            status_text: None,
+        };
+        let u4 = User {
+            uuid: gen_uuid(),
+            name: "u3".into(),
+            pfp: "".into(),
+            password: "".into(),
+            groups: vec![],
+            status: UserStatus::Online,
+            status_text: None,
+        };
+
+        s.insert_user(&u1).unwrap();
+        s.insert_user(&u3).unwrap();
+        s.insert_user(&u4).unwrap();
+        s.insert_user(&u2).unwrap();
+
+        let mut c1 = Channel {
+            uuid: gen_uuid(),
+            name: "c1".into(),
+            position: 0,
+            permissions: HashMap::new(),
+            dm_members: None,
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        };
+
+        c1.permissions.insert(
+            PermableEntity::Group(g2.uuid),
+            Permissions {
+                send_messages: Allow,
+                join_voice: Deny,
+                ..Df::default()
+            },
+        );
+        c1.permissions.insert(
+            PermableEntity::User(u2.uuid),
+            Permissions {
+                send_messages: Allow,
+                join_voice: Allow,
+                manage_messages: Allow,
+                ..Df::default()
+            },
+        );
+
+        s.insert_channel(&c1).unwrap();
+
+        assert_eq!(
+            s.resolve_server_permissions(&u1).unwrap(),
+            Permissions {
+                modify_channels: Deny,
+                modify_icon_name: Deny,
+                modify_groups: Deny,
+                modify_user_groups: Deny,
+                ban_users: Deny,
+                send_messages: Deny,
+                read_messages: Allow,
+                manage_messages: Deny,
+                join_voice: Allow,
+                view_channel: Deny,
+                manage_emoji: Deny,
+                view_audit_log: Deny,
+                mention_everyone: Deny,
+                post_announcements: Deny,
+            }
+        );
+        assert_eq!(
+            s.resolve_channel_permissions(&u1, &c1).unwrap(),
+            Permissions {
+                modify_channels: Deny,
+                modify_icon_name: Deny,
+                modify_groups: Deny,
+                modify_user_groups: Deny,
+                ban_users: Deny,
+                send_messages: Allow,
+                read_messages: Allow,
+                manage_messages: Deny,
+                join_voice: Deny,
+                view_channel: Deny,
+                manage_emoji: Deny,
+                view_audit_log: Deny,
+                mention_everyone: Deny,
+                post_announcements: Deny,
+            }
+        );
+        assert_eq!(
+            s.resolve_channel_permissions(&u2, &c1).unwrap(),
+            Permissions {
+                modify_channels: Deny,
+                modify_icon_name: Deny,
+                modify_groups: Deny,
+                modify_user_groups: Deny,
+                ban_users: Deny,
+                send_messages: Allow,
+                read_messages: Allow,
+                manage_messages: Allow,
+                join_voice: Allow,
+                view_channel: Deny,
+                manage_emoji: Deny,
+                view_audit_log: Deny,
+                mention_everyone: Deny,
+                post_announcements: Deny,
+            }
+        );
+        assert_eq!(
+            s.resolve_server_permissions(&u2).unwrap(),
+            Permissions {
+                modify_channels: Deny,
+                modify_icon_name: Deny,
+                modify_groups: Deny,
+                modify_user_groups: Deny,
+                ban_users: Deny,
+                send_messages: Deny,
+                read_messages: Allow,
+                manage_messages: Deny,
+                join_voice: Allow,
+                view_channel: Deny,
+                manage_emoji: Deny,
+                view_audit_log: Deny,
+                mention_everyone: Deny,
+                post_announcements: Deny,
+            }
+        );
+        assert_eq!(
+            s.resolve_server_permissions(&u3).unwrap(),
+            Permissions {
+                modify_channels: Deny,
+                modify_icon_name: Deny,
+                modify_groups: Deny,
+                modify_user_groups: Deny,
+                ban_users: Deny,
+                send_messages: Deny,
+                read_messages: Allow,
+                manage_messages: Deny,
+                join_voice: Deny,
+                view_channel: Deny,
+                manage_emoji: Deny,
+                view_audit_log: Deny,
+                mention_everyone: Deny,
+                post_announcements: Deny,
+            }
+        );
+        assert_eq!(
+            s.resolve_server_permissions(&u4).unwrap(),
+            Permissions {
+                modify_channels: Deny,
+                modify_icon_name: Deny,
+                modify_groups: Deny,
+                modify_user_groups: Deny,
+                ban_users: Deny,
+                send_messages: Allow,
+                read_messages: Allow,
+                manage_messages: Deny,
+                join_voice: Deny,
+                view_channel: Deny,
+                manage_emoji: Deny,
+                view_audit_log: Deny,
+                mention_everyone: Deny,
+                post_announcements: Deny,
+            }
+        );
+    }
+
+    #[test]
+    fn groups() {}
 
-    // #[test]
-    // fn user_groups() {
-    //     let s = init();
-    //     let (mut u1, mut u2) = test_users();
-    // }
+    #[test]
+    fn server_config() {
+        let s = init();
+        assert_eq!(s.get_name().unwrap(), "Aster Server");
+        s.set_name("Renamed").unwrap();
+        assert_eq!(s.get_name().unwrap(), "Renamed");
 
-    // TODO !!!!!! test new user/channel/group/groups_of/perms stuff
-    // TODO !!!!! improve / increase migration testing
-    // TODO !!! test that non-channel perms being given in a specific channel are ignored
+        let icon = include_bytes!("../test_emoji.png");
+        s.set_icon(icon).unwrap();
+        assert_eq!(s.get_icon().unwrap(), icon.to_vec());
+
+        let perms = Permissions {
+            send_messages: Perm::Deny,
+            ..Default::default()
+        };
+        s.set_base_perms(perms.clone()).unwrap();
+        assert_eq!(s.get_base_perms().unwrap(), perms);
+    }
 
     #[test]
-    fn permissions_simple() {
-        use std::default::Default as Df;
-        use Perm::*;
-        let s = init();
-        s.set_base_perms(Permissions {
-            modify_channels: Deny,
-            modify_icon_name: Deny,
-            modify_groups: Deny,
-            modify_user_groups: Deny,
-            ban_users: Deny,
-            send_messages: Allow,
-            read_messages: Allow,
-            manage_messages: Deny,
-            join_voice: Deny,
-            view_channel: Deny,
-        })
-        .unwrap();
+    fn migration_simple() {
+        let init = r#"
+            BEGIN;
+            CREATE TABLE version (
+                version integer NOT NULL
+            );
+            CREATE TABLE server_config (
+                name text NOT NULL,
+                icon blob NOT NULL,
+                base_perms blob NOT NULL
+            );
+            INSERT INTO version VALUES(1);
+            COMMIT;
+        "#;
+        let migrations = &[Migration {
+            from: 1,
+            to: 2,
+            sql: r#"
+                CREATE TABLE users (
+                    uuid BigInt NOT NULL,
+                    name text NOT NULL
+                )
+                "#,
+            f: None,
+        }];
+        let sqlitedb = Connection::open_in_memory().expect("Unable to create a DB?");
+        let shared = Shared::new(sqlitedb);
+        shared.init_tables(init);
+        shared.apply_migrations(migrations, 1, 2);
+        assert_eq!(shared.get_db_version(), Some(2));
+        shared
+            .conn
+            .execute_batch("INSERT INTO users VALUES (1, \"hello world\")")
+            .unwrap();
+        let q: Result<(i64, String), _> = shared.conn.query_row("select * from users", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        });
 
-        let g1 = Group {
+        let r = q.unwrap();
+        assert_eq!(r.0, 1);
+        assert_eq!(r.1, "hello world");
+    }
+
+    #[test]
+    fn group_columns() {
+        let s = init();
+        let group = Group {
             uuid: gen_uuid(),
             permissions: Permissions {
-                modify_groups: Deny,
-                send_messages: Deny,
-                ..Df::default()
+                ban_users: Perm::Allow,
+                ..Default::default()
             },
-            name: "G1".to_owned(),
-            colour: 0xFF0000,
-            position: 1,
+            name: "mods".into(),
+            colour: 7,
+            position: 0,
         };
-        let g2 = Group {
-            uuid: gen_uuid(),
-            permissions: Permissions {
-                modify_groups: Allow,
-                join_voice: Allow,
-                ..Df::default()
+        s.insert_group(&group).unwrap();
+        assert_eq!(s.get_group(group.uuid).unwrap(), Some(group.clone()));
+        assert_eq!(s.get_groups().unwrap(), vec![group.clone()]);
+
+        // groups inserted with the columns in the old, wrong order are fixed up
+        s.conn
+            .prepare("UPDATE groups SET name = ?1, colour = 'mods', permissions = 7")
+            .unwrap()
+            .execute([Into::<Box<[u8]>>::into(&group.permissions).to_vec()])
+            .unwrap();
+        s.apply_migrations(MIGRATIONS, 24, 25);
+        assert_eq!(s.get_groups().unwrap(), vec![group]);
+    }
+
+    #[test]
+    fn migration_with_hook() {
+        let init = r#"
+            BEGIN;
+            CREATE TABLE version (
+                version integer NOT NULL
+            );
+            CREATE TABLE server_config (
+                name text NOT NULL,
+                icon blob NOT NULL,
+                base_perms blob NOT NULL
+            );
+            INSERT INTO version VALUES(1);
+            COMMIT;
+        "#;
+        let migrations = &[Migration {
+            from: 1,
+            to: 2,
+            sql: r#"
+                CREATE TABLE users (
+                    uuid BigInt NOT NULL,
+                    name text NOT NULL
+                )
+                "#,
+            f: Some(|conn: &Connection| {
+                conn.execute_batch("insert into users values (2, \"hi\")")?;
+                Ok(())
+            }),
+        }];
+        let sqlitedb = Connection::open_in_memory().expect("Unable to create a DB?");
+        let shared = Shared::new(sqlitedb);
+        shared.init_tables(init);
+        shared.apply_migrations(migrations, 1, 2);
+        assert_eq!(shared.get_db_version(), Some(2));
+
+        let q: Result<(i64, String), _> = shared.conn.query_row("select * from users", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        });
+
+        let r = q.unwrap();
+        assert_eq!(r.0, 2);
+        assert_eq!(r.1, "hi");
+    }
+    #[test]
+    fn multiple_migrations() {
+        let init = r#"
+            BEGIN;
+            CREATE TABLE version (
+                version integer NOT NULL
+            );
+            CREATE TABLE server_config (
+                name text NOT NULL,
+                icon blob NOT NULL,
+                base_perms blob NOT NULL
+            );
+            INSERT INTO version VALUES(1);
+            COMMIT;
+        "#;
+        let migrations = &[
+            Migration {
+                from: 1,
+                to: 2,
+                sql: r#"
+                CREATE TABLE users (
+                    uuid BigInt NOT NULL,
+                    name text NOT NULL
+                )
+                "#,
+                f: None,
             },
-            name: "G2".to_owned(),
-            colour: 0xF0F000,
-            position: 2,
-        };
+            Migration {
+                from: 2,
+                to: 3,
+                sql: r#"
+                CREATE TABLE messages (
+                    uuid BigInt NOT NULL,
+                    content text NOT NULL
+                )
+                "#,
+                f: Some(|conn: &Connection| {
+                    conn.execute_batch("insert into messages values (2, \"hi\")")?;
+                    Ok(())
+                }),
+            },
+        ];
+        let sqlitedb = Connection::open_in_memory().expect("Unable to create a DB?");
+        let shared = Shared::new(sqlitedb);
+        shared.init_tables(init);
+        shared.apply_migrations(migrations, 1, 3);
 
-        s.insert_group(&g1).unwrap();
-        s.insert_group(&g2).unwrap();
+        assert_eq!(shared.get_db_version(), Some(3));
+        shared
+            .conn
+            .execute_batch("INSERT INTO users VALUES (1, \"hello world\")")
+            .unwrap();
 
-        let u1 = User {
-            uuid: gen_uuid(),
-            name: "u1".into(),
-            pfp: "".into(),
-            password: "".into(),
-            groups: vec![g2.uuid, g1.uuid],
-        };
-        let u2 = User {
-            uuid: gen_uuid(),
-            name: "u4".into(),
-            pfp: "".into(),
-            password: "".into(),
-            groups: vec![g1.uuid, g2.uuid],
-        };
-        let u3 = User {
+        let q: Result<(i64, String), _> = shared.conn.query_row("select * from users", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        });
+
+        let r = q.unwrap();
+        assert_eq!(r.0, 1);
+        assert_eq!(r.1, "hello world");
+
+        let q: Result<(i64, String), _> =
+            shared.conn.query_row("select * from messages", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            });
+
+        let r = q.unwrap();
+        assert_eq!(r.0, 2);
+        assert_eq!(r.1, "hi");
+    }
+
+    fn init() -> Shared {
+        let sqlitedb = Connection::open_in_memory().expect("Unable to create a DB?");
+        let shared = Shared::new(sqlitedb);
+        shared.init_db();
+        shared
+    }
+
+    fn test_users() -> (User, User) {
+        let mut user = User {
             uuid: gen_uuid(),
-            name: "u2".into(),
-            pfp: "".into(),
-            password: "".into(),
-            groups: vec![g1.uuid],
+            name: "Test user".into(),
+            pfp: "test pfp".into(),
+            password: "password".into(),
+            groups: Vec::new(),
+            status: UserStatus::Online,
+            status_text: None,
         };
-        let u4 = User {
+        let user_2 = User {
             uuid: gen_uuid(),
-            name: "u3".into(),
-            pfp: "".into(),
-            password: "".into(),
-            groups: vec![],
+            name: "User 2".into(),
+            pfp: "test_pfp".into(),
+            password: "12345".into(),
+            groups: Vec::new(),
+            status: UserStatus::Online,
+            status_text: None,
         };
+        user.groups.push(gen_uuid());
+        user.groups.push(gen_uuid());
+        user.groups.push(gen_uuid());
+        (user, user_2)
+    }
 
-        s.insert_user(&u1).unwrap();
-        s.insert_user(&u3).unwrap();
-        s.insert_user(&u4).unwrap();
-        s.insert_user(&u2).unwrap();
-
+    fn test_channels() -> (Channel, Channel) {
         let mut c1 = Channel {
+            name: "general-16.35".into(),
             uuid: gen_uuid(),
-            name: "c1".into(),
             position: 0,
             permissions: HashMap::new(),
+            dm_members: None,
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        };
+        let c2 = Channel {
+            name: "memes".into(),
+            uuid: gen_uuid(),
+            position: 1,
+            permissions: HashMap::new(),
+            dm_members: None,
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
         };
 
         c1.permissions.insert(
-            PermableEntity::Group(g2.uuid),
+            PermableEntity::User(gen_uuid()),
             Permissions {
-                send_messages: Allow,
-                join_voice: Deny,
-                ..Df::default()
+                modify_channels: Perm::Allow,
+                modify_icon_name: Perm::Allow,
+                modify_groups: Perm::Allow,
+                modify_user_groups: Perm::Allow,
+                ban_users: Perm::Allow,
+                send_messages: Perm::Allow,
+                read_messages: Perm::Allow,
+                manage_messages: Perm::Allow,
+                join_voice: Perm::Allow,
+                view_channel: Perm::Allow,
+                manage_emoji: Perm::Allow,
+                view_audit_log: Perm::Allow,
+                mention_everyone: Perm::Allow,
+                post_announcements: Perm::Allow,
             },
         );
-        c1.permissions.insert(
-            PermableEntity::User(u2.uuid),
-            Permissions {
-                send_messages: Allow,
-                join_voice: Allow,
-                manage_messages: Allow,
-                ..Df::default()
+
+        (c1, c2)
+    }
+
+    fn test_messages(
+        c1: &Channel,
+        c2: &Channel,
+        u1: &User,
+        u2: &User,
+    ) -> (Message, Message, Message) {
+        let m1_uuid = gen_uuid();
+        (
+            Message {
+                uuid: m1_uuid,
+                content: "Hello world".into(),
+                author_uuid: u1.uuid,
+                channel_uuid: c1.uuid,
+                date: 1359083513,
+                edited: false,
+                edited_at: None,
+                deleted_at: None,
+                deleted_by: None,
+                reply: None,
+                reactions: Vec::new(),
+                attachments: Vec::new(),
             },
-        );
+            Message {
+                uuid: gen_uuid(),
+                content: "Goodbye world".into(),
+                author_uuid: u1.uuid,
+                channel_uuid: c2.uuid,
+                date: 1359083514,
+                edited: false,
+                edited_at: None,
+                deleted_at: None,
+                deleted_by: None,
+                reply: None,
+                reactions: Vec::new(),
+                attachments: Vec::new(),
+            },
+            Message {
+                uuid: gen_uuid(),
+                content: "aster is the greatest lmfao".into(),
+                author_uuid: u2.uuid,
+                channel_uuid: c1.uuid,
+                date: 1359083515,
+                edited: false,
+                edited_at: None,
+                deleted_at: None,
+                deleted_by: None,
+                reply: Some(m1_uuid),
+                reactions: Vec::new(),
+                attachments: Vec::new(),
+            },
+        )
+    }
 
-        s.insert_channel(&c1).unwrap();
+    fn init_with_users() -> (Shared, User, User) {
+        let s = init();
+        let (u1, u2) = test_users();
+        s.insert_user(&u1).unwrap();
+        s.insert_user(&u2).unwrap();
+        (s, u1, u2)
+    }
 
-        assert_eq!(
-            s.resolve_server_permissions(&u1).unwrap(),
-            Permissions {
-                modify_channels: Deny,
-                modify_icon_name: Deny,
-                modify_groups: Deny,
-                modify_user_groups: Deny,
-                ban_users: Deny,
-                send_messages: Deny,
-                read_messages: Allow,
-                manage_messages: Deny,
-                join_voice: Allow,
-                view_channel: Deny,
-            }
-        );
-        assert_eq!(
-            s.resolve_channel_permissions(&u1, &c1).unwrap(),
-            Permissions {
-                modify_channels: Deny,
-                modify_icon_name: Deny,
-                modify_groups: Deny,
-                modify_user_groups: Deny,
-                ban_users: Deny,
-                send_messages: Allow,
-                read_messages: Allow,
-                manage_messages: Deny,
-                join_voice: Deny,
-                view_channel: Deny,
-            }
-        );
-        assert_eq!(
-            s.resolve_channel_permissions(&u2, &c1).unwrap(),
-            Permissions {
-                modify_channels: Deny,
-                modify_icon_name: Deny,
-                modify_groups: Deny,
-                modify_user_groups: Deny,
-                ban_users: Deny,
-                send_messages: Allow,
-                read_messages: Allow,
-                manage_messages: Allow,
-                join_voice: Allow,
-                view_channel: Deny,
-            }
-        );
-        assert_eq!(
-            s.resolve_server_permissions(&u2).unwrap(),
-            Permissions {
-                modify_channels: Deny,
-                modify_icon_name: Deny,
-                modify_groups: Deny,
-                modify_user_groups: Deny,
-                ban_users: Deny,
-                send_messages: Deny,
-                read_messages: Allow,
-                manage_messages: Deny,
-                join_voice: Allow,
-                view_channel: Deny,
-            }
-        );
-        assert_eq!(
-            s.resolve_server_permissions(&u3).unwrap(),
-            Permissions {
-                modify_channels: Deny,
-                modify_icon_name: Deny,
-                modify_groups: Deny,
-                modify_user_groups: Deny,
-                ban_users: Deny,
-                send_messages: Deny,
-                read_messages: Allow,
-                manage_messages: Deny,
-                join_voice: Deny,
-                view_channel: Deny,
-            }
-        );
-        assert_eq!(
-            s.resolve_server_permissions(&u4).unwrap(),
-            Permissions {
-                modify_channels: Deny,
-                modify_icon_name: Deny,
-                modify_groups: Deny,
-                modify_user_groups: Deny,
-                ban_users: Deny,
-                send_messages: Allow,
-                read_messages: Allow,
-                manage_messages: Deny,
-                join_voice: Deny,
-                view_channel: Deny,
-            }
-        );
+    #[test]
+    fn insert_user() {
+        let s = init();
+        let (u1, u2) = test_users();
+        s.insert_user(&u1).unwrap();
+        s.insert_user(&u2).unwrap();
+    }
+
+    #[test]
+    fn get_user_by_name() {
+        let (s, u1, _) = init_with_users();
+        let user_1_query = s.get_user_by_name(&u1.name).unwrap().unwrap();
+        assert_eq!(user_1_query.uuid, u1.uuid);
+        assert_eq!(user_1_query.name, u1.name);
+    }
+
+    #[test]
+    fn get_nonexistant_user_by_name() {
+        let (s, _, _) = init_with_users();
+        let user_1_query = s.get_user_by_name("I am a nonexistant user").unwrap();
+        assert!(user_1_query.is_none());
+    }
+
+    #[test]
+    fn get_user_by_uuid() {
+        let (s, u1, _) = init_with_users();
+        let user_1_query = s.get_user(u1.uuid).unwrap().unwrap();
+        assert_eq!(user_1_query.uuid, u1.uuid);
+        assert_eq!(user_1_query.name, u1.name);
+    }
+
+    #[test]
+    fn get_nonexistant_user_by_uuid() {
+        let (s, _, _) = init_with_users();
+
+        let user_1_query = s.get_user(gen_uuid());
+        assert!(user_1_query.is_ok());
+        let user_1_query = user_1_query.unwrap();
+        assert!(user_1_query.is_none());
+    }
+
+    #[test]
+    fn list_users() {
+        let (s, u1, u2) = init_with_users();
+
+        let users = s.get_users();
+        assert!(users.is_ok());
+        let users = users.unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].uuid, u1.uuid);
+        assert_eq!(users[1].uuid, u2.uuid);
+    }
+
+    #[test]
+    fn update_user() {
+        let (s, u1, _) = init_with_users();
+        assert_eq!(u1.name, "Test user");
+        let new_u1 = User {
+            uuid: u1.uuid,
+            name: "Test user updated".into(),
+            pfp: "pfp2".into(),
+            password: "abcde".into(),
+            groups: Vec::new(),
+            status: UserStatus::Online,
+            status_text: None,
+        };
+        s.update_user(&new_u1).unwrap();
+        let user_1_query = s.get_user(u1.uuid);
+        assert!(user_1_query.is_ok());
+        let user_1_query = user_1_query.unwrap();
+        assert!(user_1_query.is_some());
+        assert_eq!(user_1_query.unwrap(), new_u1);
+    }
+
+    #[test]
+    fn initial_channel_is_general() {
+        let s = init();
+        let chans = s.get_channels();
+        assert!(chans.is_ok());
+        let chans = chans.unwrap();
+        assert_eq!(chans.len(), 1);
+        assert_eq!(chans[0].name, "general");
     }
 
     #[test]
-    fn groups() {}
+    fn insert_channels() {
+        let s = init();
+        let (c1, c2) = test_channels();
+        assert!(s.insert_channel(&c1).is_ok());
+        assert!(s.insert_channel(&c2).is_ok());
+    }
 
     #[test]
-    fn migration_simple() {
-        let init = r#"
-            BEGIN;
-            CREATE TABLE version (
-                version integer NOT NULL
-            );
-            CREATE TABLE server_config (
-                name text NOT NULL,
-                icon blob NOT NULL,
-                base_perms blob NOT NULL
-            );
-            INSERT INTO version VALUES(1);
-            COMMIT;
-        "#;
-        let migrations = &[Migration {
-            from: 1,
-            to: 2,
-            sql: r#"
-                CREATE TABLE users (
-                    uuid BigInt NOT NULL,
-                    name text NOT NULL
-                )
-                "#,
-            f: None,
-        }];
-        let sqlitedb = Connection::open_in_memory().expect("Unable to create a DB?");
-        let shared = Shared::new(sqlitedb);
-        shared.init_tables(init);
-        shared.apply_migrations(migrations, 1, 2);
-        assert_eq!(shared.get_db_version(), Some(2));
-        shared
-            .conn
-            .execute_batch("INSERT INTO users VALUES (1, \"hello world\")")
-            .unwrap();
-        let q: Result<(i64, String), _> = shared.conn.query_row("select * from users", [], |row| {
-            Ok((row.get(0)?, row.get(1)?))
-        });
+    fn get_channel_by_uuid() {
+        let s = init();
+        let (c1, c2) = test_channels();
+        assert!(s.insert_channel(&c1).is_ok());
+        assert!(s.insert_channel(&c2).is_ok());
 
-        let r = q.unwrap();
-        assert_eq!(r.0, 1);
-        assert_eq!(r.1, "hello world");
+        let c1_q = s.get_channel(&c1.uuid);
+        assert!(c1_q.is_ok());
+        let c1_q = c1_q.unwrap();
+        assert!(c1_q.is_some());
+        let c1_q = c1_q.unwrap();
+        assert_eq!(c1_q.uuid, c1.uuid);
+        assert_eq!(c1_q.name, c1.name);
     }
 
     #[test]
-    fn migration_with_hook() {
-        let init = r#"
-            BEGIN;
-            CREATE TABLE version (
-                version integer NOT NULL
-            );
-            CREATE TABLE server_config (
-                name text NOT NULL,
-                icon blob NOT NULL,
-                base_perms blob NOT NULL
-            );
-            INSERT INTO version VALUES(1);
-            COMMIT;
-        "#;
-        let migrations = &[Migration {
-            from: 1,
-            to: 2,
-            sql: r#"
-                CREATE TABLE users (
-                    uuid BigInt NOT NULL,
-                    name text NOT NULL
-                )
-                "#,
-            f: Some(|conn: &Connection| {
-                conn.execute_batch("insert into users values (2, \"hi\")")?;
-                Ok(())
-            }),
-        }];
-        let sqlitedb = Connection::open_in_memory().expect("Unable to create a DB?");
-        let shared = Shared::new(sqlitedb);
-        shared.init_tables(init);
-        shared.apply_migrations(migrations, 1, 2);
-        assert_eq!(shared.get_db_version(), Some(2));
+    fn get_channel_by_name() {
+        let s = init();
+        let (c1, c2) = test_channels();
+        assert!(s.insert_channel(&c1).is_ok());
+        assert!(s.insert_channel(&c2).is_ok());
 
-        let q: Result<(i64, String), _> = shared.conn.query_row("select * from users", [], |row| {
-            Ok((row.get(0)?, row.get(1)?))
-        });
+        let c1_q = s.get_channel_by_name(&c1.name);
+        assert!(c1_q.is_ok());
+        let c1_q = c1_q.unwrap();
+        assert!(c1_q.is_some());
+        let c1_q = c1_q.unwrap();
+        assert_eq!(c1_q.uuid, c1.uuid);
+        assert_eq!(c1_q.name, c1.name);
+    }
 
-        let r = q.unwrap();
-        assert_eq!(r.0, 2);
-        assert_eq!(r.1, "hi");
+    #[test]
+    fn get_nonexistant_channel_by_uuid() {
+        let s = init();
+        let (c1, c2) = test_channels();
+        assert!(s.insert_channel(&c1).is_ok());
+        assert!(s.insert_channel(&c2).is_ok());
+
+        let c1_q = s.get_channel(&gen_uuid());
+        assert!(c1_q.is_ok());
+        let c1_q = c1_q.unwrap();
+        assert!(c1_q.is_none());
     }
+
     #[test]
-    fn multiple_migrations() {
-        let init = r#"
-            BEGIN;
-            CREATE TABLE version (
-                version integer NOT NULL
-            );
-            CREATE TABLE server_config (
-                name text NOT NULL,
-                icon blob NOT NULL,
-                base_perms blob NOT NULL
-            );
-            INSERT INTO version VALUES(1);
-            COMMIT;
-        "#;
-        let migrations = &[
-            Migration {
-                from: 1,
-                to: 2,
-                sql: r#"
-                CREATE TABLE users (
-                    uuid BigInt NOT NULL,
-                    name text NOT NULL
-                )
-                "#,
-                f: None,
-            },
-            Migration {
-                from: 2,
-                to: 3,
-                sql: r#"
-                CREATE TABLE messages (
-                    uuid BigInt NOT NULL,
-                    content text NOT NULL
-                )
-                "#,
-                f: Some(|conn: &Connection| {
-                    conn.execute_batch("insert into messages values (2, \"hi\")")?;
-                    Ok(())
-                }),
-            },
-        ];
-        let sqlitedb = Connection::open_in_memory().expect("Unable to create a DB?");
-        let shared = Shared::new(sqlitedb);
-        shared.init_tables(init);
-        shared.apply_migrations(migrations, 1, 3);
+    fn get_nonexistant_channel_by_name() {
+        let s = init();
+        let (c1, c2) = test_channels();
+        assert!(s.insert_channel(&c1).is_ok());
+        assert!(s.insert_channel(&c2).is_ok());
 
-        assert_eq!(shared.get_db_version(), Some(3));
-        shared
-            .conn
-            .execute_batch("INSERT INTO users VALUES (1, \"hello world\")")
-            .unwrap();
+        let c1_q = s.get_channel_by_name("this channel does not exist");
+        assert!(c1_q.is_ok());
+        let c1_q = c1_q.unwrap();
+        assert!(c1_q.is_none());
+    }
 
-        let q: Result<(i64, String), _> = shared.conn.query_row("select * from users", [], |row| {
-            Ok((row.get(0)?, row.get(1)?))
-        });
+    #[test]
+    fn channel_exists() {
+        let s = init();
+        let c1 = Channel {
+            name: "general-16.35".into(),
+            uuid: gen_uuid(),
+            position: 0,
+            permissions: HashMap::new(),
+            dm_members: None,
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        };
+
+        assert!(s.insert_channel(&c1).is_ok());
+        assert!(s.channel_exists(&c1.uuid).is_ok());
+        assert!(s.channel_exists(&c1.uuid).unwrap());
+    }
+
+    #[test]
+    fn channel_doesnt_exist() {
+        let s = init();
+        let c1 = Channel {
+            name: "general-16.35".into(),
+            uuid: gen_uuid(),
+            position: 0,
+            permissions: HashMap::new(),
+            dm_members: None,
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        };
+
+        let not_existing_uuid = gen_uuid();
+        assert!(s.insert_channel(&c1).is_ok());
+        assert!(s.channel_exists(&not_existing_uuid).is_ok());
+        assert!(!s.channel_exists(&not_existing_uuid).unwrap());
+    }
+
+    #[test]
+    fn duplicate_channel() {
+        let s = init();
+        let c1 = Channel {
+            name: "general-16.35".into(),
+            uuid: gen_uuid(),
+            position: 0,
+            permissions: HashMap::new(),
+            dm_members: None,
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        };
+        assert!(s.insert_channel(&c1).is_ok());
+        assert!(s.insert_channel(&c1).is_err());
+    }
+
+    #[test]
+    fn delete_channel() {
+        let s = init();
+        let c1 = Channel {
+            name: "general-16.35".into(),
+            uuid: gen_uuid(),
+            position: 0,
+            permissions: HashMap::new(),
+            dm_members: None,
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        };
+        assert!(s.insert_channel(&c1).is_ok());
+        assert!(s.delete_channel(c1.uuid).is_ok());
+        assert!(s.get_channel(&c1.uuid).is_ok_and(|c| c.is_none()));
+    }
+    #[test]
+    fn update_channel() {
+        let s = init();
+        let c1 = Channel {
+            name: "general-16.35".into(),
+            uuid: gen_uuid(),
+            position: 1,
+            permissions: HashMap::new(),
+            dm_members: None,
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        };
+        assert!(s.insert_channel(&c1).is_ok());
+        let c2 = Channel {
+            name: "random-shit".into(),
+            uuid: c1.uuid,
+            position: 1,
+            permissions: HashMap::new(),
+            dm_members: None,
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        };
+        assert!(s.update_channel(&c2).is_ok());
+        assert!(s.get_channel(&c1.uuid).is_ok());
+        assert!(s.get_channel(&c1.uuid).unwrap().is_some());
+        assert_eq!(s.get_channel(&c1.uuid).unwrap().unwrap(), c2);
+    }
 
-        let r = q.unwrap();
-        assert_eq!(r.0, 1);
-        assert_eq!(r.1, "hello world");
+    fn test_dm(u1: &User, u2: &User) -> Channel {
+        Channel {
+            name: "".into(),
+            uuid: gen_uuid(),
+            position: 0,
+            permissions: HashMap::new(),
+            dm_members: Some((u1.uuid, u2.uuid)),
+            category: None,
+            topic: None,
+            nsfw: false,
+            archived: false,
+            slow_mode: 0,
+            announcement: false,
+        }
+    }
 
-        let q: Result<(i64, String), _> =
-            shared.conn.query_row("select * from messages", [], |row| {
-                Ok((row.get(0)?, row.get(1)?))
-            });
+    #[test]
+    fn dm_channels_not_listed() {
+        let (s, u1, u2) = init_with_users();
+        let dm = test_dm(&u1, &u2);
+        s.insert_channel(&dm).unwrap();
+        let chans = s.get_channels().unwrap();
+        assert_eq!(chans.len(), 1);
+        assert_eq!(chans[0].name, "general");
+        assert_eq!(s.get_channel(&dm.uuid).unwrap().unwrap(), dm);
+    }
 
-        let r = q.unwrap();
-        assert_eq!(r.0, 2);
-        assert_eq!(r.1, "hi");
+    #[test]
+    fn dm_channel_between() {
+        let (s, u1, u2) = init_with_users();
+        let dm = test_dm(&u1, &u2);
+        s.insert_channel(&dm).unwrap();
+        assert_eq!(
+            s.get_dm_channel_between(u1.uuid, u2.uuid).unwrap(),
+            Some(dm.clone())
+        );
+        assert_eq!(
+            s.get_dm_channel_between(u2.uuid, u1.uuid).unwrap(),
+            Some(dm)
+        );
+        assert!(s
+            .get_dm_channel_between(u1.uuid, gen_uuid())
+            .unwrap()
+            .is_none());
     }
 
-    fn init() -> Shared {
-        let sqlitedb = Connection::open_in_memory().expect("Unable to create a DB?");
-        let shared = Shared::new(sqlitedb);
-        shared.init_db();
-        shared
+    #[test]
+    fn dm_channels_of() {
+        let (s, u1, u2) = init_with_users();
+        let dm = test_dm(&u1, &u2);
+        s.insert_channel(&dm).unwrap();
+        assert_eq!(s.get_dm_channels_of(u1.uuid).unwrap(), vec![dm.clone()]);
+        assert_eq!(s.get_dm_channels_of(u2.uuid).unwrap(), vec![dm]);
+        assert!(s.get_dm_channels_of(gen_uuid()).unwrap().is_empty());
     }
 
-    fn test_users() -> (User, User) {
-        let mut user = User {
-            uuid: gen_uuid(),
-            name: "Test user".into(),
-            pfp: "test pfp".into(),
-            password: "password".into(),
-            groups: Vec::new(),
-        };
-        let user_2 = User {
+    #[test]
+    fn dm_permissions() {
+        let s = init();
+        let (mut u1, u2) = test_users();
+        u1.groups.clear();
+        let u3 = User {
             uuid: gen_uuid(),
-            name: "User 2".into(),
-            pfp: "test_pfp".into(),
-            password: "12345".into(),
-            groups: Vec::new(),
+            name: "u3".into(),
+            pfp: "".into(),
+            password: "".into(),
+            groups: vec![],
+            status: UserStatus::Online,
+            status_text: None,
         };
-        user.groups.push(gen_uuid());
-        user.groups.push(gen_uuid());
-        user.groups.push(gen_uuid());
-        (user, user_2)
+        s.insert_user(&u1).unwrap();
+        s.insert_user(&u2).unwrap();
+        s.insert_user(&u3).unwrap();
+        let dm = test_dm(&u1, &u2);
+        s.insert_channel(&dm).unwrap();
+
+        let member = s.resolve_channel_permissions(&u2, &dm).unwrap();
+        assert_eq!(member.read_messages, Perm::Allow);
+        assert_eq!(member.send_messages, Perm::Allow);
+        assert_eq!(member.manage_messages, Perm::Default);
+
+        let outsider = s.resolve_channel_permissions(&u3, &dm).unwrap();
+        assert_eq!(outsider.read_messages, Perm::Deny);
+        assert_eq!(outsider.send_messages, Perm::Deny);
+        assert_eq!(outsider.view_channel, Perm::Deny);
     }
 
-    fn test_channels() -> (Channel, Channel) {
-        let mut c1 = Channel {
-            name: "general-16.35".into(),
+    fn test_thread(m: &Message, creator: &User) -> Thread {
+        Thread {
             uuid: gen_uuid(),
-            position: 0,
-            permissions: HashMap::new(),
-        };
-        let c2 = Channel {
-            name: "memes".into(),
+            name: "a thread".into(),
+            channel_uuid: m.channel_uuid,
+            message_uuid: m.uuid,
+            creator_uuid: creator.uuid,
+            date: 1359083520,
+            participants: vec![creator.uuid],
+        }
+    }
+
+    #[test]
+    fn threads() {
+        let (s, m1, _, m3, c1, c2, u1, u2) = init_with_msgs(true);
+        let t = test_thread(&m1, &u1);
+        s.insert_thread(&t).unwrap();
+
+        assert_eq!(s.get_thread(t.uuid).unwrap(), Some(t.clone()));
+        assert_eq!(s.get_thread_of_message(m1.uuid).unwrap(), Some(t.clone()));
+        assert!(s.get_thread_of_message(m3.uuid).unwrap().is_none());
+        assert_eq!(s.get_threads_in(c1.uuid).unwrap(), vec![t.clone()]);
+        assert!(s.get_threads_in(c2.uuid).unwrap().is_empty());
+        assert_eq!(s.get_thread_parent(t.uuid).unwrap(), Some(c1.uuid));
+        assert!(s.get_thread_parent(c1.uuid).unwrap().is_none());
+
+        // threads aren't channels in their own right
+        assert!(!s.get_channels().unwrap().iter().any(|c| c.uuid == t.uuid));
+
+        assert_eq!(s.add_thread_participant(t.uuid, u2.uuid).unwrap(), 1);
+        assert_eq!(s.add_thread_participant(t.uuid, u1.uuid).unwrap(), 0);
+        assert_eq!(
+            s.get_thread_participants(t.uuid).unwrap(),
+            vec![u1.uuid, u2.uuid]
+        );
+    }
+
+    #[test]
+    fn thread_history_separate() {
+        let (s, m1, _, m3, c1, _, u1, u2) = init_with_msgs(true);
+        let t = test_thread(&m1, &u1);
+        s.insert_thread(&t).unwrap();
+        let reply = Message {
             uuid: gen_uuid(),
-            position: 1,
-            permissions: HashMap::new(),
+            channel_uuid: t.uuid,
+            author_uuid: u2.uuid,
+            date: 1359083521,
+            ..m3.clone()
         };
+        s.add_to_history(&reply).unwrap();
+
+        assert_eq!(s.get_history(t.uuid, 50, None).unwrap(), vec![reply]);
+        assert_eq!(
+            s.get_history(c1.uuid, 50, None).unwrap(),
+            vec![m3.clone(), m1.clone()]
+        );
+
+        // unread counts work per thread
+        s.update_last_read_for_user_in_channel(u1.uuid, t.uuid, m1.uuid)
+            .unwrap();
+        assert_eq!(s.get_num_unread_messages(u1.uuid, t.uuid).unwrap(), 1);
+    }
 
+    #[test]
+    fn thread_permissions() {
+        let (s, m1, _, _, c1, _, u1, u2) = init_with_msgs(true);
+        let t = test_thread(&m1, &u1);
+        s.insert_thread(&t).unwrap();
+        let thread_channel = s.get_channel(&t.uuid).unwrap().unwrap();
+
+        let mut c1 = c1;
         c1.permissions.insert(
-            PermableEntity::User(gen_uuid()),
+            PermableEntity::User(u2.uuid),
             Permissions {
-                modify_channels: Perm::Allow,
-                modify_icon_name: Perm::Allow,
-                modify_groups: Perm::Allow,
-                modify_user_groups: Perm::Allow,
-                ban_users: Perm::Allow,
-                send_messages: Perm::Allow,
-                read_messages: Perm::Allow,
-                manage_messages: Perm::Allow,
-                join_voice: Perm::Allow,
-                view_channel: Perm::Allow,
+                read_messages: Perm::Deny,
+                ..Default::default()
             },
         );
+        s.update_channel(&c1).unwrap();
+        assert_eq!(
+            s.resolve_channel_permissions(&u2, &thread_channel)
+                .unwrap()
+                .read_messages,
+            Perm::Deny
+        );
 
-        (c1, c2)
+        // deleting the parent takes the thread with it
+        s.delete_channel(c1.uuid).unwrap();
+        assert!(s.get_thread(t.uuid).unwrap().is_none());
+        assert!(s.get_channel(&t.uuid).unwrap().is_none());
     }
 
-    fn test_messages(
-        c1: &Channel,
-        c2: &Channel,
-        u1: &User,
-        u2: &User,
-    ) -> (Message, Message, Message) {
-        let m1_uuid = gen_uuid();
-        (
-            Message {
-                uuid: m1_uuid,
-                content: "Hello world".into(),
-                author_uuid: u1.uuid,
-                channel_uuid: c1.uuid,
-                date: 1359083513,
-                edited: false,
-                reply: None,
-            },
-            Message {
-                uuid: gen_uuid(),
-                content: "Goodbye world".into(),
-                author_uuid: u1.uuid,
-                channel_uuid: c2.uuid,
-                date: 1359083514,
-                edited: false,
-                reply: None,
-            },
-            Message {
-                uuid: gen_uuid(),
-                content: "aster is the greatest lmfao".into(),
-                author_uuid: u2.uuid,
-                channel_uuid: c1.uuid,
-                date: 1359083515,
-                edited: false,
-                reply: Some(m1_uuid),
-            },
-        )
+    #[test]
+    fn delete_channel_with_thread_messages() {
+        let (s, m1, _, m3, c1, _, u1, u2) = init_with_msgs(true);
+        let t = test_thread(&m1, &u1);
+        s.insert_thread(&t).unwrap();
+        let reply = Message {
+            uuid: gen_uuid(),
+            channel_uuid: t.uuid,
+            author_uuid: u2.uuid,
+            date: 1359083521,
+            ..m3
+        };
+        s.add_to_history(&reply).unwrap();
+        s.add_reaction(reply.uuid, u1.uuid, &ReactionEmoji::Unicode("👍".into()))
+            .unwrap();
+        s.pin_message(&reply, u1.uuid, 1359083522).unwrap();
+        s.insert_mentions(reply.uuid, &[u1.uuid]).unwrap();
+
+        s.delete_channel(c1.uuid).unwrap();
+        assert!(!s.message_exists(&reply.uuid).unwrap());
+        assert!(s.get_reactions(reply.uuid).unwrap().is_empty());
+        // these join on the messages, so check the rows themselves are gone too
+        for table in ["pins", "mentions"].iter() {
+            let count: i64 = s
+                .conn
+                .query_row(&format!("select count(*) from {}", table), [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(count, 0, "{}", table);
+        }
     }
 
-    fn init_with_users() -> (Shared, User, User) {
-        let s = init();
-        let (u1, u2) = test_users();
-        s.insert_user(&u1).unwrap();
-        s.insert_user(&u2).unwrap();
-        (s, u1, u2)
+    #[test]
+    fn pins() {
+        let (s, m1, m2, m3, c1, c2, u1, _) = init_with_msgs(true);
+        assert!(s.get_pins(c1.uuid).unwrap().is_empty());
+        assert_eq!(s.pin_message(&m1, u1.uuid, 10).unwrap(), 1);
+        assert_eq!(s.pin_message(&m3, u1.uuid, 20).unwrap(), 1);
+        assert_eq!(s.pin_message(&m2, u1.uuid, 30).unwrap(), 1);
+        assert_eq!(s.pin_message(&m1, u1.uuid, 40).unwrap(), 0);
+
+        assert_eq!(s.get_pins(c1.uuid).unwrap(), vec![m3.clone(), m1.clone()]);
+        assert_eq!(s.get_pins(c2.uuid).unwrap(), vec![m2.clone()]);
+        assert_eq!(s.count_pins(c1.uuid).unwrap(), 2);
+
+        assert_eq!(s.unpin_message(m3.uuid).unwrap(), 1);
+        assert_eq!(s.unpin_message(m3.uuid).unwrap(), 0);
+        assert_eq!(s.get_pins(c1.uuid).unwrap(), vec![m1.clone()]);
+
+        s.delete_message(m1.uuid).unwrap();
+        assert!(s.get_pins(c1.uuid).unwrap().is_empty());
     }
 
     #[test]
-    fn insert_user() {
-        let s = init();
-        let (u1, u2) = test_users();
-        s.insert_user(&u1).unwrap();
-        s.insert_user(&u2).unwrap();
+    fn mentions() {
+        let (s, m1, m2, m3, _, _, u1, u2) = init_with_msgs(true);
+        s.insert_mentions(m1.uuid, &[u1.uuid, u2.uuid]).unwrap();
+        s.insert_mentions(m2.uuid, &[u2.uuid]).unwrap();
+        s.insert_mentions(m3.uuid, &[u2.uuid, u2.uuid]).unwrap();
+
+        assert_eq!(s.get_mentions(u1.uuid, 10, None).unwrap(), vec![m1.clone()]);
+        assert_eq!(
+            s.get_mentions(u2.uuid, 10, None).unwrap(),
+            vec![m3.clone(), m2.clone(), m1.clone()]
+        );
+        assert_eq!(
+            s.get_mentions(u2.uuid, 1, Some(m3.uuid)).unwrap(),
+            vec![m2.clone()]
+        );
+
+        s.soft_delete_message(m2.uuid, u1.uuid, 10).unwrap();
+        s.delete_message(m3.uuid).unwrap();
+        assert_eq!(s.get_mentions(u2.uuid, 10, None).unwrap(), vec![m1.clone()]);
     }
 
     #[test]
-    fn get_user_by_name() {
-        let (s, u1, _) = init_with_users();
-        let user_1_query = s.get_user_by_name(&u1.name).unwrap().unwrap();
-        assert_eq!(user_1_query.uuid, u1.uuid);
-        assert_eq!(user_1_query.name, u1.name);
+    fn notify_settings() {
+        let (s, _, _, _, c1, c2, u1, u2) = init_with_msgs(true);
+        let default = s.get_channel_notify_settings(u1.uuid, c1.uuid).unwrap();
+        assert_eq!(default.level, NotifyLevel::All);
+        assert!(!default.is_muted(0));
+
+        let mentions_only = NotifySettings {
+            channel_uuid: c1.uuid,
+            level: NotifyLevel::Mentions,
+            muted_until: None,
+        };
+        let muted = NotifySettings {
+            channel_uuid: c2.uuid,
+            level: NotifyLevel::All,
+            muted_until: Some(100),
+        };
+        s.set_notify_settings(u1.uuid, &mentions_only).unwrap();
+        s.set_notify_settings(u1.uuid, &muted).unwrap();
+        let mut settings = s.get_notify_settings(u1.uuid).unwrap();
+        settings.sort_unstable_by_key(|n| n.channel_uuid == c2.uuid);
+        assert_eq!(settings, vec![mentions_only.clone(), muted.clone()]);
+        assert!(s.get_notify_settings(u2.uuid).unwrap().is_empty());
+
+        assert!(!mentions_only.is_muted(0));
+        assert!(muted.is_muted(99));
+        assert!(!muted.is_muted(100));
+
+        // going back to the defaults forgets the settings
+        s.set_notify_settings(
+            u1.uuid,
+            &NotifySettings {
+                channel_uuid: c1.uuid,
+                level: NotifyLevel::All,
+                muted_until: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(s.get_notify_settings(u1.uuid).unwrap(), vec![muted]);
     }
 
     #[test]
-    fn get_nonexistant_user_by_name() {
-        let (s, _, _) = init_with_users();
-        let user_1_query = s.get_user_by_name("I am a nonexistant user").unwrap();
-        assert!(user_1_query.is_none());
+    fn channel_settings() {
+        let (s, m1, _, m3, mut c1, _, u1, u2) = init_with_msgs(true);
+        assert_eq!(
+            s.last_message_date(u1.uuid, c1.uuid).unwrap(),
+            Some(m1.date as i64)
+        );
+        assert_eq!(
+            s.last_message_date(u2.uuid, c1.uuid).unwrap(),
+            Some(m3.date as i64)
+        );
+        assert_eq!(s.last_message_date(u1.uuid, gen_uuid()).unwrap(), None);
+
+        c1.topic = Some("cat pictures only".into());
+        c1.nsfw = true;
+        c1.archived = true;
+        c1.slow_mode = 30;
+        s.update_channel(&c1).unwrap();
+        assert_eq!(s.get_channel(&c1.uuid).unwrap(), Some(c1));
     }
 
     #[test]
-    fn get_user_by_uuid() {
-        let (s, u1, _) = init_with_users();
-        let user_1_query = s.get_user(u1.uuid).unwrap().unwrap();
-        assert_eq!(user_1_query.uuid, u1.uuid);
-        assert_eq!(user_1_query.name, u1.name);
+    fn channel_follows() {
+        let (s, m1, m2, _, c1, c2, _, _) = init_with_msgs(true);
+        let (c3, _) = test_channels();
+        s.insert_channel(&c3).unwrap();
+        assert_eq!(s.follow_channel(c1.uuid, c2.uuid).unwrap(), 1);
+        assert_eq!(s.follow_channel(c1.uuid, c3.uuid).unwrap(), 1);
+        assert_eq!(s.follow_channel(c1.uuid, c2.uuid).unwrap(), 0);
+        let mut followers = s.get_followers(c1.uuid).unwrap();
+        followers.sort_unstable();
+        let mut expected = vec![c2.uuid, c3.uuid];
+        expected.sort_unstable();
+        assert_eq!(followers, expected);
+        assert!(s.get_followers(c2.uuid).unwrap().is_empty());
+
+        assert_eq!(s.unfollow_channel(c1.uuid, c2.uuid).unwrap(), 1);
+        assert_eq!(s.unfollow_channel(c1.uuid, c2.uuid).unwrap(), 0);
+        s.delete_channel(c3.uuid).unwrap();
+        assert!(s.get_followers(c1.uuid).unwrap().is_empty());
+
+        assert!(!s.is_published(m1.uuid).unwrap());
+        s.insert_published(m2.uuid, m1.uuid).unwrap();
+        assert!(s.is_published(m1.uuid).unwrap());
+        assert!(!s.is_published(m2.uuid).unwrap());
     }
 
     #[test]
-    fn get_nonexistant_user_by_uuid() {
-        let (s, _, _) = init_with_users();
+    fn scheduled_messages() {
+        let (s, _, _, _, c1, c2, u1, u2) = init_with_msgs(true);
+        let scheduled = |author: &User, channel: &Channel, send_at| ScheduledMessage {
+            uuid: gen_uuid(),
+            content: "later".into(),
+            author_uuid: author.uuid,
+            channel_uuid: channel.uuid,
+            reply: None,
+            send_at,
+        };
+        let s1 = scheduled(&u1, &c1, 30);
+        let s2 = scheduled(&u1, &c2, 10);
+        let s3 = scheduled(&u2, &c1, 20);
+        for msg in [&s1, &s2, &s3].iter() {
+            s.insert_scheduled(msg).unwrap();
+        }
 
-        let user_1_query = s.get_user(gen_uuid());
-        assert!(user_1_query.is_ok());
-        let user_1_query = user_1_query.unwrap();
-        assert!(user_1_query.is_none());
+        assert_eq!(
+            s.get_scheduled_of(u1.uuid).unwrap(),
+            vec![s2.clone(), s1.clone()]
+        );
+        assert_eq!(s.get_scheduled(s3.uuid).unwrap(), Some(s3.clone()));
+        assert!(s.get_due_scheduled(5).unwrap().is_empty());
+        assert_eq!(
+            s.get_due_scheduled(20).unwrap(),
+            vec![s2.clone(), s3.clone()]
+        );
+        // they stay scheduled until they're deleted
+        assert_eq!(s.get_scheduled(s3.uuid).unwrap(), Some(s3.clone()));
+
+        for msg in [&s1, &s2, &s3].iter() {
+            assert_eq!(s.delete_scheduled(msg.uuid).unwrap(), 1);
+        }
+        assert!(s.get_due_scheduled(100).unwrap().is_empty());
     }
 
     #[test]
-    fn list_users() {
+    fn categories() {
+        let (s, _, u2) = init_with_users();
+        let (mut c1, mut c2) = test_channels();
+        let mut cat = Category {
+            uuid: gen_uuid(),
+            name: "text".into(),
+            position: 0,
+            permissions: HashMap::new(),
+        };
+        cat.permissions.insert(
+            PermableEntity::User(u2.uuid),
+            Permissions {
+                read_messages: Perm::Deny,
+                ..Default::default()
+            },
+        );
+        s.insert_category(&cat).unwrap();
+        assert_eq!(s.get_category(cat.uuid).unwrap(), Some(cat.clone()));
+
+        c1.category = Some(cat.uuid);
+        c2.category = Some(cat.uuid);
+        c2.permissions.insert(
+            PermableEntity::User(u2.uuid),
+            Permissions {
+                read_messages: Perm::Allow,
+                ..Default::default()
+            },
+        );
+        s.insert_channel(&c1).unwrap();
+        s.insert_channel(&c2).unwrap();
+        assert_eq!(s.get_channel(&c1.uuid).unwrap(), Some(c1.clone()));
+
+        // channels inherit the category's overrides, unless they override them again
+        let perms = |c: &Channel| s.resolve_channel_permissions(&u2, c).unwrap().read_messages;
+        assert_eq!(perms(&c1), Perm::Deny);
+        assert_eq!(perms(&c2), Perm::Allow);
+        assert_eq!(
+            s.resolve_category_permissions(&u2, &cat)
+                .unwrap()
+                .read_messages,
+            Perm::Deny
+        );
+
+        cat.name = "renamed".into();
+        cat.permissions.clear();
+        s.update_category(&cat).unwrap();
+        assert_eq!(s.get_categories().unwrap(), vec![cat.clone()]);
+        assert_eq!(perms(&c1), Perm::Allow);
+
+        s.delete_category(cat.uuid).unwrap();
+        assert!(s.get_categories().unwrap().is_empty());
+        assert_eq!(s.get_channel(&c1.uuid).unwrap().unwrap().category, None);
+    }
+
+    fn test_audit_entry(actor: &User, action: AuditAction, target: Option<Uuid>) -> AuditLogEntry {
+        AuditLogEntry {
+            id: 0,
+            date: 1359083600,
+            actor_uuid: actor.uuid,
+            action,
+            target,
+            before: Some(serde_json::json!({"name": "before"})),
+            after: None,
+        }
+    }
+
+    #[test]
+    fn audit_log() {
         let (s, u1, u2) = init_with_users();
+        let e1 = test_audit_entry(&u1, AuditAction::Ban, Some(u2.uuid));
+        let e2 = test_audit_entry(&u2, AuditAction::CreateChannel, None);
+        let e3 = test_audit_entry(&u1, AuditAction::Kick, Some(u2.uuid));
+        let ids: Vec<_> = [&e1, &e2, &e3]
+            .iter()
+            .map(|e| s.insert_audit_log(e).unwrap())
+            .collect();
+        let with_id = |e: &AuditLogEntry, id| AuditLogEntry { id, ..e.clone() };
+        let all = AuditLogFilter::default();
 
-        let users = s.get_users();
-        assert!(users.is_ok());
-        let users = users.unwrap();
-        assert_eq!(users.len(), 2);
-        assert_eq!(users[0].uuid, u1.uuid);
-        assert_eq!(users[1].uuid, u2.uuid);
+        assert_eq!(
+            s.get_audit_log(&all, 50, None).unwrap(),
+            vec![
+                with_id(&e3, ids[2]),
+                with_id(&e2, ids[1]),
+                with_id(&e1, ids[0])
+            ]
+        );
+        assert_eq!(
+            s.get_audit_log(&all, 1, Some(ids[2])).unwrap(),
+            vec![with_id(&e2, ids[1])]
+        );
+
+        let by_u1 = AuditLogFilter {
+            actor: Some(u1.uuid),
+            ..Default::default()
+        };
+        assert_eq!(s.get_audit_log(&by_u1, 50, None).unwrap().len(), 2);
+        let bans = AuditLogFilter {
+            action: Some(AuditAction::Ban),
+            target: Some(u2.uuid),
+            ..Default::default()
+        };
+        assert_eq!(
+            s.get_audit_log(&bans, 50, None).unwrap(),
+            vec![with_id(&e1, ids[0])]
+        );
     }
 
     #[test]
-    fn update_user() {
+    fn audit_log_append_only() {
         let (s, u1, _) = init_with_users();
-        assert_eq!(u1.name, "Test user");
-        let new_u1 = User {
-            uuid: u1.uuid,
-            name: "Test user updated".into(),
-            pfp: "pfp2".into(),
-            password: "abcde".into(),
-            groups: Vec::new(),
-        };
-        s.update_user(&new_u1).unwrap();
-        let user_1_query = s.get_user(u1.uuid);
-        assert!(user_1_query.is_ok());
-        let user_1_query = user_1_query.unwrap();
-        assert!(user_1_query.is_some());
-        assert_eq!(user_1_query.unwrap(), new_u1);
+        s.insert_audit_log(&test_audit_entry(&u1, AuditAction::Kick, None))
+            .unwrap();
+        assert!(s.conn.execute("delete from audit_log", []).is_err());
+        assert!(s
+            .conn
+            .execute("update audit_log set actor_uuid = 0", [])
+            .is_err());
+        assert_eq!(
+            s.get_audit_log(&AuditLogFilter::default(), 50, None)
+                .unwrap()
+                .len(),
+            1
+        );
     }
 
     #[test]
-    fn initial_channel_is_general() {
-        let s = init();
-        let chans = s.get_channels();
-        assert!(chans.is_ok());
-        let chans = chans.unwrap();
-        assert_eq!(chans.len(), 1);
-        assert_eq!(chans[0].name, "general");
+    fn typing() {
+        let mut s = init();
+        let (c1, c2, u1, u2) = (1, 2, 3, 4);
+        s.set_typing(c1, u1, 10);
+        s.set_typing(c2, u1, 20);
+        s.set_typing(c1, u2, 30);
+
+        let mut expired = s.take_expired_typing(20);
+        expired.sort_unstable();
+        assert_eq!(expired, vec![(c1, u1), (c2, u1)]);
+        assert!(s.take_expired_typing(20).is_empty());
+
+        // typing again pushes back the expiry
+        s.set_typing(c1, u2, 40);
+        assert!(s.take_expired_typing(30).is_empty());
+
+        s.set_typing(c2, u2, 40);
+        assert!(s.stop_typing(c2, u2));
+        assert!(!s.stop_typing(c2, u2));
+        assert_eq!(s.take_typing_of(u2), vec![c1]);
+        assert!(s.typing.is_empty());
     }
 
     #[test]
-    fn insert_channels() {
-        let s = init();
-        let (c1, c2) = test_channels();
-        assert!(s.insert_channel(&c1).is_ok());
-        assert!(s.insert_channel(&c2).is_ok());
+    fn login_backoff() {
+        let mut s = init();
+        let addr = LoginKey::Addr("192.168.0.1".parse().unwrap());
+        let account = LoginKey::Account(1);
+        let base = CONF.login_backoff_base;
+
+        assert!(!s.login_blocked(&[addr, account], 1000));
+        s.record_login_failure(&[addr, account], 1000);
+        assert!(s.login_blocked(&[account], 1000 + base - 1));
+        assert!(!s.login_blocked(&[addr, account], 1000 + base));
+
+        // the second failure doubles the wait
+        s.record_login_failure(&[addr], 1000 + base);
+        assert!(s.login_blocked(&[addr], 1000 + 3 * base - 1));
+        assert!(!s.login_blocked(&[addr], 1000 + 3 * base));
+        assert!(!s.login_blocked(&[account], 1000 + 3 * base));
+
+        s.clear_login_failures(addr);
+        assert!(!s.login_blocked(&[addr], 1000 + base));
     }
 
     #[test]
-    fn get_channel_by_uuid() {
-        let s = init();
-        let (c1, c2) = test_channels();
-        assert!(s.insert_channel(&c1).is_ok());
-        assert!(s.insert_channel(&c2).is_ok());
+    fn login_lockout() {
+        let mut s = init();
+        let account = LoginKey::Account(1);
+        let window = CONF.login_lockout_duration;
+        for _ in 0..CONF.login_attempts_before_lockout {
+            s.record_login_failure(&[account], 1000);
+        }
+        assert!(s.login_blocked(&[account], 1000 + window - 1));
+        assert!(!s.login_blocked(&[account], 1000 + window));
 
-        let c1_q = s.get_channel(&c1.uuid);
-        assert!(c1_q.is_ok());
-        let c1_q = c1_q.unwrap();
-        assert!(c1_q.is_some());
-        let c1_q = c1_q.unwrap();
-        assert_eq!(c1_q.uuid, c1.uuid);
-        assert_eq!(c1_q.name, c1.name);
+        // the failures have been forgotten by the time the lockout ends
+        s.record_login_failure(&[account], 1000 + window);
+        assert_eq!(s.login_failures[&account].count, 1);
     }
 
     #[test]
-    fn get_channel_by_name() {
-        let s = init();
-        let (c1, c2) = test_channels();
-        assert!(s.insert_channel(&c1).is_ok());
-        assert!(s.insert_channel(&c2).is_ok());
+    fn user_status() {
+        let (s, u1, u2) = init_with_users();
+        assert_eq!(
+            s.get_user(u1.uuid).unwrap().unwrap().status,
+            UserStatus::Online
+        );
 
-        let c1_q = s.get_channel_by_name(&c1.name);
-        assert!(c1_q.is_ok());
-        let c1_q = c1_q.unwrap();
-        assert!(c1_q.is_some());
-        let c1_q = c1_q.unwrap();
-        assert_eq!(c1_q.uuid, c1.uuid);
-        assert_eq!(c1_q.name, c1.name);
+        s.set_status(u1.uuid, UserStatus::DoNotDisturb, Some("busy"))
+            .unwrap();
+        let user = s.get_user(u1.uuid).unwrap().unwrap();
+        assert_eq!(user.status, UserStatus::DoNotDisturb);
+        assert_eq!(user.status_text.as_deref(), Some("busy"));
+        assert_eq!(
+            s.get_presence(u1.uuid).unwrap(),
+            Some(Presence {
+                uuid: u1.uuid,
+                status: UserStatus::DoNotDisturb,
+                status_text: Some("busy".into()),
+            })
+        );
+
+        // only the user themselves can see that they're invisible, or what their status text is
+        s.set_status(u2.uuid, UserStatus::Invisible, Some("hiding"))
+            .unwrap();
+        let user = s.get_user_by_name(&u2.name).unwrap().unwrap();
+        assert_eq!(user.status_text.as_deref(), Some("hiding"));
+        let own = user.clone().seen_by(Some(u2.uuid));
+        assert_eq!(own.status, UserStatus::Invisible);
+        assert_eq!(own.status_text.as_deref(), Some("hiding"));
+        let other = user.clone().seen_by(Some(u1.uuid));
+        assert_eq!(other.status, UserStatus::Online);
+        assert_eq!(other.status_text, None);
+        let anon = user.seen_by(None);
+        assert_eq!(anon.status, UserStatus::Online);
+        assert_eq!(anon.status_text, None);
+
+        assert_eq!(s.get_presence(gen_uuid()).unwrap(), None);
     }
 
     #[test]
-    fn get_nonexistant_channel_by_uuid() {
-        let s = init();
-        let (c1, c2) = test_channels();
-        assert!(s.insert_channel(&c1).is_ok());
-        assert!(s.insert_channel(&c2).is_ok());
+    fn manage_emoji() {
+        let (s, m1, _, _, _, _, u1, _) = init_with_msgs(true);
+        let e1 = Emoji::new(gen_uuid(), "party".into(), "data1".into());
+        let e2 = Emoji::new(gen_uuid(), "sad".into(), "data2".into());
+        s.insert_emoji(&e1).unwrap();
+        s.insert_emoji(&e2).unwrap();
+        assert_eq!(s.get_emoji(e1.uuid).unwrap(), Some(e1.clone()));
+        assert_eq!(s.get_emoji_by_name("sad").unwrap(), Some(e2.clone()));
+        assert_eq!(s.list_emoji().unwrap().len(), 2);
+
+        assert_eq!(s.rename_emoji(e1.uuid, "celebrate").unwrap(), 1);
+        assert!(s.get_emoji_by_name("party").unwrap().is_none());
+        assert_eq!(s.get_emoji(e1.uuid).unwrap().unwrap().name, "celebrate");
+
+        let thumbs = ReactionEmoji::Unicode("👍".into());
+        s.add_reaction(m1.uuid, u1.uuid, &ReactionEmoji::Custom(e2.uuid))
+            .unwrap();
+        s.add_reaction(m1.uuid, u1.uuid, &thumbs).unwrap();
+        assert_eq!(s.delete_emoji(e2.uuid).unwrap(), 1);
+        assert!(s.get_emoji(e2.uuid).unwrap().is_none());
+        // reactions with the deleted emoji go with it, but others are kept
+        assert_eq!(
+            s.get_reactions(m1.uuid).unwrap(),
+            vec![ReactionCount {
+                emoji: thumbs,
+                count: 1
+            }]
+        );
+        assert_eq!(s.delete_emoji(e2.uuid).unwrap(), 0);
+        assert_eq!(
+            s.list_emoji().unwrap(),
+            vec![("celebrate".to_owned(), e1.uuid)]
+        );
+    }
 
-        let c1_q = s.get_channel(&gen_uuid());
-        assert!(c1_q.is_ok());
-        let c1_q = c1_q.unwrap();
-        assert!(c1_q.is_none());
+    fn test_ban(u1: &User, u2: &User, expires: Option<i64>) -> Ban {
+        Ban {
+            user_uuid: u1.uuid,
+            issuer_uuid: u2.uuid,
+            reason: "spam".into(),
+            date: 1000,
+            expires,
+        }
     }
 
     #[test]
-    fn get_nonexistant_channel_by_name() {
-        let s = init();
-        let (c1, c2) = test_channels();
-        assert!(s.insert_channel(&c1).is_ok());
-        assert!(s.insert_channel(&c2).is_ok());
-
-        let c1_q = s.get_channel_by_name("this channel does not exist");
-        assert!(c1_q.is_ok());
-        let c1_q = c1_q.unwrap();
-        assert!(c1_q.is_none());
+    fn ban_user() {
+        let (s, u1, u2) = init_with_users();
+        let ban = test_ban(&u1, &u2, None);
+        s.insert_ban(&ban).unwrap();
+        assert_eq!(s.get_ban(u1.uuid, 2000).unwrap(), Some(ban.clone()));
+        assert!(s.get_ban(u2.uuid, 2000).unwrap().is_none());
+        assert_eq!(s.get_bans(2000).unwrap(), vec![ban]);
     }
 
     #[test]
-    fn channel_exists() {
-        let s = init();
-        let c1 = Channel {
-            name: "general-16.35".into(),
-            uuid: gen_uuid(),
-            position: 0,
-            permissions: HashMap::new(),
-        };
+    fn ban_expires() {
+        let (s, u1, u2) = init_with_users();
+        s.insert_ban(&test_ban(&u1, &u2, Some(1500))).unwrap();
+        assert!(s.get_ban(u1.uuid, 1200).unwrap().is_some());
+        assert!(s.get_ban(u1.uuid, 1500).unwrap().is_none());
+        assert!(s.get_bans(1600).unwrap().is_empty());
+    }
 
-        assert!(s.insert_channel(&c1).is_ok());
-        assert!(s.channel_exists(&c1.uuid).is_ok());
-        assert_eq!(s.channel_exists(&c1.uuid).unwrap(), true);
+    #[test]
+    fn unban_user() {
+        let (s, u1, u2) = init_with_users();
+        let addr: IpAddr = "192.168.0.1".parse().unwrap();
+        s.insert_ban(&test_ban(&u1, &u2, None)).unwrap();
+        s.insert_ban_addr(u1.uuid, &addr).unwrap();
+        assert_eq!(s.delete_ban(u1.uuid).unwrap(), 1);
+        assert!(s.get_ban(u1.uuid, 2000).unwrap().is_none());
+        assert!(!s.is_addr_banned(&addr, 2000).unwrap());
+        assert_eq!(s.delete_ban(u1.uuid).unwrap(), 0);
     }
 
     #[test]
-    fn channel_doesnt_exist() {
-        let s = init();
-        let c1 = Channel {
-            name: "general-16.35".into(),
-            uuid: gen_uuid(),
-            position: 0,
-            permissions: HashMap::new(),
-        };
+    fn banned_addr() {
+        let (s, u1, u2) = init_with_users();
+        let addr: IpAddr = "192.168.0.1".parse().unwrap();
+        let other: IpAddr = "::1".parse().unwrap();
+        s.insert_ban(&test_ban(&u1, &u2, Some(1500))).unwrap();
+        s.insert_ban_addr(u1.uuid, &addr).unwrap();
+        assert!(s.is_addr_banned(&addr, 1200).unwrap());
+
+        // banning again from the same address doesn't pile up rows
+        s.insert_ban(&test_ban(&u1, &u2, Some(1500))).unwrap();
+        s.insert_ban_addr(u1.uuid, &addr).unwrap();
+        let count: i64 = s
+            .conn
+            .query_row("select count(*) from ban_addrs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
 
-        let not_existing_uuid = gen_uuid();
-        assert!(s.insert_channel(&c1).is_ok());
-        assert!(s.channel_exists(&not_existing_uuid).is_ok());
-        assert_eq!(s.channel_exists(&not_existing_uuid).unwrap(), false);
+        assert!(!s.is_addr_banned(&other, 1200).unwrap());
+        assert!(!s.is_addr_banned(&addr, 1600).unwrap());
     }
 
-    #[test]
-    fn duplicate_channel() {
-        let s = init();
-        let c1 = Channel {
-            name: "general-16.35".into(),
+    fn test_session(user: &User, last_used: i64) -> Session {
+        Session {
             uuid: gen_uuid(),
-            position: 0,
-            permissions: HashMap::new(),
-        };
-        assert!(s.insert_channel(&c1).is_ok());
-        assert!(s.insert_channel(&c1).is_err());
+            user_uuid: user.uuid,
+            created: 1000,
+            expires: 2000,
+            last_used,
+        }
     }
 
     #[test]
-    fn delete_channel() {
-        let s = init();
-        let c1 = Channel {
-            name: "general-16.35".into(),
-            uuid: gen_uuid(),
-            position: 0,
-            permissions: HashMap::new(),
-        };
-        assert!(s.insert_channel(&c1).is_ok());
-        assert!(s.delete_channel(c1.uuid).is_ok());
-        assert!(s.get_channel(&c1.uuid).is_ok_and(|c| c.is_none()));
+    fn sessions() {
+        let (s, u1, u2) = init_with_users();
+        let a = test_session(&u1, 1000);
+        let b = test_session(&u1, 1100);
+        s.insert_session(&a, "aaaa").unwrap();
+        s.insert_session(&b, "bbbb").unwrap();
+        s.insert_session(&test_session(&u2, 1000), "cccc").unwrap();
+
+        assert_eq!(
+            s.get_session_by_token("aaaa", 1200).unwrap(),
+            Some(a.clone())
+        );
+        assert!(s.get_session_by_token("dddd", 1200).unwrap().is_none());
+        assert_eq!(
+            s.get_sessions_of(u1.uuid, 1200).unwrap(),
+            vec![b.clone(), a.clone()]
+        );
+
+        assert_eq!(s.touch_session(a.uuid, 1300).unwrap(), 1);
+        assert_eq!(s.get_sessions_of(u1.uuid, 1300).unwrap()[0].uuid, a.uuid);
+
+        assert_eq!(s.delete_session(b.uuid).unwrap(), 1);
+        assert!(s.get_session_by_token("bbbb", 1300).unwrap().is_none());
+        assert_eq!(s.delete_sessions_of(u1.uuid).unwrap(), 1);
+        assert!(s.get_sessions_of(u1.uuid, 1300).unwrap().is_empty());
+        assert_eq!(s.get_sessions_of(u2.uuid, 1300).unwrap().len(), 1);
     }
+
     #[test]
-    fn update_channel() {
-        let s = init();
-        let c1 = Channel {
-            name: "general-16.35".into(),
-            uuid: gen_uuid(),
-            position: 1,
-            permissions: HashMap::new(),
-        };
-        assert!(s.insert_channel(&c1).is_ok());
-        let c2 = Channel {
-            name: "random-shit".into(),
-            uuid: c1.uuid,
-            position: 1,
-            permissions: HashMap::new(),
-        };
-        assert!(s.update_channel(&c2).is_ok());
-        assert!(s.get_channel(&c1.uuid).is_ok());
-        assert!(s.get_channel(&c1.uuid).unwrap().is_some());
-        assert_eq!(s.get_channel(&c1.uuid).unwrap().unwrap(), c2);
+    fn totp() {
+        let (s, u1, u2) = init_with_users();
+        assert!(s.get_totp(u1.uuid).unwrap().is_none());
+        s.set_totp_secret(u1.uuid, "AAAA").unwrap();
+        s.set_totp_secret(u1.uuid, "BBBB").unwrap();
+        let totp = s.get_totp(u1.uuid).unwrap().unwrap();
+        assert_eq!(totp.secret, "BBBB");
+        assert!(!totp.enabled);
+
+        assert_eq!(s.enable_totp(u1.uuid, 5).unwrap(), 1);
+        assert_eq!(s.set_totp_last_step(u1.uuid, 6).unwrap(), 1);
+        let totp = s.get_totp(u1.uuid).unwrap().unwrap();
+        assert!(totp.enabled);
+        assert_eq!(totp.last_step, 6);
+
+        let codes = vec!["one".to_string(), "two".to_string()];
+        s.set_recovery_codes(u1.uuid, &codes).unwrap();
+        s.set_recovery_codes(u2.uuid, &codes).unwrap();
+        assert_eq!(s.delete_recovery_code(u1.uuid, "one").unwrap(), 1);
+        assert_eq!(
+            s.get_recovery_codes(u1.uuid).unwrap(),
+            vec!["two".to_string()]
+        );
+
+        assert_eq!(s.delete_totp(u1.uuid).unwrap(), 1);
+        assert!(s.get_totp(u1.uuid).unwrap().is_none());
+        assert!(s.get_recovery_codes(u1.uuid).unwrap().is_empty());
+        assert_eq!(s.get_recovery_codes(u2.uuid).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn session_expires() {
+        let (s, u1, _) = init_with_users();
+        s.insert_session(&test_session(&u1, 1000), "aaaa").unwrap();
+        assert!(s.get_session_by_token("aaaa", 1999).unwrap().is_some());
+        assert!(s.get_session_by_token("aaaa", 2000).unwrap().is_none());
+        assert!(s.get_sessions_of(u1.uuid, 2000).unwrap().is_empty());
+        assert_eq!(s.delete_expired_sessions(1999).unwrap(), 0);
+        assert_eq!(s.delete_expired_sessions(2000).unwrap(), 1);
     }
 
     fn init_with_msgs(
@@ -1804,7 +4753,7 @@ mod tests {
     fn message_exists() {
         let (s, _, m2, _, _, _, _, _) = init_with_msgs(true);
         assert!(s.message_exists(&m2.uuid).is_ok());
-        assert_eq!(s.message_exists(&m2.uuid).unwrap(), true);
+        assert!(s.message_exists(&m2.uuid).unwrap());
     }
 
     #[test]
@@ -1812,7 +4761,7 @@ mod tests {
         let (s, _, _, _, _, _, _, _) = init_with_msgs(true);
         let not_existing_uuid = gen_uuid();
         assert!(s.message_exists(&not_existing_uuid).is_ok());
-        assert_eq!(s.message_exists(&not_existing_uuid).unwrap(), false);
+        assert!(!s.message_exists(&not_existing_uuid).unwrap());
     }
 
     #[test]
@@ -1839,10 +4788,39 @@ mod tests {
     fn edit_message() {
         let (s, _, m2, _, _, _, _, _) = init_with_msgs(true);
         assert_eq!(m2.content, "Goodbye world");
-        assert!(s.edit_message(m2.uuid, "Hello world").is_ok());
+        assert!(s.edit_message(m2.uuid, "Hello world", 1359083600).is_ok());
         let mq = s.get_message(m2.uuid).unwrap().unwrap();
         assert_eq!(mq.content, "Hello world");
-        assert_eq!(mq.edited, true);
+        assert!(mq.edited);
+        assert_eq!(mq.edited_at, Some(1359083600));
+    }
+
+    #[test]
+    fn edit_history() {
+        let (s, m1, m2, _, _, _, _, _) = init_with_msgs(true);
+        let rev = |content: &str, date: i64| MessageRevision {
+            content: content.into(),
+            date,
+        };
+        assert_eq!(
+            s.get_edit_history(m1.uuid).unwrap(),
+            vec![rev("Hello world", m1.date.into())]
+        );
+        s.edit_message(m1.uuid, "Hello there", 1359083600).unwrap();
+        s.edit_message(m1.uuid, "Hello again", 1359083700).unwrap();
+        assert_eq!(
+            s.get_edit_history(m1.uuid).unwrap(),
+            vec![
+                rev("Hello world", m1.date.into()),
+                rev("Hello there", 1359083600),
+                rev("Hello again", 1359083700),
+            ]
+        );
+        // other messages are unaffected
+        assert_eq!(s.get_edit_history(m2.uuid).unwrap().len(), 1);
+
+        s.delete_message(m1.uuid).unwrap();
+        assert!(s.get_edit_history(m1.uuid).unwrap().is_empty());
     }
 
     #[test]
@@ -1853,6 +4831,301 @@ mod tests {
         assert!(s.get_message(m2.uuid).is_ok_and(|o| o.is_none()));
     }
 
+    #[test]
+    fn reactions() {
+        let (s, m1, _, _, _, _, u1, u2) = init_with_msgs(true);
+        let thumbs = ReactionEmoji::Unicode("👍".into());
+        let custom = ReactionEmoji::Custom(gen_uuid());
+        assert_eq!(s.add_reaction(m1.uuid, u1.uuid, &thumbs).unwrap(), 1);
+        assert_eq!(s.add_reaction(m1.uuid, u1.uuid, &thumbs).unwrap(), 0);
+        assert_eq!(s.add_reaction(m1.uuid, u2.uuid, &custom).unwrap(), 1);
+        assert_eq!(s.add_reaction(m1.uuid, u2.uuid, &thumbs).unwrap(), 1);
+
+        let counts = s.get_message(m1.uuid).unwrap().unwrap().reactions;
+        assert_eq!(
+            counts,
+            vec![
+                ReactionCount {
+                    emoji: thumbs.clone(),
+                    count: 2
+                },
+                ReactionCount {
+                    emoji: custom.clone(),
+                    count: 1
+                },
+            ]
+        );
+
+        assert_eq!(s.remove_reaction(m1.uuid, u1.uuid, &thumbs).unwrap(), 1);
+        assert_eq!(s.remove_reaction(m1.uuid, u1.uuid, &thumbs).unwrap(), 0);
+        assert_eq!(s.remove_reaction(m1.uuid, u1.uuid, &custom).unwrap(), 0);
+        assert_eq!(s.get_reactions(m1.uuid).unwrap()[0].count, 1);
+    }
+
+    fn test_attachment(u1: &User, hash: &str) -> Attachment {
+        Attachment {
+            uuid: gen_uuid(),
+            name: "file.txt".into(),
+            mime: "text/plain".into(),
+            size: 11,
+            hash: hash.into(),
+            uploader_uuid: u1.uuid,
+            message_uuid: None,
+            uploaded_at: 1000,
+        }
+    }
+
+    #[test]
+    fn blob_range() {
+        let s = init();
+        s.insert_blob("abc", b"hello world").unwrap();
+        s.insert_blob("abc", b"hello world").unwrap();
+        assert_eq!(s.get_blob_range("abc", 0, 5).unwrap().unwrap(), b"hello");
+        assert_eq!(s.get_blob_range("abc", 6, 100).unwrap().unwrap(), b"world");
+        assert!(s.get_blob_range("def", 0, 5).unwrap().is_none());
+    }
+
+    #[test]
+    fn attachments() {
+        let (s, m1, _, _, _, _, u1, _) = init_with_msgs(true);
+        s.insert_blob("abc", b"hello world").unwrap();
+        let a1 = test_attachment(&u1, "abc");
+        let a2 = test_attachment(&u1, "abc");
+        s.insert_attachment(&a1).unwrap();
+        s.insert_attachment(&a2).unwrap();
+        assert_eq!(s.get_attachment(a1.uuid).unwrap(), Some(a1.clone()));
+        assert!(s.get_attachments_of(m1.uuid).unwrap().is_empty());
+
+        assert_eq!(s.attach_to_message(a1.uuid, m1.uuid).unwrap(), 1);
+        let attached = s.get_message(m1.uuid).unwrap().unwrap().attachments;
+        assert_eq!(attached.len(), 1);
+        assert_eq!(attached[0].uuid, a1.uuid);
+        assert_eq!(attached[0].message_uuid, Some(m1.uuid));
+    }
+
+    #[test]
+    fn purge_unattached() {
+        let (s, m1, _, _, _, _, u1, u2) = init_with_msgs(true);
+        s.insert_blob("abc", b"hello world").unwrap();
+        s.insert_blob("def", b"goodbye world").unwrap();
+        let sent = test_attachment(&u1, "abc");
+        let old = test_attachment(&u1, "def");
+        let new = Attachment {
+            uploaded_at: 2000,
+            ..test_attachment(&u1, "abc")
+        };
+        for a in [&sent, &old, &new].iter() {
+            s.insert_attachment(a).unwrap();
+        }
+        s.attach_to_message(sent.uuid, m1.uuid).unwrap();
+        assert_eq!(s.get_unattached_size(u1.uuid).unwrap(), 22);
+        assert_eq!(s.get_unattached_size(u2.uuid).unwrap(), 0);
+
+        assert_eq!(s.purge_unattached(1500).unwrap(), 1);
+        assert!(s.get_attachment(old.uuid).unwrap().is_none());
+        assert!(s.get_attachment(sent.uuid).unwrap().is_some());
+        assert!(s.get_attachment(new.uuid).unwrap().is_some());
+        assert!(s.get_blob_range("def", 0, 5).unwrap().is_none());
+        assert!(s.get_blob_range("abc", 0, 5).unwrap().is_some());
+        assert_eq!(s.get_unattached_size(u1.uuid).unwrap(), 11);
+    }
+
+    #[test]
+    fn copy_attachments() {
+        let (s, m1, m2, _, _, _, u1, _) = init_with_msgs(true);
+        s.insert_blob("abc", b"hello world").unwrap();
+        let a1 = test_attachment(&u1, "abc");
+        s.insert_attachment(&a1).unwrap();
+        s.attach_to_message(a1.uuid, m1.uuid).unwrap();
+
+        let copies = s.copy_attachments(m1.uuid, m2.uuid).unwrap();
+        assert_eq!(copies.len(), 1);
+        assert_ne!(copies[0].uuid, a1.uuid);
+        assert_eq!(copies[0].hash, a1.hash);
+        assert_eq!(s.get_attachments_of(m2.uuid).unwrap(), copies);
+
+        // the data is kept until neither message uses it
+        s.delete_message(m1.uuid).unwrap();
+        assert!(s.get_blob_range("abc", 0, 5).unwrap().is_some());
+        s.delete_message(m2.uuid).unwrap();
+        assert!(s.get_blob_range("abc", 0, 5).unwrap().is_none());
+    }
+
+    #[test]
+    fn delete_message_with_attachment() {
+        let (s, m1, _, _, _, _, u1, _) = init_with_msgs(true);
+        s.insert_blob("abc", b"hello world").unwrap();
+        s.insert_blob("def", b"goodbye world").unwrap();
+        let a1 = test_attachment(&u1, "abc");
+        s.insert_attachment(&a1).unwrap();
+        s.attach_to_message(a1.uuid, m1.uuid).unwrap();
+
+        s.delete_message(m1.uuid).unwrap();
+        assert!(s.get_attachment(a1.uuid).unwrap().is_none());
+        assert!(s.get_blob_range("abc", 0, 5).unwrap().is_none());
+        assert!(s.get_blob_range("def", 0, 5).unwrap().is_none());
+    }
+
+    #[test]
+    fn search_messages() {
+        let (s, m1, m2, m3, c1, c2, u1, u2) = init_with_msgs(true);
+        let both = vec![c1.uuid, c2.uuid];
+        let search = |search: MessageSearch| s.search_messages(&search, 50, None).unwrap();
+
+        let res = search(MessageSearch {
+            query: "world".into(),
+            channels: both.clone(),
+            ..Default::default()
+        });
+        assert_eq!(res, vec![m2.clone(), m1.clone()]);
+
+        // only channels that are passed in are searched
+        let res = search(MessageSearch {
+            query: "world".into(),
+            channels: vec![c1.uuid],
+            ..Default::default()
+        });
+        assert_eq!(res, vec![m1.clone()]);
+        assert!(search(MessageSearch {
+            query: "world".into(),
+            ..Default::default()
+        })
+        .is_empty());
+
+        // every word has to match, and fts syntax is taken literally
+        let res = search(MessageSearch {
+            query: "GREATEST aster".into(),
+            channels: both.clone(),
+            ..Default::default()
+        });
+        assert_eq!(res, vec![m3.clone()]);
+        assert!(search(MessageSearch {
+            query: "hello OR goodbye".into(),
+            channels: both.clone(),
+            ..Default::default()
+        })
+        .is_empty());
+        assert!(search(MessageSearch {
+            query: "\"unterminated".into(),
+            channels: both.clone(),
+            ..Default::default()
+        })
+        .is_empty());
+
+        // filters without a query
+        let res = search(MessageSearch {
+            channels: both.clone(),
+            author: Some(u2.uuid),
+            ..Default::default()
+        });
+        assert_eq!(res, vec![m3.clone()]);
+        let res = search(MessageSearch {
+            channels: both.clone(),
+            has_reply: Some(false),
+            ..Default::default()
+        });
+        assert_eq!(res, vec![m2.clone(), m1.clone()]);
+        let res = search(MessageSearch {
+            channels: both.clone(),
+            after: Some(m2.date.into()),
+            before: Some(m3.date.into()),
+            ..Default::default()
+        });
+        assert_eq!(res, vec![m3.clone(), m2.clone()]);
+        let res = search(MessageSearch {
+            query: "world".into(),
+            channels: both.clone(),
+            author: Some(u1.uuid),
+            has_reply: Some(true),
+            ..Default::default()
+        });
+        assert!(res.is_empty());
+
+        // paging
+        let all = MessageSearch {
+            channels: both,
+            ..Default::default()
+        };
+        assert_eq!(
+            s.search_messages(&all, 2, None).unwrap(),
+            vec![m3.clone(), m2.clone()]
+        );
+        assert_eq!(s.search_messages(&all, 2, Some(m2.uuid)).unwrap(), vec![m1]);
+    }
+
+    #[test]
+    fn search_messages_edited_and_deleted() {
+        let (s, m1, _, _, c1, c2, _, _) = init_with_msgs(true);
+        let search = MessageSearch {
+            query: "hello".into(),
+            channels: vec![c1.uuid, c2.uuid],
+            ..Default::default()
+        };
+        s.edit_message(m1.uuid, "Hi there", 1359083600).unwrap();
+        assert!(s.search_messages(&search, 50, None).unwrap().is_empty());
+        s.edit_message(m1.uuid, "Hello again", 1359083700).unwrap();
+        assert_eq!(s.search_messages(&search, 50, None).unwrap().len(), 1);
+        s.delete_message(m1.uuid).unwrap();
+        assert!(s.search_messages(&search, 50, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn soft_delete_message() {
+        let (s, m1, m2, m3, c1, c2, u1, u2) = init_with_msgs(true);
+        s.pin_message(&m1, u1.uuid, 10).unwrap();
+        s.update_last_read_for_user_in_channel(u2.uuid, c1.uuid, m1.uuid)
+            .unwrap();
+        assert_eq!(s.get_num_unread_messages(u2.uuid, c1.uuid).unwrap(), 1);
+
+        assert_eq!(s.soft_delete_message(m3.uuid, u2.uuid, 100).unwrap(), 1);
+        assert_eq!(s.soft_delete_message(m3.uuid, u2.uuid, 200).unwrap(), 0);
+        assert_eq!(s.soft_delete_message(m1.uuid, u1.uuid, 300).unwrap(), 1);
+
+        // gone from everywhere except the history, where the content is still available
+        assert!(s.get_message(m3.uuid).unwrap().is_none());
+        assert!(!s.message_exists(&m3.uuid).unwrap());
+        assert!(s.get_pins(c1.uuid).unwrap().is_empty());
+        assert_eq!(s.get_num_unread_messages(u2.uuid, c1.uuid).unwrap(), 0);
+        let search = MessageSearch {
+            channels: vec![c1.uuid, c2.uuid],
+            ..Default::default()
+        };
+        assert_eq!(s.search_messages(&search, 50, None).unwrap(), vec![m2]);
+        let deleted = s.get_deleted_message(m3.uuid).unwrap().unwrap();
+        assert_eq!(deleted.content, m3.content);
+        assert_eq!(deleted.deleted_at, Some(100));
+        assert_eq!(deleted.deleted_by, Some(u2.uuid));
+        assert_eq!(s.get_history(c1.uuid, 50, None).unwrap()[0], deleted);
+
+        // restoring brings everything back
+        assert_eq!(s.restore_message(m1.uuid).unwrap(), 1);
+        assert_eq!(s.restore_message(m1.uuid).unwrap(), 0);
+        assert_eq!(s.get_message(m1.uuid).unwrap(), Some(m1.clone()));
+        assert_eq!(s.get_pins(c1.uuid).unwrap(), vec![m1.clone()]);
+        assert!(s.get_deleted_message(m1.uuid).unwrap().is_none());
+
+        // only messages deleted long enough ago are purged
+        assert_eq!(s.purge_deleted_messages(99).unwrap(), 0);
+        assert_eq!(s.purge_deleted_messages(100).unwrap(), 1);
+        assert!(s.get_deleted_message(m3.uuid).unwrap().is_none());
+        assert_eq!(s.get_history(c1.uuid, 50, None).unwrap(), vec![m1]);
+    }
+
+    #[test]
+    fn redact_deleted_message() {
+        let (s, m1, _, _, _, _, u1, _) = init_with_msgs(true);
+        s.add_reaction(m1.uuid, u1.uuid, &ReactionEmoji::Unicode("👍".into()))
+            .unwrap();
+        s.soft_delete_message(m1.uuid, u1.uuid, 100).unwrap();
+        let mut deleted = s.get_deleted_message(m1.uuid).unwrap().unwrap();
+        deleted.redact();
+        assert!(deleted.content.is_empty());
+        assert!(deleted.reactions.is_empty());
+        assert_eq!(deleted.deleted_at, Some(100));
+        assert_eq!(deleted.deleted_by, None);
+        assert_eq!(deleted.author_uuid, m1.author_uuid);
+    }
+
     #[test]
     fn get_history() {
         let (s, m1, _, _, c1, _, _, _) = init_with_msgs(true);
@@ -1996,6 +5269,6 @@ mod tests {
         let ss1 = s.get_sync_servers(ss.user_uuid);
         assert!(ss1.is_ok());
         let ss1 = ss1.unwrap();
-        assert!(ss1.len() == 0);
+        assert!(ss1.is_empty());
     }
 }